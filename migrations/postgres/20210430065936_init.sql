@@ -0,0 +1,9 @@
+-- Схема базы данных
+-- https://dbdiagram.io/d/608babe3b29a09603d12cda0
+-- Таблица purchases в Postgres версии не создается, так как в SQLite она удалена следующей миграцией
+
+CREATE TABLE products (
+    product_id SERIAL PRIMARY KEY,
+    product_name VARCHAR(64) UNIQUE NOT NULL,
+    price BIGINT NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Add migration script here
+
+DROP INDEX IF EXISTS purchases_products_idx;
+DROP TABLE IF EXISTS purchases;
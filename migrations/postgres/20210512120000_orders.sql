@@ -0,0 +1,34 @@
+-- Заказы и история изменения их статусов
+
+CREATE TABLE orders (
+    order_id VARCHAR(64) PRIMARY KEY,
+    product_id INTEGER,
+    amount BIGINT NOT NULL,
+    currency VARCHAR(3) NOT NULL,
+    order_status VARCHAR(16) NOT NULL
+        DEFAULT('created'),
+    payment_id VARCHAR(64),
+    checkout_url TEXT,
+    created_at BIGINT NOT NULL,
+    updated_at BIGINT NOT NULL,
+
+    CONSTRAINT order_status_check
+        CHECK (order_status IN ('created', 'processing', 'declined', 'approved', 'expired', 'reversed'))
+);
+
+CREATE INDEX orders_status_idx ON orders (order_status, created_at);
+
+CREATE TABLE order_events (
+    event_id BIGSERIAL PRIMARY KEY,
+    order_id VARCHAR(64) NOT NULL,
+    from_status VARCHAR(16),
+    to_status VARCHAR(16) NOT NULL,
+    event_source VARCHAR(16) NOT NULL,
+    created_at BIGINT NOT NULL,
+
+    CONSTRAINT order_id_ref
+        FOREIGN KEY (order_id)
+        REFERENCES orders(order_id)
+);
+
+CREATE INDEX order_events_order_idx ON order_events (order_id);
@@ -0,0 +1,7 @@
+-- Время жизни оплаты: для продукта отдельно и крайний срок оплаты заказа
+
+ALTER TABLE products ADD COLUMN checkout_lifetime_secs BIGINT;
+
+ALTER TABLE orders ADD COLUMN expires_at BIGINT;
+
+CREATE INDEX orders_expires_idx ON orders (order_status, expires_at);
@@ -0,0 +1,10 @@
+-- Ключ идемпотентности создания заказа: повторный запрос с тем же ключом получает тот же платеж.
+-- Токен встроенной оплаты сохраняется, чтобы отдать его повторно.
+
+ALTER TABLE orders ADD COLUMN idempotency_key VARCHAR(255);
+
+ALTER TABLE orders ADD COLUMN idempotency_hash VARCHAR(40);
+
+ALTER TABLE orders ADD COLUMN checkout_token TEXT;
+
+CREATE UNIQUE INDEX orders_idempotency_key_idx ON orders (idempotency_key);
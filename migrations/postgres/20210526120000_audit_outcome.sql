@@ -0,0 +1,5 @@
+-- Результат действия с внешним эффектом, например возврата через Fondy.
+-- Запись создается со статусом requested до запроса и обновляется после него,
+-- поэтому в журнале остается и запрос, ответ на который так и не был получен.
+
+ALTER TABLE audit_log ADD COLUMN outcome TEXT;
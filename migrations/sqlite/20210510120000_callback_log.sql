@@ -0,0 +1,19 @@
+-- Журнал всех пришедших коллбеков в исходном виде для аудита и повторной обработки
+
+CREATE TABLE callback_log (
+    callback_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    received_at BIGINT NOT NULL,
+    callback_kind VARCHAR(16) NOT NULL,
+    source_ip VARCHAR(64),
+    headers TEXT NOT NULL,
+    body TEXT NOT NULL,
+    order_id VARCHAR(64),
+    signature_valid BOOLEAN,
+    processing_result TEXT,
+    replayed_at BIGINT,
+
+    CONSTRAINT callback_kind_check
+        CHECK (callback_kind IN ('server', 'browser'))
+);
+
+CREATE INDEX callback_log_order_idx ON callback_log (order_id);
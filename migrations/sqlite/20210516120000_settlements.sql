@@ -0,0 +1,24 @@
+-- Транзакции из отчетов Fondy для сверки расчетов с нашими заказами
+
+CREATE TABLE settlements (
+    settlement_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    order_id VARCHAR(64) NOT NULL,
+    payment_id VARCHAR(64) NOT NULL,
+    tran_type VARCHAR(16) NOT NULL,
+    order_status VARCHAR(16) NOT NULL,
+    currency VARCHAR(3) NOT NULL,
+    amount BIGINT,
+    actual_amount BIGINT,
+    settlement_amount BIGINT,
+    settlement_currency VARCHAR(3),
+    settlement_date VARCHAR(32),
+    fee BIGINT,
+    order_time BIGINT,
+    imported_at BIGINT NOT NULL,
+
+    CONSTRAINT settlements_payment_unique
+        UNIQUE (payment_id, tran_type)
+);
+
+CREATE INDEX settlements_order_idx ON settlements (order_id);
+CREATE INDEX settlements_time_idx ON settlements (order_time);
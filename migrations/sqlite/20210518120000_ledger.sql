@@ -0,0 +1,30 @@
+-- Двойная запись: проводка состоит из строк по счетам, сумма строк каждой проводки равна нулю.
+-- Дебет записывается положительной суммой, кредит отрицательной.
+
+CREATE TABLE ledger_entries (
+    entry_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    entry_key VARCHAR(128) UNIQUE NOT NULL,
+    entry_kind VARCHAR(16) NOT NULL,
+    order_id VARCHAR(64),
+    currency VARCHAR(3) NOT NULL,
+    created_at BIGINT NOT NULL,
+
+    CONSTRAINT entry_kind_check
+        CHECK (entry_kind IN ('payment', 'fee', 'refund', 'chargeback', 'payout'))
+);
+
+CREATE INDEX ledger_entries_order_idx ON ledger_entries (order_id);
+CREATE INDEX ledger_entries_time_idx ON ledger_entries (created_at);
+
+CREATE TABLE ledger_postings (
+    posting_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    entry_id BIGINT NOT NULL,
+    account VARCHAR(32) NOT NULL,
+    amount BIGINT NOT NULL,
+
+    CONSTRAINT entry_id_ref
+        FOREIGN KEY (entry_id)
+        REFERENCES ledger_entries(entry_id)
+);
+
+CREATE INDEX ledger_postings_entry_idx ON ledger_postings (entry_id);
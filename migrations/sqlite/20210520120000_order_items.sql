@@ -0,0 +1,20 @@
+-- Состав заказа и почта покупателя для заказов через API
+
+ALTER TABLE orders ADD COLUMN customer_email VARCHAR(254);
+
+CREATE TABLE order_items (
+    item_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    order_id VARCHAR(64) NOT NULL,
+    product_id INTEGER NOT NULL,
+    quantity BIGINT NOT NULL,
+    unit_price BIGINT NOT NULL,
+
+    CONSTRAINT order_id_ref
+        FOREIGN KEY (order_id)
+        REFERENCES orders(order_id),
+
+    CONSTRAINT quantity_check
+        CHECK (quantity > 0)
+);
+
+CREATE INDEX order_items_order_idx ON order_items (order_id);
@@ -0,0 +1,52 @@
+-- Пользователи админки, пароль хранится хешем argon2 в формате PHC
+CREATE TABLE users (
+    user_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username VARCHAR(64) UNIQUE NOT NULL,
+    password_hash VARCHAR(255) NOT NULL,
+    user_role VARCHAR(16) NOT NULL,
+    created_at BIGINT NOT NULL,
+    disabled_at BIGINT,
+
+    CONSTRAINT user_role_check
+        CHECK (user_role IN ('viewer', 'support', 'finance', 'admin'))
+);
+
+-- Сессии админки, в базе только хеш токена из cookie
+CREATE TABLE sessions (
+    session_hash VARCHAR(64) PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL,
+
+    CONSTRAINT user_id_ref
+        FOREIGN KEY (user_id)
+        REFERENCES users(user_id)
+        ON DELETE CASCADE
+);
+
+CREATE INDEX sessions_expires_idx ON sessions (expires_at);
+
+-- Ключи API для машинных клиентов, в базе только хеш ключа
+CREATE TABLE api_keys (
+    key_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    key_hash VARCHAR(64) UNIQUE NOT NULL,
+    key_name VARCHAR(64) NOT NULL,
+    key_role VARCHAR(16) NOT NULL,
+    created_at BIGINT NOT NULL,
+    revoked_at BIGINT,
+
+    CONSTRAINT key_role_check
+        CHECK (key_role IN ('viewer', 'support', 'finance', 'admin'))
+);
+
+-- Журнал привилегированных действий
+CREATE TABLE audit_log (
+    audit_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at BIGINT NOT NULL,
+    actor VARCHAR(128) NOT NULL,
+    action VARCHAR(64) NOT NULL,
+    target VARCHAR(255),
+    details TEXT
+);
+
+CREATE INDEX audit_log_time_idx ON audit_log (created_at);
@@ -0,0 +1,5 @@
+-- Время последней попытки сверки заказа со статусом в Fondy.
+-- Сверка берет сначала заказы, которые дольше всех не проверялись,
+-- чтобы постоянно падающие заказы не занимали весь пакет.
+
+ALTER TABLE orders ADD COLUMN reconciled_at BIGINT;
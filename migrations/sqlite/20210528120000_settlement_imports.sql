@@ -0,0 +1,8 @@
+-- Дни, отчет Fondy за которые уже загружен фоновой задачей.
+-- После простоя задача догружает пропущенные дни начиная с последнего загруженного.
+
+CREATE TABLE settlement_imports (
+    import_day VARCHAR(10) PRIMARY KEY,
+    transactions BIGINT NOT NULL,
+    imported_at BIGINT NOT NULL
+);
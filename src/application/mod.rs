@@ -6,27 +6,35 @@ use std::{
 use handlebars::{
     Handlebars
 };
-use reqwest::{
-    Client
-};
 use crate::{
     database::{
         Database
+    },
+    config::{
+        AppConfig
+    },
+    fondy::{
+        FondyClient
+    },
+    orders::{
+        StatusEvents
+    },
+    health::{
+        Heartbeats
+    },
+    shutdown::{
+        Shutdown
     }
 };
 
 
-#[derive(Debug)]
-pub struct AppConfig{
-    pub site_url: url::Url,
-    pub merchant_id: u64,
-    pub merchant_password: String
-}
-
 #[derive(Debug)]
 pub struct Application{
     pub db: Arc<Database>,
     pub templates: Arc<Handlebars<'static>>,
-    pub http_client: Client, // Arc inside
-    pub config: Arc<AppConfig>
+    pub fondy: FondyClient, // Arc inside
+    pub config: Arc<AppConfig>,
+    pub status_events: StatusEvents,
+    pub heartbeats: Heartbeats,
+    pub shutdown: Shutdown
 }
\ No newline at end of file
@@ -0,0 +1,311 @@
+use std::{
+    str::{
+        FromStr
+    },
+    time::{
+        Duration
+    }
+};
+use argon2::{
+    Argon2,
+    password_hash::{
+        PasswordHash,
+        PasswordHasher,
+        PasswordVerifier,
+        SaltString
+    }
+};
+use rand_core::{
+    OsRng,
+    RngCore
+};
+use sha1::{
+    Digest
+};
+use tap::{
+    TapFallible
+};
+use tracing::{
+    debug,
+    error,
+    warn,
+    instrument
+};
+use crate::{
+    database::{
+        Database,
+        NewAuditRecord
+    },
+    error::{
+        FondyError
+    }
+};
+
+/// Результаты действий в журнале
+const AUDIT_REQUESTED: &str = "requested";
+const AUDIT_SUCCEEDED: &str = "succeeded";
+const AUDIT_FAILED: &str = "failed";
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Роль пользователя или ключа API, каждая следующая может все, что и предыдущие
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role{
+    /// Только просмотр заказов и продуктов
+    Viewer,
+
+    /// Создание заказов, например от имени покупателя
+    Support,
+
+    /// Возвраты и списания
+    Finance,
+
+    /// Управление каталогом
+    Admin
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Viewer, Role::Support, Role::Finance, Role::Admin];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Support => "support",
+            Role::Finance => "finance",
+            Role::Admin => "admin"
+        }
+    }
+
+    pub fn allows(self, permission: Permission) -> bool {
+        self >= permission.min_role()
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .iter()
+            .find(|role| role.as_str() == text)
+            .copied()
+            .ok_or_else(|| format!("Unknown role: {}", text))
+    }
+}
+
+/// Действия, доступ к которым проверяется
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission{
+    View,
+    CreateOrders,
+    RefundOrders,
+    ManageProducts
+}
+
+impl Permission {
+    fn min_role(self) -> Role {
+        match self {
+            Permission::View => Role::Viewer,
+            Permission::CreateOrders => Role::Support,
+            Permission::RefundOrders => Role::Finance,
+            Permission::ManageProducts => Role::Admin
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::View => "view",
+            Permission::CreateOrders => "create orders",
+            Permission::RefundOrders => "refund orders",
+            Permission::ManageProducts => "manage products"
+        }
+    }
+}
+
+/// Кто выполняет запрос
+#[derive(Debug, Clone)]
+pub struct Principal{
+    /// Имя для журнала действий: имя пользователя или key:<имя ключа>
+    pub actor: String,
+    pub role: Role
+}
+
+impl Principal {
+    /// Проверка доступа к действию
+    pub fn require(&self, permission: Permission) -> Result<(), FondyError> {
+        if self.role.allows(permission) {
+            Ok(())
+        }else{
+            warn!(actor = %self.actor, role = self.role.as_str(), permission = permission.as_str(), "Permission denied");
+            Err(FondyError::Forbidden(permission.as_str().to_owned()))
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub fn hash_password(password: &str) -> Result<String, FondyError> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| FondyError::PasswordHashError(err.to_string()))?;
+    Ok(hash.to_string())
+}
+
+pub fn verify_password(password_hash: &str, password: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(err) => {
+            warn!(%err, "Stored password hash is invalid");
+            false
+        }
+    }
+}
+
+/// Случайный токен для сессии или ключа API
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// В базе хранится только хеш токена, токены случайные, поэтому соль не нужна
+pub fn token_hash(token: &str) -> String {
+    format!("{:x}", sha1::Sha1::digest(token.as_bytes()))
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Записывает действие в журнал
+#[instrument(skip(db))]
+pub async fn audit(db: &Database, actor: &str, action: &str, target: Option<&str>, details: Option<&str>) -> Result<(), FondyError> {
+    db.insert_audit_record(NewAuditRecord{
+            actor,
+            action,
+            target,
+            details,
+            outcome: None
+        })
+        .await?;
+    Ok(())
+}
+
+/// Записывает действие с внешним эффектом до его выполнения.
+/// Если ответ так и не придет, в журнале останется запись со статусом requested.
+#[instrument(skip(db))]
+pub async fn audit_requested(db: &Database, actor: &str, action: &str, target: Option<&str>, details: Option<&str>) -> Result<i64, FondyError> {
+    db.insert_audit_record(NewAuditRecord{
+            actor,
+            action,
+            target,
+            details,
+            outcome: Some(AUDIT_REQUESTED)
+        })
+        .await
+}
+
+/// Дописывает результат действия, записанного через audit_requested.
+/// Само действие уже выполнено, поэтому ошибку записи только логируем.
+#[instrument(skip(db, result))]
+pub async fn audit_outcome<T>(db: &Database, audit_id: i64, result: &Result<T, FondyError>) {
+    let outcome = match result {
+        Ok(_) => AUDIT_SUCCEEDED.to_owned(),
+        Err(err) => format!("{}: {}", AUDIT_FAILED, err)
+    };
+    db.update_audit_outcome(audit_id, &outcome)
+        .await
+        .tap_err(|err| { error!(audit_id, %err, "Audit outcome update failed"); })
+        .ok();
+}
+
+/// Проверяет пароль и создает сессию, возвращает токен для cookie
+#[instrument(skip(db, password))]
+pub async fn login(db: &Database, username: &str, password: &str, session_lifetime: Duration) -> Result<Option<String>, FondyError> {
+    let user = db
+        .get_user(username)
+        .await?
+        .filter(|user| user.disabled_at.is_none());
+    let user = match user {
+        Some(user) if verify_password(&user.password_hash, password) => user,
+        _ => {
+            audit(db, username, "login_failed", None, None).await?;
+            return Ok(None);
+        }
+    };
+
+    // Заодно чистим истекшие сессии, чтобы таблица не росла
+    let removed = db.delete_expired_sessions().await?;
+    debug!(removed, "Expired sessions removed");
+
+    let token = generate_token();
+    let expires_at = crate::database::unix_now() + session_lifetime.as_secs() as i64;
+    db.insert_session(&token_hash(&token), user.user_id, expires_at).await?;
+    audit(db, &user.username, "login", None, None).await?;
+    Ok(Some(token))
+}
+
+#[instrument(skip(db, token))]
+pub async fn logout(db: &Database, token: &str) -> Result<(), FondyError> {
+    db.delete_session(&token_hash(token)).await
+}
+
+/// Пользователь по токену сессии из cookie
+#[instrument(skip(db, token))]
+pub async fn authenticate_session(db: &Database, token: &str) -> Result<Option<Principal>, FondyError> {
+    let user = match db.get_session_user(&token_hash(token)).await? {
+        Some(user) => user,
+        None => return Ok(None)
+    };
+    let role = Role::from_str(&user.user_role)
+        .map_err(FondyError::Custom)?;
+    Ok(Some(Principal{
+        actor: user.username,
+        role
+    }))
+}
+
+/// Машинный клиент по ключу API
+#[instrument(skip(db, key))]
+pub async fn authenticate_api_key(db: &Database, key: &str) -> Result<Option<Principal>, FondyError> {
+    let record = match db.get_api_key(&token_hash(key)).await? {
+        Some(record) => record,
+        None => return Ok(None)
+    };
+    let role = Role::from_str(&record.key_role)
+        .map_err(FondyError::Custom)?;
+    Ok(Some(Principal{
+        actor: format!("key:{}", record.key_name),
+        role
+    }))
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_role_permissions(){
+        assert!(Role::Viewer.allows(Permission::View));
+        assert!(!Role::Viewer.allows(Permission::CreateOrders));
+        assert!(Role::Finance.allows(Permission::RefundOrders));
+        assert!(!Role::Support.allows(Permission::RefundOrders));
+        assert!(!Role::Finance.allows(Permission::ManageProducts));
+        assert!(Role::Admin.allows(Permission::ManageProducts));
+    }
+
+    #[test]
+    fn test_password_hash(){
+        let hash = hash_password("secret").unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_password(&hash, "secret"));
+        assert!(!verify_password(&hash, "wrong"));
+        assert!(!verify_password("garbage", "secret"));
+    }
+}
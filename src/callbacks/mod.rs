@@ -0,0 +1,363 @@
+use std::{
+    net::{
+        IpAddr
+    },
+    str::{
+        FromStr
+    }
+};
+use tracing::{
+    debug,
+    error,
+    warn,
+    instrument
+};
+use tap::{
+    prelude::{
+        *
+    }
+};
+use warp::{
+    http::{
+        HeaderMap
+    }
+};
+use crate::{
+    config::{
+        AppConfig
+    },
+    database::{
+        Database,
+        CallbackKind,
+        NewCallbackLog,
+        CALLBACK_RESULT_OK
+    },
+    error::{
+        FondyError
+    },
+    fondy::{
+        FondyPaymentResponse,
+        verify_callback_signature
+    },
+    orders::{
+        EventSource,
+        TransitionOutcome,
+        apply_status
+    },
+    ledger::{
+        PaymentAmounts,
+        record_payment_amounts
+    },
+    metrics::{
+        callback_received
+    }
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Сколько байт тела сохранять в журнал для коллбеков без верной подписи.
+/// Такой запрос может прислать кто угодно, поэтому целиком его не храним.
+const UNVERIFIED_BODY_LOG_LIMIT: usize = 4 * 1024;
+
+/// Заголовки с учетными данными, в журнал вместо значения пишем заглушку.
+/// Браузерный коллбек приходит от покупателя вместе с его cookie, включая сессию админки.
+const REDACTED_HEADERS: [&str; 4] = ["cookie", "authorization", "proxy-authorization", "x-api-key"];
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Исходный HTTP запрос коллбека, сохраняется в журнал без изменений
+#[derive(Debug)]
+pub struct RawCallback{
+    /// Адрес клиента с учетом доверенных прокси
+    pub source_ip: Option<IpAddr>,
+    pub headers: HeaderMap,
+    pub body: bytes::Bytes
+}
+
+/// Результат разбора коллбека вместе с данными для журнала
+struct ProcessOutcome{
+    order_id: Option<String>,
+    signature_valid: Option<bool>,
+    result: Result<FondyPaymentResponse, FondyError>
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+fn headers_to_json(headers: &HeaderMap) -> String {
+    let mut map = serde_json::Map::new();
+    for (name, value) in headers.iter() {
+        let value = if REDACTED_HEADERS.contains(&name.as_str()) {
+            "[redacted]".to_owned()
+        }else{
+            String::from_utf8_lossy(value.as_bytes()).into_owned()
+        };
+        match map.get_mut(name.as_str()) {
+            // Повторяющиеся заголовки склеиваем как в HTTP
+            Some(serde_json::Value::String(prev)) => {
+                prev.push_str(", ");
+                prev.push_str(&value);
+            },
+            _ => {
+                map.insert(name.as_str().to_owned(), serde_json::Value::String(value));
+            }
+        }
+    }
+    serde_json::Value::Object(map).to_string()
+}
+
+/// Тело для журнала: с верной подписью целиком, иначе только начало
+fn body_for_log(body: &[u8], signature_valid: Option<bool>) -> String {
+    if signature_valid == Some(true) || body.len() <= UNVERIFIED_BODY_LOG_LIMIT {
+        return String::from_utf8_lossy(body).into_owned();
+    }
+    let mut text = String::from_utf8_lossy(&body[..UNVERIFIED_BODY_LOG_LIMIT]).into_owned();
+    text.push_str(&format!("... ({} bytes total)", body.len()));
+    text
+}
+
+fn order_id_of(data: &serde_json::Value) -> Option<String> {
+    data.get("order_id")
+        .and_then(|val| val.as_str())
+        .map(|val| val.to_owned())
+}
+
+/// Разбор серверного коллбека: JSON в теле запроса
+fn process_server_body(config: &AppConfig, body: &[u8]) -> ProcessOutcome {
+    let data = std::str::from_utf8(body)
+        .map_err(|err| FondyError::InvalidRequest(format!("server callback is not utf-8: {}", err)))
+        .and_then(|text| {
+            serde_json::Value::from_str(text)
+                .map_err(|err| FondyError::InvalidRequest(format!("server callback parse failed: {}", err)))
+        });
+    let data = match data {
+        Ok(data) => data,
+        Err(err) => return ProcessOutcome{ order_id: None, signature_valid: None, result: Err(err) }
+    };
+    let order_id = order_id_of(&data);
+
+    // Проверяем подпись и только потом парсим в структуру
+    let signature_valid = match verify_callback_signature(&config.merchant_password, &data) {
+        Ok(valid) => valid,
+        Err(err) => return ProcessOutcome{ order_id, signature_valid: None, result: Err(err) }
+    };
+    let result = if signature_valid {
+        serde_json::from_value::<FondyPaymentResponse>(data)
+            .map_err(|err| FondyError::InvalidRequest(format!("server callback parse failed: {}", err)))
+    }else{
+        Err(FondyError::InvalidCallbackSignature)
+    };
+
+    ProcessOutcome{
+        order_id,
+        signature_valid: Some(signature_valid),
+        result
+    }
+}
+
+/// Разбор браузерного коллбека: form-urlencoded в теле запроса
+fn process_browser_body(config: &AppConfig, body: &[u8]) -> ProcessOutcome {
+    // Для проверки подписи все значения формы считаем строками, именно так их подписывает Fondy
+    let data = serde_urlencoded::from_bytes::<Vec<(String, String)>>(body)
+        .map(|pairs|{
+            let map = pairs
+                .into_iter()
+                .map(|(key, value)| (key, serde_json::Value::String(value)))
+                .collect::<serde_json::Map<_, _>>();
+            serde_json::Value::Object(map)
+        })
+        .map_err(|err| FondyError::InvalidRequest(format!("browser callback form parse failed: {}", err)));
+    let data = match data {
+        Ok(data) => data,
+        Err(err) => return ProcessOutcome{ order_id: None, signature_valid: None, result: Err(err) }
+    };
+    let order_id = order_id_of(&data);
+
+    let signature_valid = match verify_callback_signature(&config.merchant_password, &data) {
+        Ok(valid) => valid,
+        Err(err) => return ProcessOutcome{ order_id, signature_valid: None, result: Err(err) }
+    };
+    let result = if signature_valid {
+        serde_urlencoded::from_bytes::<FondyPaymentResponse>(body)
+            .map_err(|err| FondyError::InvalidRequest(format!("browser callback parse failed: {}", err)))
+    }else{
+        Err(FondyError::InvalidCallbackSignature)
+    };
+
+    ProcessOutcome{
+        order_id,
+        signature_valid: Some(signature_valid),
+        result
+    }
+}
+
+/// Обработка уже проверенных данных платежа, общая для живых и повторно обработанных коллбеков
+async fn apply_payment(db: &Database, data: &FondyPaymentResponse) -> Result<(), FondyError> {
+    debug!("Purchase server callback success! Data: {:#?}", data);
+
+    // Данный коллбек вызывается несколько раз на изменение статуса платежа,
+    // повторы и недопустимые переходы отсекает машина состояний заказа
+    let outcome = apply_status(db, &data.order_id, data.order_status, EventSource::Callback)
+        .await?;
+    match outcome {
+        // Суммы ниже все равно попадут в проводки, поэтому расхождение статуса должно быть заметно
+        TransitionOutcome::Rejected{ from, to } => {
+            warn!(order_id = %data.order_id, %from, %to, "Payment status from Fondy is rejected by order state machine");
+        },
+        outcome => {
+            debug!(?outcome, "Order status applied");
+        }
+    }
+
+    // Комиссия приходит строкой, пустой если ее еще нет
+    let fee = match data.fee.trim() {
+        "" => None,
+        fee => fee
+            .parse::<i64>()
+            .tap_err(|err|{ warn!(fee, %err, "Callback fee parse failed"); })
+            .ok()
+    };
+    record_payment_amounts(db, PaymentAmounts{
+            order_id: &data.order_id,
+            order_status: data.order_status,
+            currency: &data.currency,
+            actual_amount: data.actual_amount as i64,
+            reversal_amount: data.reversal_amount as i64,
+            fee
+        })
+        .await
+        .tap_err(|err|{ error!("Ledger update failed: {}", err); })?;
+
+    // - Проверяем, не была ли выдача уже через базу с транзакцией
+    // - Оповещаем наш сервер
+    // - Если наш сервер не ответил, тогда ставим в очередь периодическую отправку оповещения + сохраняем в базу до подтверждения
+
+    // Может быть сразу делать коллбек на наш сервер для выдачи??
+
+    // Лучше ждать прямо здесь пока наш сервер не ответит, затем возвращать ошибку
+    // Тогда их сервер будет сам делать перезапрос на выдачу
+
+    Ok(())
+}
+
+/// Сохраняет результат обработки в журнал
+async fn finish_log(db: &Database, callback_id: i64, outcome: &ProcessOutcome, result: &Result<(), FondyError>) -> Result<(), FondyError> {
+    let result_text = match (&outcome.result, result) {
+        (Ok(_), Ok(_)) => CALLBACK_RESULT_OK.to_owned(),
+        (Err(err), _) | (Ok(_), Err(err)) => err.to_string()
+    };
+    db.finish_callback_log(callback_id, outcome.order_id.as_deref(), outcome.signature_valid, &result_text)
+        .await
+}
+
+/// Полный цикл обработки: разбор, проверка подписи, журнал, применение платежа, результат в журнал.
+/// Разбор не трогает базу, поэтому в журнал запрос попадает до применения платежа.
+async fn handle(db: &Database, config: &AppConfig, kind: CallbackKind, callback: RawCallback) -> Result<FondyPaymentResponse, FondyError> {
+    let outcome = match kind {
+        CallbackKind::Server => process_server_body(config, callback.body.as_ref()),
+        CallbackKind::Browser => process_browser_body(config, callback.body.as_ref())
+    };
+
+    let body = body_for_log(callback.body.as_ref(), outcome.signature_valid);
+    let callback_id = db
+        .insert_callback_log(NewCallbackLog{
+            kind,
+            source_ip: callback.source_ip.map(|ip| ip.to_string()),
+            headers: headers_to_json(&callback.headers),
+            body: &body
+        })
+        .await
+        .tap_err(|err|{ error!("Callback log save failed: {}", err); })?;
+    debug!(callback_id, "Callback saved to log");
+    callback_received(kind.as_str(),
+                      outcome.result.as_ref().ok().map(|data| data.order_status.as_str()),
+                      outcome.signature_valid);
+
+    // Браузерный коллбек только показывает результат пользователю, платеж применяем по серверному
+    let apply_result = match (&outcome.result, kind) {
+        (Ok(data), CallbackKind::Server) => apply_payment(db, data).await,
+        _ => Ok(())
+    };
+    finish_log(db, callback_id, &outcome, &apply_result)
+        .await
+        .tap_err(|err|{ error!("Callback log update failed: {}", err); })?;
+
+    apply_result?;
+    outcome.result
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[instrument(skip(db, config, callback))]
+pub async fn handle_server_callback(db: &Database, config: &AppConfig, callback: RawCallback) -> Result<FondyPaymentResponse, FondyError> {
+    handle(db, config, CallbackKind::Server, callback)
+        .await
+}
+
+#[instrument(skip(db, config, callback))]
+pub async fn handle_browser_callback(db: &Database, config: &AppConfig, callback: RawCallback) -> Result<FondyPaymentResponse, FondyError> {
+    handle(db, config, CallbackKind::Browser, callback)
+        .await
+}
+
+/// Повторная обработка сохраненного серверного коллбека, например после исправления ошибки.
+/// Результат записывается в ту же запись журнала.
+#[instrument(skip(db, config))]
+pub async fn replay_callback(db: &Database, config: &AppConfig, callback_id: i64) -> Result<FondyPaymentResponse, FondyError> {
+    let record = db
+        .get_callback_log(callback_id)
+        .await?
+        .ok_or_else(||{
+            FondyError::Custom(format!("Callback {} is not found", callback_id))
+        })?;
+    if record.callback_kind != CallbackKind::Server.as_str() {
+        return Err(FondyError::Custom(format!("Only server callbacks can be replayed, callback {} is {}", callback_id, record.callback_kind)));
+    }
+
+    let outcome = process_server_body(config, record.body.as_bytes());
+    let apply_result = match &outcome.result {
+        Ok(data) => apply_payment(db, data).await,
+        Err(_) => Ok(())
+    };
+    finish_log(db, callback_id, &outcome, &apply_result).await?;
+    db.mark_callback_replayed(callback_id).await?;
+
+    apply_result?;
+    outcome.result
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_headers_to_json(){
+        let mut headers = HeaderMap::new();
+        headers.append("x-test", "a".parse().unwrap());
+        headers.append("x-test", "b".parse().unwrap());
+        headers.insert("content-type", "application/json".parse().unwrap());
+        headers.insert("cookie", "session=secret; csrf=token".parse().unwrap());
+        headers.insert("authorization", "Bearer secret".parse().unwrap());
+        headers.insert("proxy-authorization", "Basic secret".parse().unwrap());
+        headers.insert("x-api-key", "secret".parse().unwrap());
+        let text = headers_to_json(&headers);
+        assert!(!text.contains("secret"));
+        let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(json["x-test"], "a, b");
+        assert_eq!(json["content-type"], "application/json");
+        assert_eq!(json["cookie"], "[redacted]");
+        assert_eq!(json["x-api-key"], "[redacted]");
+    }
+
+    #[test]
+    fn test_body_for_log(){
+        let body = vec![b'a'; UNVERIFIED_BODY_LOG_LIMIT * 2];
+        assert_eq!(body_for_log(&body, Some(true)).len(), body.len());
+        let truncated = body_for_log(&body, Some(false));
+        assert!(truncated.starts_with(&"a".repeat(UNVERIFIED_BODY_LOG_LIMIT)));
+        assert!(truncated.ends_with(&format!("({} bytes total)", body.len())));
+        assert!(body_for_log(&body, None).len() < body.len());
+        assert_eq!(body_for_log(b"short", None), "short");
+    }
+}
@@ -0,0 +1,436 @@
+use std::{
+    time::{
+        Duration,
+        Instant
+    }
+};
+use sha1::{
+    Digest
+};
+use serde::{
+    Deserialize
+};
+use serde_json::{
+    json
+};
+use tracing::{
+    debug,
+    error,
+    info,
+    instrument
+};
+use tap::{
+    prelude::{
+        *
+    }
+};
+use crate::{
+    config::{
+        AppConfig
+    },
+    database::{
+        Database,
+        NewOrder,
+        NewOrderItem,
+        OrderRecord,
+        unix_now
+    },
+    error::{
+        FondyError
+    },
+    fondy::{
+        FondyClient,
+        FONDY_PROTOCOL_VERSION
+    },
+    metrics::{
+        checkout_created
+    },
+    orders::{
+        EventSource
+    }
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Валюта всех заказов
+pub const CHECKOUT_CURRENCY: &str = "USD";
+
+/// Максимальное количество одного продукта в заказе
+const MAX_ITEM_QUANTITY: i64 = 1000;
+
+/// Максимальная длина ключа идемпотентности от клиента
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 128;
+
+/// Сколько повторный запрос ждет платеж, который еще создается первым запросом с тем же ключом
+const IDEMPOTENCY_WAIT: Duration = Duration::from_secs(10);
+const IDEMPOTENCY_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Способ оплаты заказа
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckoutMode{
+    /// Переход на страницу оплаты Fondy
+    #[default]
+    Redirect,
+
+    /// Виджет оплаты Fondy на нашей странице
+    Embedded
+}
+
+impl CheckoutMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CheckoutMode::Redirect => "redirect",
+            CheckoutMode::Embedded => "embedded"
+        }
+    }
+}
+
+/// Данные для создания платежа
+#[derive(Debug)]
+pub struct CheckoutRequest<'a>{
+    pub mode: CheckoutMode,
+    pub items: Vec<NewOrderItem>,
+    pub description: String,
+    pub customer_email: Option<&'a str>,
+    pub lifetime: Duration,
+    /// Ключ идемпотентности с областью клиента, повтор с тем же ключом получает тот же платеж
+    pub idempotency_key: Option<&'a str>
+}
+
+/// Чем оплачивать созданный платеж
+#[derive(Debug)]
+pub enum CheckoutPayment{
+    /// Ссылка на страницу оплаты Fondy
+    Redirect{
+        payment_id: String,
+        checkout_url: String
+    },
+
+    /// Токен для виджета оплаты, идентификатор платежа придет только в коллбеке
+    Embedded{
+        token: String
+    }
+}
+
+/// Созданный платеж
+#[derive(Debug)]
+pub struct Checkout{
+    pub order_id: String,
+    pub amount: i64,
+    pub currency: &'static str,
+    pub expires_at: i64,
+    pub payment: CheckoutPayment
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Простая проверка адреса почты, полную проверку все равно делает Fondy
+pub fn validate_email(email: &str) -> Result<(), FondyError> {
+    let valid = email.len() <= 254 &&
+        !email.contains(char::is_whitespace) &&
+        matches!(email.split_once('@'), Some((user, domain)) if !user.is_empty() && domain.contains('.') && !domain.starts_with('.') && !domain.ends_with('.'));
+    if valid {
+        Ok(())
+    }else{
+        Err(FondyError::InvalidRequest(format!("invalid email: {}", email)))
+    }
+}
+
+/// Ключ идемпотентности: непустой, печатные ASCII символы без пробелов
+pub fn validate_idempotency_key(key: &str) -> Result<(), FondyError> {
+    let valid = !key.is_empty() &&
+        key.len() <= MAX_IDEMPOTENCY_KEY_LEN &&
+        key.chars().all(|c| c.is_ascii_graphic());
+    if valid {
+        Ok(())
+    }else{
+        Err(FondyError::InvalidRequest(format!("idempotency key must be 1 to {} printable ASCII characters", MAX_IDEMPOTENCY_KEY_LEN)))
+    }
+}
+
+/// Хеш параметров запроса, чтобы не отдать платеж по тому же ключу для другого заказа
+fn request_hash(request: &CheckoutRequest<'_>) -> String {
+    let mut sha = sha1::Sha1::new();
+    sha.update(request.mode.as_str());
+    for item in &request.items {
+        sha.update(format!("|{}:{}:{}", item.product_id, item.quantity, item.unit_price));
+    }
+    sha.update(format!("|{}", request.customer_email.unwrap_or_default()));
+    format!("{:x}", sha.finalize())
+}
+
+/// Уже созданный платеж заказа, None пока первый запрос его еще создает
+fn saved_checkout(order: OrderRecord, mode: CheckoutMode) -> Option<Checkout> {
+    let payment = match (mode, order.payment_id, order.checkout_url, order.checkout_token) {
+        (CheckoutMode::Redirect, Some(payment_id), Some(checkout_url), _) => CheckoutPayment::Redirect{
+            payment_id,
+            checkout_url
+        },
+        (CheckoutMode::Embedded, _, _, Some(token)) => CheckoutPayment::Embedded{
+            token
+        },
+        _ => return None
+    };
+    Some(Checkout{
+        order_id: order.order_id,
+        amount: order.amount,
+        currency: CHECKOUT_CURRENCY,
+        expires_at: order.expires_at.unwrap_or(order.created_at),
+        payment
+    })
+}
+
+/// Платеж, созданный раньше по тому же ключу идемпотентности.
+/// Если первый запрос еще ждет Fondy, ждем его результат не дольше `IDEMPOTENCY_WAIT`.
+async fn existing_checkout(db: &Database, config: &AppConfig, key: &str, hash: &str, mode: CheckoutMode) -> Result<Option<Checkout>, FondyError> {
+    let deadline = Instant::now() + IDEMPOTENCY_WAIT;
+    loop {
+        let since = unix_now() - config.orders.idempotency_window.as_secs() as i64;
+        let order = match db.get_order_by_idempotency_key(key, since).await? {
+            Some(order) => order,
+            None => return Ok(None)
+        };
+        if order.idempotency_hash.as_deref() != Some(hash) {
+            return Err(FondyError::IdempotencyKeyReused);
+        }
+
+        let order_id = order.order_id.clone();
+        if let Some(checkout) = saved_checkout(order, mode) {
+            info!(%order_id, "Returning existing checkout for repeated request");
+            return Ok(Some(checkout));
+        }
+        if Instant::now() >= deadline {
+            return Err(FondyError::IdempotentRequestInProgress);
+        }
+        tokio::time::sleep(IDEMPOTENCY_POLL_INTERVAL).await;
+    }
+}
+
+/// Позиции заказа по продуктам из каталога.
+/// Возвращает позиции с ценами, описание заказа и время жизни оплаты.
+#[instrument(skip(db, config))]
+pub async fn resolve_items(db: &Database, config: &AppConfig, requested: &[(i32, i64)]) -> Result<(Vec<NewOrderItem>, String, Duration), FondyError> {
+    if requested.is_empty() {
+        return Err(FondyError::InvalidRequest("order must contain at least one item".to_owned()));
+    }
+
+    let mut items = Vec::with_capacity(requested.len());
+    let mut names = Vec::with_capacity(requested.len());
+    let mut lifetime: Option<Duration> = None;
+    for (product_id, quantity) in requested.iter().copied() {
+        if quantity <= 0 || quantity > MAX_ITEM_QUANTITY {
+            return Err(FondyError::InvalidRequest(format!("quantity must be from 1 to {}", MAX_ITEM_QUANTITY)));
+        }
+        let product = db
+            .get_product(product_id)
+            .await?
+            .ok_or(FondyError::UnknownProduct(product_id))?;
+
+        // У заказа из нескольких продуктов берем самое короткое время оплаты
+        if let Some(secs) = product.checkout_lifetime_secs.filter(|val| *val > 0) {
+            let product_lifetime = Duration::from_secs(secs as u64);
+            lifetime = Some(lifetime.map_or(product_lifetime, |val| val.min(product_lifetime)));
+        }
+
+        names.push(format!("{} x{}", product.product_name, quantity));
+        items.push(NewOrderItem{
+            product_id: product.product_id,
+            quantity,
+            unit_price: product.price
+        });
+    }
+
+    Ok((items, names.join(", "), lifetime.unwrap_or(config.orders.checkout_lifetime)))
+}
+
+/// Сохраняет заказ и создает для него платеж в Fondy.
+/// Заказ сохраняется до запроса к Fondy, чтобы сверка нашла его даже если запрос упадет.
+/// Повторный запрос с тем же ключом идемпотентности вместо нового заказа получает уже созданный платеж.
+#[instrument(skip(fondy, db, config))]
+pub async fn create_checkout(fondy: &FondyClient, db: &Database, config: &AppConfig, request: CheckoutRequest<'_>) -> Result<Checkout, FondyError> {
+    let hash = request_hash(&request);
+    loop {
+        if let Some(key) = request.idempotency_key {
+            if let Some(checkout) = existing_checkout(db, config, key, &hash, request.mode).await? {
+                return Ok(checkout);
+            }
+        }
+
+        let order_id = uuid::Uuid::new_v4().to_string();
+        if let Some(checkout) = create_order_checkout(fondy, db, config, &request, &order_id, &hash).await? {
+            return Ok(checkout);
+        }
+        // Параллельный запрос с тем же ключом успел сохранить заказ, ждем его платеж
+    }
+}
+
+/// Сохраняет заказ и создает платеж, None если заказ с тем же ключом идемпотентности уже есть
+async fn create_order_checkout(fondy: &FondyClient, db: &Database, config: &AppConfig, request: &CheckoutRequest<'_>, order_id: &str, hash: &str) -> Result<Option<Checkout>, FondyError> {
+    let amount: i64 = request
+        .items
+        .iter()
+        .map(|item| item.unit_price * item.quantity)
+        .sum();
+    if amount <= 0 {
+        return Err(FondyError::InvalidRequest("order amount must be greater than zero".to_owned()));
+    }
+    let expires_at = unix_now() + request.lifetime.as_secs() as i64;
+
+    // Идентификатор продукта в Fondy передается только для заказа из одного продукта
+    let product_id = match request.items.as_slice() {
+        [item] => Some(item.product_id),
+        _ => None
+    };
+
+    let idempotency_since = unix_now() - config.orders.idempotency_window.as_secs() as i64;
+    let inserted = db
+        .insert_order(NewOrder{
+            order_id,
+            product_id,
+            amount,
+            currency: CHECKOUT_CURRENCY,
+            expires_at: Some(expires_at),
+            customer_email: request.customer_email,
+            items: &request.items,
+            idempotency: request.idempotency_key.map(|key| (key, hash))
+        }, EventSource::Checkout.as_str(), idempotency_since)
+        .await
+        .tap_err(|err| { error!("Order save failed: {}", err); })?;
+    if !inserted {
+        return Ok(None);
+    }
+
+    let payment = match request_payment(fondy, db, config, request, order_id, amount, product_id).await {
+        Ok(payment) => payment,
+        Err(err) => {
+            // Повтор с тем же ключом должен создать новый заказ, а не ждать платеж этого
+            if request.idempotency_key.is_some() {
+                db.release_order_idempotency_key(order_id)
+                    .await
+                    .tap_err(|err| { error!("Order idempotency key release failed: {}", err); })
+                    .ok();
+            }
+            return Err(err);
+        }
+    };
+    checkout_created(request.mode.as_str());
+
+    Ok(Some(Checkout{
+        order_id: order_id.to_owned(),
+        amount,
+        currency: CHECKOUT_CURRENCY,
+        expires_at,
+        payment
+    }))
+}
+
+/// Запрос платежа для сохраненного заказа в Fondy
+async fn request_payment(fondy: &FondyClient, db: &Database, config: &AppConfig, request: &CheckoutRequest<'_>, order_id: &str, amount: i64, product_id: Option<i32>) -> Result<CheckoutPayment, FondyError> {
+    // Адрес, куда будет редиректиться браузер
+    let browser_redirect_url = config
+        .site_url
+        .join("browser_redirect_callback_url")
+        .map_err(FondyError::from)
+        .tap_err(|err| { error!("Url join error: {}", err); })?;
+    debug!("Browser callback url: {}", browser_redirect_url);
+
+    // Коллбека на нашем сервере
+    let server_callback_url = config
+        .site_url
+        .join("purchase_server_callback_url")
+        .map_err(FondyError::from)
+        .tap_err(|err| { error!("Url join error: {}", err); })?;
+    debug!("Server callback url: {}", server_callback_url);
+
+    // Данные, которые будут в коллбеке
+    let callback_data = "our_custom_payload";
+
+    // Все параметры, идентификатор продавца и подпись добавит клиент
+    let mut parameters = json!({
+        "order_id": order_id,
+        "order_desc": request.description,
+        "amount": amount,
+        "currency": CHECKOUT_CURRENCY,
+        "version": FONDY_PROTOCOL_VERSION,
+        "merchant_data": callback_data,
+        "server_callback_url": server_callback_url.as_str(),
+        // Страница ожидания оплаты
+        "response_url": browser_redirect_url.as_str(),
+        "lifetime": request.lifetime.as_secs()
+        // "payment_systems": "card, banklinks_eu, banklinks_pl",
+        // "default_payment_system": "card",
+        // "preauth": "N" // Тип снятия денег
+        // "delayed": "Y"
+        // "lang": "ru"
+        // "required_rectoken": "N"         // Получение токена для будущих автоматических оплат
+        // "rectoken": "AAAA"               // Токен, по которому можно будет автоматически списывать деньги потом
+        // "receiver_rectoken": "AAAA"      // Токен карты, по которому можно кредитовать карту, не передавая полный номер карты
+        // "verification": "N"
+        // "verification_type": "amount"
+        // "design_id"                      // Кастомный дизайн
+        // "subscription"                   // Подписка на периодические платежи
+        // "subscription_callback_url"      // URL коллбека, куда будет перенаправлен покупатель при периодической покупке
+    });
+    if let Some(product_id) = product_id {
+        parameters["product_id"] = json!(product_id.to_string());
+    }
+    if let Some(email) = request.customer_email {
+        parameters["sender_email"] = json!(email);
+    }
+
+    // Параметры: https://docs.fondy.eu/ru/docs/page/3/
+    let payment = match request.mode {
+        CheckoutMode::Redirect => {
+            let response = fondy
+                .checkout_url(parameters)
+                .await?;
+
+            db.set_order_checkout(order_id, &response.payment_id, &response.checkout_url)
+                .await
+                .tap_err(|err| { error!("Order checkout save failed: {}", err); })?;
+
+            CheckoutPayment::Redirect{
+                payment_id: response.payment_id,
+                checkout_url: response.checkout_url
+            }
+        },
+        CheckoutMode::Embedded => {
+            let response = fondy
+                .checkout_token(parameters)
+                .await?;
+
+            db.set_order_checkout_token(order_id, &response.token)
+                .await
+                .tap_err(|err| { error!("Order checkout token save failed: {}", err); })?;
+
+            CheckoutPayment::Embedded{
+                token: response.token
+            }
+        }
+    };
+    Ok(payment)
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_validate_email(){
+        assert!(validate_email("test@gmail.com").is_ok());
+        assert!(validate_email("test@gmail").is_err());
+        assert!(validate_email("@gmail.com").is_err());
+        assert!(validate_email("te st@gmail.com").is_err());
+    }
+
+    #[test]
+    fn test_validate_idempotency_key(){
+        assert!(validate_idempotency_key("7f0c2c3e-5d4b-4a57-9a53-0f3c2e9b1d11").is_ok());
+        assert!(validate_idempotency_key("").is_err());
+        assert!(validate_idempotency_key("with space").is_err());
+        assert!(validate_idempotency_key(&"k".repeat(MAX_IDEMPOTENCY_KEY_LEN + 1)).is_err());
+    }
+}
@@ -0,0 +1,920 @@
+use std::{
+    io::{
+        BufRead
+    },
+    path::{
+        PathBuf
+    },
+    sync::{
+        Arc
+    }
+};
+use serde::{
+    Deserialize
+};
+use structopt::{
+    StructOpt
+};
+use chrono::{
+    Duration as ChronoDuration,
+    NaiveDate,
+    Utc
+};
+use tap::{
+    TapFallible
+};
+use tracing::{
+    error,
+    info,
+    warn
+};
+use crate::{
+    application::{
+        Application
+    },
+    auth::{
+        Role,
+        audit,
+        audit_outcome,
+        audit_requested,
+        generate_token,
+        hash_password,
+        token_hash
+    },
+    http::{
+        ADMIN_TEMPLATES,
+        PAGE_TEMPLATES,
+        bind_listeners,
+        start_server
+    },
+    health::{
+        Heartbeats
+    },
+    shutdown::{
+        Shutdown,
+        wait_for_signal
+    },
+    config::{
+        AppConfig
+    },
+    secret::{
+        Secret
+    },
+    database::{
+        Database
+    },
+    error::{
+        FondyError
+    },
+    fondy::{
+        FondyClient,
+        calculate_signature,
+        verify_callback_signature
+    },
+    callbacks::{
+        replay_callback
+    },
+    orders::{
+        StatusEvents,
+        STATUS_EVENTS_CAPACITY
+    },
+    reconciliation::{
+        reconcile_pending_orders,
+        run_reconciliation_worker
+    },
+    expiry::{
+        expire_orders,
+        run_expiry_worker
+    },
+    settlements::{
+        import_settlements,
+        settlement_report,
+        run_settlement_worker
+    },
+    ledger::{
+        daily_balances,
+        record_chargeback
+    }
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Сервис оплаты через Fondy.
+/// Без указания команды запускается сервер.
+#[derive(Debug, StructOpt)]
+pub struct CliArgs{
+    #[structopt(subcommand)]
+    pub command: Option<Command>
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command{
+    /// Запуск HTTP сервера
+    Serve,
+
+    /// Работа с миграциями базы данных
+    Migrate(MigrateCommand),
+
+    /// Вычисление подписи Fondy для JSON файла с параметрами запроса
+    Sign{
+        #[structopt(parse(from_os_str))]
+        file: PathBuf
+    },
+
+    /// Проверка подписи сохраненного тела серверного коллбека
+    VerifyCallback{
+        #[structopt(parse(from_os_str))]
+        file: PathBuf
+    },
+
+    /// Операции с заказом через API Fondy
+    Order(OrderCommand),
+
+    /// Работа с продуктами
+    Products(ProductsCommand),
+
+    /// Журнал пришедших коллбеков
+    Callbacks(CallbacksCommand),
+
+    /// Однократная сверка незавершенных заказов с Fondy
+    Reconcile{
+        /// Проверять только заказы старше указанного количества секунд
+        #[structopt(long)]
+        min_age_secs: Option<u64>
+    },
+
+    /// Однократная отметка заказов с истекшим сроком оплаты
+    Expire{
+        /// Запас после срока оплаты в секундах
+        #[structopt(long)]
+        grace_secs: Option<u64>
+    },
+
+    /// Сверка расчетов с отчетами Fondy
+    Settlements(SettlementsCommand),
+
+    /// Бухгалтерский учет по двойной записи
+    Ledger(LedgerCommand),
+
+    /// Пользователи админки
+    Users(UsersCommand),
+
+    /// Ключи API для машинных клиентов
+    ApiKeys(ApiKeysCommand),
+
+    /// Журнал привилегированных действий
+    Audit{
+        #[structopt(long, default_value = "50")]
+        limit: i64
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum MigrateCommand{
+    /// Применить новые миграции
+    Apply,
+
+    /// Показать состояние миграций
+    Status
+}
+
+#[derive(Debug, StructOpt)]
+pub enum OrderCommand{
+    /// Запросить статус заказа
+    Status{
+        order_id: String
+    },
+
+    /// Вернуть средства, без указания суммы возвращается еще не возвращенный остаток оплаты
+    Refund{
+        order_id: String,
+
+        /// Сумма в минимальных единицах валюты (центах)
+        #[structopt(long)]
+        amount: Option<u64>,
+
+        #[structopt(long, default_value = "Refund")]
+        comment: String
+    },
+
+    /// Списать заблокированные средства, без указания суммы списывается еще не возвращенный остаток
+    Capture{
+        order_id: String,
+
+        /// Сумма в минимальных единицах валюты (центах)
+        #[structopt(long)]
+        amount: Option<u64>
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ProductsCommand{
+    /// Импорт продуктов из CSV файла с колонками product_name,price и опционально checkout_lifetime_secs
+    Import{
+        #[structopt(parse(from_os_str))]
+        file: PathBuf
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum CallbacksCommand{
+    /// Последние сохраненные коллбеки
+    List{
+        /// Только коллбеки, обработка которых завершилась ошибкой
+        #[structopt(long)]
+        failed: bool,
+
+        #[structopt(long, default_value = "20")]
+        limit: i64
+    },
+
+    /// Показать коллбек целиком
+    Show{
+        callback_id: i64
+    },
+
+    /// Повторно обработать сохраненный серверный коллбек
+    Replay{
+        callback_id: i64
+    }
+}
+
+/// Период по дням включительно, без указания берется вчерашний день в UTC
+#[derive(Debug, StructOpt)]
+pub struct PeriodArgs{
+    /// Первый день, YYYY-MM-DD
+    #[structopt(long)]
+    from: Option<NaiveDate>,
+
+    /// Последний день, YYYY-MM-DD
+    #[structopt(long)]
+    to: Option<NaiveDate>
+}
+
+impl PeriodArgs {
+    fn resolve(&self) -> Result<(NaiveDate, NaiveDate), FondyError> {
+        let yesterday = Utc::today().naive_utc() - ChronoDuration::days(1);
+        let from = self.from.unwrap_or(yesterday);
+        let to = self.to.unwrap_or_else(|| self.from.unwrap_or(yesterday));
+        if from > to {
+            return Err(FondyError::Custom(format!("Period start {} is after end {}", from, to)));
+        }
+        Ok((from, to))
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum SettlementsCommand{
+    /// Загрузить транзакции за период из отчета Fondy
+    Import{
+        #[structopt(flatten)]
+        period: PeriodArgs
+    },
+
+    /// Отчет о расхождениях за период по загруженным транзакциям
+    Report{
+        #[structopt(flatten)]
+        period: PeriodArgs,
+
+        /// Сохранить отчет в CSV файл
+        #[structopt(long, parse(from_os_str))]
+        csv: Option<PathBuf>
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum LedgerCommand{
+    /// Остатки по счетам на конец каждого дня периода
+    Balances{
+        #[structopt(flatten)]
+        period: PeriodArgs,
+
+        /// Сохранить остатки в CSV файл
+        #[structopt(long, parse(from_os_str))]
+        csv: Option<PathBuf>
+    },
+
+    /// Проверить, что все проводки сбалансированы
+    Check,
+
+    /// Записать чарджбек по заказу
+    Chargeback{
+        order_id: String,
+
+        /// Сумма в минимальных единицах валюты, без указания - вся сумма заказа
+        #[structopt(long)]
+        amount: Option<i64>
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum UsersCommand{
+    /// Добавить пользователя, пароль читается из stdin
+    Add{
+        username: String,
+
+        /// viewer, support, finance или admin
+        #[structopt(long, default_value = "viewer")]
+        role: Role
+    },
+
+    /// Список пользователей
+    List,
+
+    /// Сменить пароль, пароль читается из stdin, сессии пользователя закрываются
+    Password{
+        username: String
+    },
+
+    /// Отключить пользователя
+    Disable{
+        username: String
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ApiKeysCommand{
+    /// Создать ключ, сам ключ показывается только один раз
+    Create{
+        key_name: String,
+
+        /// viewer, support, finance или admin
+        #[structopt(long, default_value = "support")]
+        role: Role
+    },
+
+    /// Список ключей
+    List,
+
+    /// Отозвать ключ
+    Revoke{
+        key_id: i64
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Выполняет команду.
+/// Офлайн командам подписи нужен только пароль продавца, остальные загружают конфиг целиком.
+pub async fn execute_command(command: Command) -> Result<(), FondyError> {
+    let command = match command {
+        Command::Sign{ file } => {
+            return sign(&AppConfig::load_merchant_password()?, file);
+        },
+        Command::VerifyCallback{ file } => {
+            return verify_callback(&AppConfig::load_merchant_password()?, file);
+        },
+        command => command
+    };
+
+    // Конфиг из файла и окружения, все ошибки выводятся сразу
+    let config = AppConfig::load()?;
+    match command {
+        Command::Serve => {
+            serve(config).await
+        },
+        Command::Migrate(command) => {
+            migrate(&config, command).await
+        },
+        Command::Sign{ .. } | Command::VerifyCallback{ .. } => {
+            unreachable!("Offline commands are executed without full config")
+        },
+        Command::Order(command) => {
+            order(&config, command).await
+        },
+        Command::Products(ProductsCommand::Import{ file }) => {
+            import_products(&config, file).await
+        },
+        Command::Callbacks(command) => {
+            callbacks(&config, command).await
+        },
+        Command::Reconcile{ min_age_secs } => {
+            reconcile(&config, min_age_secs).await
+        },
+        Command::Expire{ grace_secs } => {
+            expire(&config, grace_secs).await
+        },
+        Command::Settlements(command) => {
+            settlements(&config, command).await
+        },
+        Command::Ledger(command) => {
+            ledger(&config, command).await
+        },
+        Command::Users(command) => {
+            users(&config, command).await
+        },
+        Command::ApiKeys(command) => {
+            api_keys(&config, command).await
+        },
+        Command::Audit{ limit } => {
+            audit_log(&config, limit).await
+        }
+    }
+}
+
+async fn serve(config: AppConfig) -> Result<(), FondyError> {
+    // База данных
+    let db = Arc::new(Database::open_database(&config.database)
+        .await?);
+
+    // Шаблоны HTML
+    let mut templates = handlebars::Handlebars::new();
+    {
+        for name in PAGE_TEMPLATES {
+            templates.register_template_file(name, format!("templates/{}.hbs", name))
+                .tap_err(|err| { error!("Page template read failed: {}", err); })?;
+        }
+
+        // Страницы админки, шапка и подвал подключаются в них как partial
+        for name in ADMIN_TEMPLATES {
+            templates.register_template_file(&format!("admin/{}", name), format!("templates/admin/{}.hbs", name))
+                .tap_err(|err| { error!("Admin template read failed: {}", err); })?;
+        }
+    }
+
+    // Приложение со всеми нужными нам менеджерами
+    let app = Arc::new(Application{
+        db,
+        templates: Arc::new(templates),
+        fondy: FondyClient::new(&config)?,
+        config: Arc::new(config),
+        status_events: StatusEvents::new(STATUS_EVENTS_CAPACITY),
+        heartbeats: Heartbeats::default(),
+        shutdown: Shutdown::new()
+    });
+
+    // Сокеты открываются до фоновых задач, занятый порт или битый сертификат останавливают запуск
+    let listeners = bind_listeners(&app.config.server)
+        .await?;
+
+    // Фоновые задачи
+    let mut workers = Vec::new();
+
+    // Фоновая сверка зависших заказов
+    if app.config.reconciliation.enabled {
+        let heartbeat = app.heartbeats.register("reconciliation", app.config.reconciliation.interval);
+        workers.push(tokio::spawn(run_reconciliation_worker(app.db.clone(), app.fondy.clone(), app.config.reconciliation.clone(), heartbeat, app.shutdown.listener())));
+    }
+
+    // Фоновая отметка просроченных заказов
+    if app.config.expiry.enabled {
+        let heartbeat = app.heartbeats.register("expiry", app.config.expiry.interval);
+        workers.push(tokio::spawn(run_expiry_worker(app.db.clone(), app.fondy.clone(), app.config.expiry.clone(), heartbeat, app.shutdown.listener())));
+    }
+
+    // Ежедневная сверка расчетов
+    if app.config.settlement.enabled {
+        let heartbeat = app.heartbeats.register("settlement", app.config.settlement.interval);
+        workers.push(tokio::spawn(run_settlement_worker(app.db.clone(), app.fondy.clone(), app.config.settlement.clone(), heartbeat, app.shutdown.listener())));
+    }
+
+    // Стартуем сервер, после сигнала он перестает принимать соединения и дожидается текущих запросов
+    let server = start_server(app.clone(), listeners);
+    tokio::pin!(server);
+    let server_stopped = tokio::select!{
+        _ = wait_for_signal() => false,
+        _ = &mut server => {
+            // Без сигнала остановки сервер не завершается
+            warn!("Server stopped unexpectedly");
+            true
+        }
+    };
+    app.shutdown.trigger();
+
+    // Ждем запросы и фоновые задачи, но не дольше таймаута
+    let shutdown_timeout = app.config.server.shutdown_timeout;
+    info!(timeout = ?shutdown_timeout, "Shutdown started, waiting for in-flight requests and workers");
+    let drain = async {
+        if !server_stopped {
+            server.await;
+        }
+        futures::future::join_all(workers.iter_mut()).await;
+    };
+    match tokio::time::timeout(shutdown_timeout, drain).await {
+        Ok(()) => info!("In-flight requests and workers finished"),
+        Err(_) => {
+            // Зависшие задачи держат соединения с базой, без отмены пул не закроется
+            warn!("Shutdown timeout elapsed, unfinished work is interrupted");
+            for worker in workers.iter() {
+                worker.abort();
+            }
+        }
+    }
+
+    app.db.close().await;
+    info!("Shutdown complete");
+
+    Ok(())
+}
+
+async fn migrate(config: &AppConfig, command: MigrateCommand) -> Result<(), FondyError> {
+    let db = Database::connect(&config.database)
+        .await?;
+    match command {
+        MigrateCommand::Apply => {
+            db.migrate().await?;
+            println!("Migrations applied");
+        },
+        MigrateCommand::Status => {
+            println!("Database backend: {:?}", db.kind());
+            for migration in db.migration_status().await? {
+                let status = if migration.applied { "applied" } else { "pending" };
+                println!("{} {:<8} {}", migration.version, status, migration.description);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_json(file: PathBuf) -> Result<serde_json::Value, FondyError> {
+    let text = std::fs::read_to_string(file)?;
+    let data = serde_json::from_str::<serde_json::Value>(&text)?;
+
+    // Допускаем как сами параметры, так и обертку {"request": {...}}
+    match data {
+        serde_json::Value::Object(mut map) if map.len() == 1 && map.contains_key("request") => {
+            Ok(map.remove("request").unwrap_or_default())
+        },
+        data => Ok(data)
+    }
+}
+
+fn sign(merchant_password: &Secret, file: PathBuf) -> Result<(), FondyError> {
+    let data = read_json(file)?;
+    let signature = calculate_signature(merchant_password, &data, &["signature", "response_signature_string"])?;
+    println!("{}", signature);
+    Ok(())
+}
+
+fn verify_callback(merchant_password: &Secret, file: PathBuf) -> Result<(), FondyError> {
+    let data = read_json(file)?;
+    if verify_callback_signature(merchant_password, &data)? {
+        println!("Signature is valid");
+        Ok(())
+    }else{
+        Err(FondyError::Custom("Signature is invalid".to_owned()))
+    }
+}
+
+/// Действия из командной строки записываются в журнал от этого имени
+const CLI_ACTOR: &str = "cli";
+
+async fn order(config: &AppConfig, command: OrderCommand) -> Result<(), FondyError> {
+    let fondy = FondyClient::new(config)?;
+    match command {
+        OrderCommand::Status{ order_id } => {
+            // Сначала локальное состояние заказа, затем данные Fondy
+            let db = Database::open_database(&config.database)
+                .await?;
+            match db.get_order(&order_id).await? {
+                Some(order) => {
+                    println!("order_id: {}", order.order_id);
+                    println!("product_id: {}", order.product_id.map(|val| val.to_string()).unwrap_or_else(|| "-".to_owned()));
+                    println!("amount: {} {}", order.amount, order.currency);
+                    println!("status: {}", order.order_status);
+                    println!("payment_id: {}", order.payment_id.as_deref().unwrap_or("-"));
+                    println!("checkout_url: {}", order.checkout_url.as_deref().unwrap_or("-"));
+                    println!("created_at: {}, updated_at: {}", order.created_at, order.updated_at);
+                    println!("expires_at: {}", order.expires_at.map(|val| val.to_string()).unwrap_or_else(|| "-".to_owned()));
+                    println!("customer_email: {}", order.customer_email.as_deref().unwrap_or("-"));
+                    for item in db.list_order_items(&order_id).await? {
+                        println!("item: product {} x{} by {}", item.product_id, item.quantity, item.unit_price);
+                    }
+                    for event in db.list_order_events(&order_id).await? {
+                        println!("{} {} {} -> {}",
+                                 event.created_at,
+                                 event.event_source,
+                                 event.from_status.as_deref().unwrap_or("-"),
+                                 event.to_status);
+                    }
+                },
+                None => {
+                    println!("Local order is missing");
+                }
+            }
+            let status = fondy.order_status(&order_id).await?;
+            println!("Fondy order: {:#?}", status);
+        },
+        OrderCommand::Refund{ order_id, amount, comment } => {
+            let status = fondy.order_status(&order_id).await?;
+            let amount = amount.unwrap_or_else(|| status.remaining_amount());
+            info!("Refund {} {} for order {}", amount, status.currency, order_id);
+            let db = Database::open_database(&config.database)
+                .await?;
+            let audit_id = audit_requested(&db, CLI_ACTOR, "refund", Some(&order_id), Some(&format!("{} {}: {}", amount, status.currency, comment))).await?;
+            let result = fondy.reverse(&order_id, amount, &status.currency, &comment).await;
+            audit_outcome(&db, audit_id, &result).await;
+            println!("{:#?}", result?);
+        },
+        OrderCommand::Capture{ order_id, amount } => {
+            let status = fondy.order_status(&order_id).await?;
+            let amount = amount.unwrap_or_else(|| status.remaining_amount());
+            info!("Capture {} {} for order {}", amount, status.currency, order_id);
+            let db = Database::open_database(&config.database)
+                .await?;
+            let audit_id = audit_requested(&db, CLI_ACTOR, "capture", Some(&order_id), Some(&format!("{} {}", amount, status.currency))).await?;
+            let result = fondy.capture(&order_id, amount, &status.currency).await;
+            audit_outcome(&db, audit_id, &result).await;
+            println!("{:#?}", result?);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct ProductRecord{
+    product_name: String,
+    price: i64,
+    #[serde(default)]
+    checkout_lifetime_secs: Option<i64>
+}
+
+async fn import_products(config: &AppConfig, file: PathBuf) -> Result<(), FondyError> {
+    let db = Database::open_database(&config.database)
+        .await?;
+
+    // Сначала читаем весь файл, чтобы не импортировать половину при ошибке в данных
+    let records = csv::Reader::from_path(file)?
+        .deserialize::<ProductRecord>()
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for record in records.iter() {
+        db.upsert_product(&record.product_name, record.price, record.checkout_lifetime_secs).await?;
+    }
+    println!("Imported {} products", records.len());
+
+    Ok(())
+}
+
+async fn callbacks(config: &AppConfig, command: CallbacksCommand) -> Result<(), FondyError> {
+    let db = Database::open_database(&config.database)
+        .await?;
+    match command {
+        CallbacksCommand::List{ failed, limit } => {
+            for record in db.list_callback_logs(failed, limit).await? {
+                println!("{:>6} {} {:<7} {:<15} {:<36} signature_valid={:<5} {}",
+                         record.callback_id,
+                         record.received_at,
+                         record.callback_kind,
+                         record.source_ip.as_deref().unwrap_or("-"),
+                         record.order_id.as_deref().unwrap_or("-"),
+                         record.signature_valid.map(|val| val.to_string()).unwrap_or_else(|| "-".to_owned()),
+                         record.processing_result.as_deref().unwrap_or("-"));
+            }
+        },
+        CallbacksCommand::Show{ callback_id } => {
+            let record = db
+                .get_callback_log(callback_id)
+                .await?
+                .ok_or_else(|| FondyError::Custom(format!("Callback {} is not found", callback_id)))?;
+            println!("callback_id: {}", record.callback_id);
+            println!("received_at: {}", record.received_at);
+            println!("kind: {}", record.callback_kind);
+            println!("source_ip: {}", record.source_ip.as_deref().unwrap_or("-"));
+            println!("order_id: {}", record.order_id.as_deref().unwrap_or("-"));
+            println!("signature_valid: {:?}", record.signature_valid);
+            println!("processing_result: {}", record.processing_result.as_deref().unwrap_or("-"));
+            println!("replayed_at: {}", record.replayed_at.map(|val| val.to_string()).unwrap_or_else(|| "-".to_owned()));
+            println!("headers: {}", record.headers);
+            println!("body: {}", record.body);
+        },
+        CallbacksCommand::Replay{ callback_id } => {
+            let data = replay_callback(&db, config, callback_id).await?;
+            println!("Callback {} replayed, order {} status {:?}", callback_id, data.order_id, data.order_status);
+        }
+    }
+    Ok(())
+}
+
+async fn reconcile(config: &AppConfig, min_age_secs: Option<u64>) -> Result<(), FondyError> {
+    let db = Database::open_database(&config.database)
+        .await?;
+    let fondy = FondyClient::new(config)?;
+    let min_age = min_age_secs
+        .map(std::time::Duration::from_secs)
+        .unwrap_or(config.reconciliation.min_age);
+
+    let report = reconcile_pending_orders(&db, &fondy, min_age, config.reconciliation.batch_size)
+        .await?;
+    println!("Checked: {}, updated: {}, unchanged: {}, rejected: {}",
+             report.checked, report.updated, report.unchanged, report.rejected);
+    for order_id in report.unknown_in_fondy.iter() {
+        println!("Unknown to Fondy: {}", order_id);
+    }
+    for (order_id, err) in report.failed.iter() {
+        println!("Failed: {} {}", order_id, err);
+    }
+    Ok(())
+}
+
+async fn expire(config: &AppConfig, grace_secs: Option<u64>) -> Result<(), FondyError> {
+    let db = Database::open_database(&config.database)
+        .await?;
+    let fondy = FondyClient::new(config)?;
+    let grace = grace_secs
+        .map(std::time::Duration::from_secs)
+        .unwrap_or(config.expiry.grace);
+
+    let report = expire_orders(&db, &fondy, grace, config.expiry.batch_size)
+        .await?;
+    println!("Checked: {}, expired: {}, updated: {}, still processing: {}",
+             report.checked, report.expired, report.updated, report.still_processing);
+    for (order_id, err) in report.failed.iter() {
+        println!("Failed: {} {}", order_id, err);
+    }
+    Ok(())
+}
+
+async fn settlements(config: &AppConfig, command: SettlementsCommand) -> Result<(), FondyError> {
+    let db = Database::open_database(&config.database)
+        .await?;
+    match command {
+        SettlementsCommand::Import{ period } => {
+            let (from, to) = period.resolve()?;
+            let fondy = FondyClient::new(config)?;
+            let count = import_settlements(&db, &fondy, from, to).await?;
+            println!("Imported {} transactions for {} - {}", count, from, to);
+        },
+        SettlementsCommand::Report{ period, csv } => {
+            let (from, to) = period.resolve()?;
+            let report = settlement_report(&db, from, to).await?;
+            match csv {
+                Some(path) => {
+                    let mut writer = csv::Writer::from_path(path)?;
+                    for discrepancy in report.iter() {
+                        writer.serialize(discrepancy)?;
+                    }
+                    writer.flush()?;
+                },
+                None => {
+                    for discrepancy in report.iter() {
+                        println!("{:<15} {:<36} local={} {} {} fondy={} {} {} payment_id={} settlement={} {} {} fee={}",
+                                 format!("{:?}", discrepancy.kind),
+                                 discrepancy.order_id,
+                                 discrepancy.local_status.as_deref().unwrap_or("-"),
+                                 discrepancy.local_amount.map(|val| val.to_string()).unwrap_or_else(|| "-".to_owned()),
+                                 discrepancy.local_currency.as_deref().unwrap_or("-"),
+                                 discrepancy.fondy_status.as_deref().unwrap_or("-"),
+                                 discrepancy.fondy_amount.map(|val| val.to_string()).unwrap_or_else(|| "-".to_owned()),
+                                 discrepancy.fondy_currency.as_deref().unwrap_or("-"),
+                                 discrepancy.payment_id.as_deref().unwrap_or("-"),
+                                 discrepancy.settlement_amount.map(|val| val.to_string()).unwrap_or_else(|| "-".to_owned()),
+                                 discrepancy.settlement_currency.as_deref().unwrap_or("-"),
+                                 discrepancy.settlement_date.as_deref().unwrap_or("-"),
+                                 discrepancy.fee.map(|val| val.to_string()).unwrap_or_else(|| "-".to_owned()));
+                    }
+                }
+            }
+            println!("Discrepancies for {} - {}: {}", from, to, report.len());
+        }
+    }
+    Ok(())
+}
+
+async fn ledger(config: &AppConfig, command: LedgerCommand) -> Result<(), FondyError> {
+    let db = Database::open_database(&config.database)
+        .await?;
+    match command {
+        LedgerCommand::Balances{ period, csv } => {
+            let (from, to) = period.resolve()?;
+            let balances = daily_balances(&db, from, to).await?;
+            match csv {
+                Some(path) => {
+                    let mut writer = csv::Writer::from_path(path)?;
+                    for balance in balances.iter() {
+                        writer.serialize(balance)?;
+                    }
+                    writer.flush()?;
+                },
+                None => {
+                    for balance in balances.iter() {
+                        println!("{} {:<18} {:<3} {:>12}", balance.date, balance.account, balance.currency, balance.balance);
+                    }
+                }
+            }
+        },
+        LedgerCommand::Check => {
+            let entries = db.unbalanced_ledger_entries().await?;
+            for entry in entries.iter() {
+                println!("Unbalanced: {} {}", entry.entry_key, entry.amount);
+            }
+            if !entries.is_empty() {
+                return Err(FondyError::UnbalancedLedgerEntry(entries[0].entry_key.clone()));
+            }
+            println!("All ledger entries are balanced");
+        },
+        LedgerCommand::Chargeback{ order_id, amount } => {
+            let order = db
+                .get_order(&order_id)
+                .await?
+                .ok_or_else(|| FondyError::UnknownOrder(order_id.clone()))?;
+            let amount = amount.unwrap_or(order.amount);
+            if record_chargeback(&db, &order_id, &order.currency, amount).await? {
+                println!("Chargeback {} {} recorded for order {}", amount, order.currency, order_id);
+            }else{
+                println!("Chargeback for order {} is already recorded", order_id);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Пароль читается первой строкой stdin, чтобы не оставлять его в истории команд
+fn read_password() -> Result<String, FondyError> {
+    let mut password = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut password)?;
+    let password = password.trim_end_matches(&['\r', '\n'][..]).to_owned();
+    if password.is_empty() {
+        return Err(FondyError::Custom("Password is empty".to_owned()));
+    }
+    Ok(password)
+}
+
+async fn users(config: &AppConfig, command: UsersCommand) -> Result<(), FondyError> {
+    let db = Database::open_database(&config.database)
+        .await?;
+    match command {
+        UsersCommand::Add{ username, role } => {
+            let password_hash = hash_password(&read_password()?)?;
+            let user_id = db.insert_user(&username, &password_hash, role.as_str()).await?;
+            audit(&db, CLI_ACTOR, "user_add", Some(&username), Some(role.as_str())).await?;
+            println!("User {} added with id {}", username, user_id);
+        },
+        UsersCommand::List => {
+            for user in db.list_users().await? {
+                println!("{:>6} {:<24} {:<8} created_at={} disabled_at={}",
+                         user.user_id,
+                         user.username,
+                         user.user_role,
+                         user.created_at,
+                         user.disabled_at.map(|val| val.to_string()).unwrap_or_else(|| "-".to_owned()));
+            }
+        },
+        UsersCommand::Password{ username } => {
+            let password_hash = hash_password(&read_password()?)?;
+            if !db.set_user_password(&username, &password_hash).await? {
+                return Err(FondyError::Custom(format!("User {} is not found", username)));
+            }
+            audit(&db, CLI_ACTOR, "user_password", Some(&username), None).await?;
+            println!("Password for {} changed", username);
+        },
+        UsersCommand::Disable{ username } => {
+            if !db.disable_user(&username).await? {
+                return Err(FondyError::Custom(format!("User {} is not found or already disabled", username)));
+            }
+            audit(&db, CLI_ACTOR, "user_disable", Some(&username), None).await?;
+            println!("User {} disabled", username);
+        }
+    }
+    Ok(())
+}
+
+async fn api_keys(config: &AppConfig, command: ApiKeysCommand) -> Result<(), FondyError> {
+    let db = Database::open_database(&config.database)
+        .await?;
+    match command {
+        ApiKeysCommand::Create{ key_name, role } => {
+            // В базе только хеш, поэтому ключ можно показать лишь сейчас
+            let key = generate_token();
+            let key_id = db.insert_api_key(&token_hash(&key), &key_name, role.as_str()).await?;
+            audit(&db, CLI_ACTOR, "api_key_create", Some(&key_id.to_string()), Some(&format!("{} {}", key_name, role.as_str()))).await?;
+            println!("API key {} created, save it now: {}", key_id, key);
+        },
+        ApiKeysCommand::List => {
+            for key in db.list_api_keys().await? {
+                println!("{:>6} {:<24} {:<8} created_at={} revoked_at={}",
+                         key.key_id,
+                         key.key_name,
+                         key.key_role,
+                         key.created_at,
+                         key.revoked_at.map(|val| val.to_string()).unwrap_or_else(|| "-".to_owned()));
+            }
+        },
+        ApiKeysCommand::Revoke{ key_id } => {
+            if !db.revoke_api_key(key_id).await? {
+                return Err(FondyError::Custom(format!("API key {} is not found or already revoked", key_id)));
+            }
+            audit(&db, CLI_ACTOR, "api_key_revoke", Some(&key_id.to_string()), None).await?;
+            println!("API key {} revoked", key_id);
+        }
+    }
+    Ok(())
+}
+
+async fn audit_log(config: &AppConfig, limit: i64) -> Result<(), FondyError> {
+    let db = Database::open_database(&config.database)
+        .await?;
+    for record in db.list_audit_records(limit).await? {
+        println!("{:>6} {} {:<16} {:<16} {:<36} {} {}",
+                 record.audit_id,
+                 record.created_at,
+                 record.actor,
+                 record.action,
+                 record.target.as_deref().unwrap_or("-"),
+                 record.details.as_deref().unwrap_or("-"),
+                 record.outcome.as_deref().unwrap_or(""));
+    }
+    Ok(())
+}
@@ -0,0 +1,973 @@
+use std::{
+    net::{
+        IpAddr,
+        SocketAddr
+    },
+    path::{
+        Path,
+        PathBuf
+    },
+    str::{
+        FromStr
+    },
+    time::{
+        Duration
+    }
+};
+use ipnet::{
+    IpNet
+};
+use serde::{
+    Deserialize
+};
+use tracing::{
+    debug
+};
+use url::{
+    Url
+};
+use crate::{
+    error::{
+        FondyError
+    },
+    secret::{
+        Secret
+    },
+    database::{
+        DatabaseKind
+    }
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Файл конфига по-умолчанию, используется если он существует и не задана переменная CONFIG_FILE
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0:8080";
+
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+const DEFAULT_TLS_RELOAD_INTERVAL_SECS: u64 = 60;
+
+const DEFAULT_FONDY_API_URL: &str = "https://pay.fondy.eu/api/";
+const DEFAULT_FONDY_CONNECT_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_FONDY_REQUEST_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_FONDY_MAX_RETRIES: u32 = 2;
+const DEFAULT_FONDY_RETRY_BASE_DELAY_MS: u64 = 200;
+const DEFAULT_FONDY_RETRY_MAX_DELAY_MS: u64 = 2000;
+const DEFAULT_FONDY_BREAKER_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_FONDY_BREAKER_OPEN_SECS: u64 = 30;
+
+const DEFAULT_DATABASE_MAX_CONNECTIONS: u32 = 10;
+const DEFAULT_DATABASE_MIN_CONNECTIONS: u32 = 0;
+const DEFAULT_DATABASE_CONNECT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_DATABASE_CONNECT_RETRIES: u32 = 5;
+const DEFAULT_DATABASE_BUSY_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_DATABASE_JOURNAL_MODE: &str = "wal";
+
+const DEFAULT_RECONCILIATION_INTERVAL_SECS: u64 = 300;
+const DEFAULT_RECONCILIATION_MIN_AGE_SECS: u64 = 900;
+const DEFAULT_RECONCILIATION_BATCH_SIZE: i64 = 50;
+
+/// Время жизни оплаты по-умолчанию, такое же как по-умолчанию у Fondy
+const DEFAULT_CHECKOUT_LIFETIME_SECS: u64 = 36000;
+const DEFAULT_ORDER_IDEMPOTENCY_WINDOW_SECS: u64 = 60 * 60;
+
+const DEFAULT_EXPIRY_INTERVAL_SECS: u64 = 60;
+const DEFAULT_EXPIRY_GRACE_SECS: u64 = 300;
+const DEFAULT_EXPIRY_BATCH_SIZE: i64 = 50;
+
+const DEFAULT_SETTLEMENT_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+const DEFAULT_SESSION_LIFETIME_SECS: u64 = 8 * 60 * 60;
+
+const DEFAULT_HEALTH_TIMEOUT_SECS: u64 = 5;
+
+const DEFAULT_BUY_RATE_LIMIT_BURST: u32 = 5;
+const DEFAULT_BUY_RATE_LIMIT_PER_MINUTE: u32 = 10;
+
+/// Допустимые значения journal_mode для SQLite
+const SQLITE_JOURNAL_MODES: &[&str] = &["delete", "truncate", "persist", "memory", "wal", "off"];
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Содержимое TOML файла конфига, все поля опциональны и могут быть переопределены окружением
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig{
+    pub site_url: Option<String>,
+    pub merchant: FileMerchantConfig,
+    pub fondy: FileFondyConfig,
+    pub database: FileDatabaseConfig,
+    pub server: FileServerConfig,
+    pub reconciliation: FileReconciliationConfig,
+    pub orders: FileOrdersConfig,
+    pub expiry: FileExpiryConfig,
+    pub settlement: FileSettlementConfig,
+    pub auth: FileAuthConfig,
+    pub health: FileHealthConfig,
+    pub buy: FileBuyConfig,
+    pub callbacks: FileCallbacksConfig
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileMerchantConfig{
+    pub id: Option<u64>,
+    pub password: Option<Secret>,
+    pub password_file: Option<PathBuf>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileFondyConfig{
+    pub api_url: Option<String>,
+    pub connect_timeout_secs: Option<u64>,
+    pub request_timeout_secs: Option<u64>,
+    pub max_retries: Option<u32>,
+    pub retry_base_delay_ms: Option<u64>,
+    pub retry_max_delay_ms: Option<u64>,
+    pub breaker_failure_threshold: Option<u32>,
+    pub breaker_open_secs: Option<u64>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileDatabaseConfig{
+    pub url: Option<String>,
+    pub max_connections: Option<u32>,
+    pub min_connections: Option<u32>,
+    pub connect_timeout_secs: Option<u64>,
+    pub connect_retries: Option<u32>,
+    pub busy_timeout_ms: Option<u64>,
+    pub journal_mode: Option<String>,
+    pub foreign_keys: Option<bool>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileServerConfig{
+    pub bind_address: Option<String>,
+    pub shutdown_timeout_secs: Option<u64>,
+    pub trusted_proxies: Option<String>,
+    pub tls: FileTlsConfig
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileTlsConfig{
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+    pub reload_interval_secs: Option<u64>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileReconciliationConfig{
+    pub enabled: Option<bool>,
+    pub interval_secs: Option<u64>,
+    pub min_age_secs: Option<u64>,
+    pub batch_size: Option<i64>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileOrdersConfig{
+    pub checkout_lifetime_secs: Option<u64>,
+    pub idempotency_window_secs: Option<u64>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileExpiryConfig{
+    pub enabled: Option<bool>,
+    pub interval_secs: Option<u64>,
+    pub grace_secs: Option<u64>,
+    pub batch_size: Option<i64>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileSettlementConfig{
+    pub enabled: Option<bool>,
+    pub interval_secs: Option<u64>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileAuthConfig{
+    pub session_lifetime_secs: Option<u64>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileHealthConfig{
+    pub check_fondy: Option<bool>,
+    pub timeout_secs: Option<u64>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileBuyConfig{
+    pub allow_get: Option<bool>,
+    pub rate_limit_burst: Option<u32>,
+    pub rate_limit_per_minute: Option<u32>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileCallbacksConfig{
+    pub allowed_ips: Option<String>
+}
+
+impl FileConfig {
+    /// Читает файл конфига по пути из CONFIG_FILE, либо config.toml если он есть.
+    /// Если файла нет, то возвращается пустой конфиг, все значения тогда берутся из окружения.
+    pub fn load() -> Result<FileConfig, FondyError> {
+        let path = match std::env::var("CONFIG_FILE") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => {
+                let path = PathBuf::from(DEFAULT_CONFIG_FILE);
+                if !path.exists() {
+                    debug!("Config file is missing, environment only");
+                    return Ok(FileConfig::default());
+                }
+                path
+            }
+        };
+        FileConfig::from_file(&path)
+    }
+
+    pub fn from_file(path: &Path) -> Result<FileConfig, FondyError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err|{
+                FondyError::ConfigError(vec![format!("config file {} read failed: {}", path.display(), err)])
+            })?;
+        toml::from_str(&text)
+            .map_err(|err|{
+                FondyError::ConfigError(vec![format!("config file {} parse failed: {}", path.display(), err)])
+            })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+pub struct DatabaseConfig{
+    pub url: String,
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub connect_timeout: Duration,
+    /// Количество повторных попыток подключения при старте
+    pub connect_retries: u32,
+    /// Только для SQLite
+    pub busy_timeout: Duration,
+    /// Только для SQLite
+    pub journal_mode: String,
+    /// Только для SQLite
+    pub foreign_keys: bool
+}
+
+impl DatabaseConfig {
+    /// Конфиг с настройками по-умолчанию для указанного адреса
+    pub fn with_url(url: String) -> DatabaseConfig {
+        DatabaseConfig{
+            url,
+            max_connections: DEFAULT_DATABASE_MAX_CONNECTIONS,
+            min_connections: DEFAULT_DATABASE_MIN_CONNECTIONS,
+            connect_timeout: Duration::from_secs(DEFAULT_DATABASE_CONNECT_TIMEOUT_SECS),
+            connect_retries: DEFAULT_DATABASE_CONNECT_RETRIES,
+            busy_timeout: Duration::from_millis(DEFAULT_DATABASE_BUSY_TIMEOUT_MS),
+            journal_mode: DEFAULT_DATABASE_JOURNAL_MODE.to_owned(),
+            foreign_keys: true
+        }
+    }
+}
+
+/// Адрес, на котором сервер принимает соединения
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddress{
+    /// Обычный HTTP, `host:port` или `http://host:port`
+    Http(SocketAddr),
+    /// HTTP поверх TLS, `https://host:port`
+    Https(SocketAddr),
+    /// Unix сокет для реверс-прокси на той же машине, `unix:/path/to/socket`
+    Unix(PathBuf)
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<ListenAddress, String> {
+        let text = text.trim();
+        if let Some(path) = text.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix socket path is empty".to_owned());
+            }
+            Ok(ListenAddress::Unix(PathBuf::from(path)))
+        }else if let Some(address) = text.strip_prefix("https://") {
+            address
+                .parse()
+                .map(ListenAddress::Https)
+                .map_err(|err| format!("{}", err))
+        }else{
+            text.strip_prefix("http://")
+                .unwrap_or(text)
+                .parse()
+                .map(ListenAddress::Http)
+                .map_err(|err| format!("{}", err))
+        }
+    }
+}
+
+impl std::fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddress::Http(address) => write!(f, "http://{}", address),
+            ListenAddress::Https(address) => write!(f, "https://{}", address),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display())
+        }
+    }
+}
+
+/// Сертификат для https слушателей
+#[derive(Debug, Clone)]
+pub struct TlsConfig{
+    /// Цепочка сертификатов в PEM
+    pub cert_path: PathBuf,
+    /// Приватный ключ в PEM, PKCS#8 или RSA
+    pub key_path: PathBuf,
+    /// Как часто проверять файлы на изменение, например после продления Let's Encrypt
+    pub reload_interval: Duration
+}
+
+/// Устойчивость запросов к API Fondy
+#[derive(Debug, Clone)]
+pub struct FondyConfig{
+    /// Ожидание установки соединения
+    pub connect_timeout: Duration,
+    /// Ограничение всего запроса вместе с чтением ответа
+    pub request_timeout: Duration,
+    /// Повторы после первой попытки
+    pub max_retries: u32,
+    /// Пауза перед первым повтором, дальше удваивается до retry_max_delay
+    pub retry_base_delay: Duration,
+    pub retry_max_delay: Duration,
+    /// Сбоев подряд, после которых запросы к Fondy временно не отправляются
+    pub breaker_failure_threshold: u32,
+    /// Сколько запросы не отправляются, потом пропускается одна пробная попытка
+    pub breaker_open_duration: Duration
+}
+
+#[derive(Debug)]
+pub struct ServerConfig{
+    /// Все адреса, на которых сервер принимает соединения
+    pub listen: Vec<ListenAddress>,
+
+    /// Сертификат, обязателен если есть https адрес
+    pub tls: Option<TlsConfig>,
+
+    /// Сколько ждать завершения текущих запросов и фоновых задач при остановке
+    pub shutdown_timeout: Duration,
+
+    /// Прокси, которым доверяем адрес клиента из X-Forwarded-For
+    pub trusted_proxies: Vec<IpNet>
+}
+
+/// Настройки фоновой сверки незавершенных заказов с Fondy
+#[derive(Debug, Clone)]
+pub struct ReconciliationConfig{
+    pub enabled: bool,
+    /// Период запуска сверки
+    pub interval: Duration,
+    /// Проверяются только заказы старше этого возраста
+    pub min_age: Duration,
+    /// Максимум заказов за один проход
+    pub batch_size: i64
+}
+
+#[derive(Debug)]
+pub struct OrdersConfig{
+    /// Время жизни оплаты для продуктов, у которых оно не задано отдельно
+    pub checkout_lifetime: Duration,
+    /// Сколько повторный запрос с тем же ключом идемпотентности получает уже созданный платеж
+    pub idempotency_window: Duration
+}
+
+/// Настройки фоновой отметки просроченных заказов
+#[derive(Debug, Clone)]
+pub struct ExpiryConfig{
+    pub enabled: bool,
+    /// Период запуска проверки
+    pub interval: Duration,
+    /// Запас после крайнего срока, чтобы успели прийти запоздавшие коллбеки
+    pub grace: Duration,
+    /// Максимум заказов за один проход
+    pub batch_size: i64
+}
+
+/// Настройки ежедневной сверки расчетов по отчетам Fondy
+#[derive(Debug, Clone)]
+pub struct SettlementConfig{
+    pub enabled: bool,
+    /// Период запуска, каждый раз загружается вчерашний день
+    pub interval: Duration
+}
+
+#[derive(Debug)]
+pub struct AuthConfig{
+    /// Время жизни сессии в админке
+    pub session_lifetime: Duration
+}
+
+#[derive(Debug)]
+pub struct HealthConfig{
+    /// Проверять ли доступность API Fondy в /readyz
+    pub check_fondy: bool,
+
+    /// Ограничение времени каждой проверки готовности
+    pub timeout: Duration
+}
+
+/// Защита создания заказов через /buy
+#[derive(Debug)]
+pub struct BuyConfig{
+    /// Принимать ли GET /buy со старых ссылок, такие запросы идут без проверки CSRF токена
+    pub allow_get: bool,
+    /// Сколько заказов подряд можно создать с одного адреса или из одной сессии
+    pub rate_limit_burst: u32,
+    /// Сколько заказов в минуту восстанавливается после исчерпания запаса
+    pub rate_limit_per_minute: u32
+}
+
+#[derive(Debug)]
+pub struct CallbacksConfig{
+    /// Адреса и сети, с которых принимаются серверные коллбеки, пустой список - с любых
+    pub allowed_ips: Vec<IpNet>
+}
+
+/// Проверенный конфиг приложения
+#[derive(Debug)]
+pub struct AppConfig{
+    pub site_url: Url,
+    pub merchant_id: u64,
+    pub merchant_password: Secret,
+    pub fondy_api_url: Url,
+    pub fondy: FondyConfig,
+    pub database: DatabaseConfig,
+    pub server: ServerConfig,
+    pub reconciliation: ReconciliationConfig,
+    pub orders: OrdersConfig,
+    pub expiry: ExpiryConfig,
+    pub settlement: SettlementConfig,
+    pub auth: AuthConfig,
+    pub health: HealthConfig,
+    pub buy: BuyConfig,
+    pub callbacks: CallbacksConfig
+}
+
+impl AppConfig {
+    /// Загружает конфиг из файла и переменных окружения
+    pub fn load() -> Result<AppConfig, FondyError> {
+        let file = FileConfig::load()?;
+        AppConfig::from_sources(file, |name| std::env::var(name).ok())
+    }
+
+    /// Загружает только пароль продавца, для офлайн команд подписи без базы и сети
+    pub fn load_merchant_password() -> Result<Secret, FondyError> {
+        let file = FileConfig::load()?;
+        let env = |name: &str| std::env::var(name).ok();
+        let mut errors = Vec::new();
+        let password = merchant_password(&mut errors, &env, file.merchant.password, file.merchant.password_file);
+        match require(&mut errors, "MERCHANT_PASSWORD", password) {
+            Some(password) if errors.is_empty() => Ok(password),
+            _ => Err(FondyError::ConfigError(errors))
+        }
+    }
+
+    /// Накладывает значения из окружения поверх файла и проверяет результат.
+    /// Возвращает сразу все найденные проблемы, а не только первую.
+    pub fn from_sources<E>(file: FileConfig, env: E) -> Result<AppConfig, FondyError>
+    where
+        E: Fn(&str) -> Option<String>
+    {
+        let mut errors = Vec::new();
+
+        // Адрес нашего сайта
+        let site_url = env("SITE_URL")
+            .or(file.site_url)
+            .and_then(|text| parse_value::<Url>(&mut errors, "SITE_URL", &text))
+            .and_then(|url| {
+                if !matches!(url.scheme(), "http" | "https") || url.cannot_be_a_base() {
+                    errors.push(format!("SITE_URL must be http(s) base url, got: {}", url));
+                    None
+                }else{
+                    Some(url)
+                }
+            });
+
+        // Идентификатор продавца
+        let merchant_id = match env("MERCHANT_ID") {
+            Some(text) => parse_value::<u64>(&mut errors, "MERCHANT_ID", &text),
+            None => file.merchant.id
+        };
+        if merchant_id == Some(0) {
+            errors.push("MERCHANT_ID must not be zero".to_owned());
+        }
+
+        let merchant_password = merchant_password(&mut errors, &env, file.merchant.password, file.merchant.password_file);
+
+        // Базовый адрес API Fondy, завершающий слеш нужен для корректного join методов
+        let fondy_api_url = env("FONDY_API_URL")
+            .or(file.fondy.api_url)
+            .unwrap_or_else(|| DEFAULT_FONDY_API_URL.to_owned());
+        let fondy_api_url = parse_value::<Url>(&mut errors, "FONDY_API_URL", &fondy_api_url)
+            .and_then(|url| {
+                if !url.path().ends_with('/') {
+                    errors.push(format!("FONDY_API_URL must ends with '/', got: {}", url));
+                    None
+                }else{
+                    Some(url)
+                }
+            });
+
+        // Таймауты, повторы и отключение запросов к Fondy при сбоях
+        let fondy = FondyConfig{
+            connect_timeout: Duration::from_secs(setting(&mut errors, &env, "FONDY_CONNECT_TIMEOUT_SECS", file.fondy.connect_timeout_secs, DEFAULT_FONDY_CONNECT_TIMEOUT_SECS)),
+            request_timeout: Duration::from_secs(setting(&mut errors, &env, "FONDY_REQUEST_TIMEOUT_SECS", file.fondy.request_timeout_secs, DEFAULT_FONDY_REQUEST_TIMEOUT_SECS)),
+            max_retries: setting(&mut errors, &env, "FONDY_MAX_RETRIES", file.fondy.max_retries, DEFAULT_FONDY_MAX_RETRIES),
+            retry_base_delay: Duration::from_millis(setting(&mut errors, &env, "FONDY_RETRY_BASE_DELAY_MS", file.fondy.retry_base_delay_ms, DEFAULT_FONDY_RETRY_BASE_DELAY_MS)),
+            retry_max_delay: Duration::from_millis(setting(&mut errors, &env, "FONDY_RETRY_MAX_DELAY_MS", file.fondy.retry_max_delay_ms, DEFAULT_FONDY_RETRY_MAX_DELAY_MS)),
+            breaker_failure_threshold: setting(&mut errors, &env, "FONDY_BREAKER_FAILURE_THRESHOLD", file.fondy.breaker_failure_threshold, DEFAULT_FONDY_BREAKER_FAILURE_THRESHOLD),
+            breaker_open_duration: Duration::from_secs(setting(&mut errors, &env, "FONDY_BREAKER_OPEN_SECS", file.fondy.breaker_open_secs, DEFAULT_FONDY_BREAKER_OPEN_SECS))
+        };
+        if fondy.connect_timeout.as_secs() == 0 {
+            errors.push("FONDY_CONNECT_TIMEOUT_SECS must be greater than zero".to_owned());
+        }
+        if fondy.request_timeout.as_secs() == 0 {
+            errors.push("FONDY_REQUEST_TIMEOUT_SECS must be greater than zero".to_owned());
+        }
+        if fondy.retry_base_delay > fondy.retry_max_delay {
+            errors.push("FONDY_RETRY_BASE_DELAY_MS must not be greater than FONDY_RETRY_MAX_DELAY_MS".to_owned());
+        }
+        if fondy.breaker_failure_threshold == 0 {
+            errors.push("FONDY_BREAKER_FAILURE_THRESHOLD must be greater than zero".to_owned());
+        }
+        if fondy.breaker_open_duration.as_secs() == 0 {
+            errors.push("FONDY_BREAKER_OPEN_SECS must be greater than zero".to_owned());
+        }
+
+        // База данных
+        let database_url = env("DATABASE_URL")
+            .or(file.database.url.clone());
+        let database_kind = match database_url.as_deref().map(DatabaseKind::from_url) {
+            Some(Ok(kind)) => Some(kind),
+            Some(Err(err)) => {
+                errors.push(err);
+                None
+            },
+            None => None
+        };
+
+        // Настройки пула соединений, адрес подставляется ниже
+        let file_db = file.database;
+        let defaults = DatabaseConfig::with_url(String::new());
+        let database_settings = DatabaseConfig{
+            max_connections: setting(&mut errors, &env, "DATABASE_MAX_CONNECTIONS", file_db.max_connections, defaults.max_connections),
+            min_connections: setting(&mut errors, &env, "DATABASE_MIN_CONNECTIONS", file_db.min_connections, defaults.min_connections),
+            connect_timeout: Duration::from_secs(setting(&mut errors, &env, "DATABASE_CONNECT_TIMEOUT_SECS", file_db.connect_timeout_secs, defaults.connect_timeout.as_secs())),
+            connect_retries: setting(&mut errors, &env, "DATABASE_CONNECT_RETRIES", file_db.connect_retries, defaults.connect_retries),
+            busy_timeout: Duration::from_millis(setting(&mut errors, &env, "DATABASE_BUSY_TIMEOUT_MS", file_db.busy_timeout_ms, defaults.busy_timeout.as_millis() as u64)),
+            journal_mode: setting(&mut errors, &env, "DATABASE_JOURNAL_MODE", file_db.journal_mode, defaults.journal_mode.clone())
+                .to_ascii_lowercase(),
+            foreign_keys: setting(&mut errors, &env, "DATABASE_FOREIGN_KEYS", file_db.foreign_keys, defaults.foreign_keys),
+            ..defaults
+        };
+        if database_settings.max_connections == 0 {
+            errors.push("DATABASE_MAX_CONNECTIONS must be greater than zero".to_owned());
+        }
+        if database_settings.min_connections > database_settings.max_connections {
+            errors.push("DATABASE_MIN_CONNECTIONS must not be greater than DATABASE_MAX_CONNECTIONS".to_owned());
+        }
+        // Для Postgres режим журнала не используется, поэтому и не проверяется
+        let is_sqlite = database_kind.map(DatabaseKind::is_sqlite).unwrap_or(false);
+        if is_sqlite && !SQLITE_JOURNAL_MODES.contains(&database_settings.journal_mode.as_str()) {
+            errors.push(format!("DATABASE_JOURNAL_MODE must be one of {:?}", SQLITE_JOURNAL_MODES));
+        }
+
+        // Фоновая сверка заказов
+        let file_rec = file.reconciliation;
+        let reconciliation = ReconciliationConfig{
+            enabled: setting(&mut errors, &env, "RECONCILIATION_ENABLED", file_rec.enabled, true),
+            interval: Duration::from_secs(setting(&mut errors, &env, "RECONCILIATION_INTERVAL_SECS", file_rec.interval_secs, DEFAULT_RECONCILIATION_INTERVAL_SECS)),
+            min_age: Duration::from_secs(setting(&mut errors, &env, "RECONCILIATION_MIN_AGE_SECS", file_rec.min_age_secs, DEFAULT_RECONCILIATION_MIN_AGE_SECS)),
+            batch_size: setting(&mut errors, &env, "RECONCILIATION_BATCH_SIZE", file_rec.batch_size, DEFAULT_RECONCILIATION_BATCH_SIZE)
+        };
+        if reconciliation.interval.as_secs() == 0 {
+            errors.push("RECONCILIATION_INTERVAL_SECS must be greater than zero".to_owned());
+        }
+        if reconciliation.batch_size <= 0 {
+            errors.push("RECONCILIATION_BATCH_SIZE must be greater than zero".to_owned());
+        }
+
+        // Время жизни оплаты и ключей идемпотентности
+        let orders = OrdersConfig{
+            checkout_lifetime: Duration::from_secs(setting(&mut errors, &env, "ORDER_CHECKOUT_LIFETIME_SECS", file.orders.checkout_lifetime_secs, DEFAULT_CHECKOUT_LIFETIME_SECS)),
+            idempotency_window: Duration::from_secs(setting(&mut errors, &env, "ORDER_IDEMPOTENCY_WINDOW_SECS", file.orders.idempotency_window_secs, DEFAULT_ORDER_IDEMPOTENCY_WINDOW_SECS))
+        };
+        if orders.checkout_lifetime.as_secs() == 0 {
+            errors.push("ORDER_CHECKOUT_LIFETIME_SECS must be greater than zero".to_owned());
+        }
+        if orders.idempotency_window.as_secs() == 0 {
+            errors.push("ORDER_IDEMPOTENCY_WINDOW_SECS must be greater than zero".to_owned());
+        }
+
+        // Отметка просроченных заказов
+        let file_exp = file.expiry;
+        let expiry = ExpiryConfig{
+            enabled: setting(&mut errors, &env, "EXPIRY_ENABLED", file_exp.enabled, true),
+            interval: Duration::from_secs(setting(&mut errors, &env, "EXPIRY_INTERVAL_SECS", file_exp.interval_secs, DEFAULT_EXPIRY_INTERVAL_SECS)),
+            grace: Duration::from_secs(setting(&mut errors, &env, "EXPIRY_GRACE_SECS", file_exp.grace_secs, DEFAULT_EXPIRY_GRACE_SECS)),
+            batch_size: setting(&mut errors, &env, "EXPIRY_BATCH_SIZE", file_exp.batch_size, DEFAULT_EXPIRY_BATCH_SIZE)
+        };
+        if expiry.interval.as_secs() == 0 {
+            errors.push("EXPIRY_INTERVAL_SECS must be greater than zero".to_owned());
+        }
+        if expiry.batch_size <= 0 {
+            errors.push("EXPIRY_BATCH_SIZE must be greater than zero".to_owned());
+        }
+
+        // Сверка расчетов, по-умолчанию выключена, так как доступ к отчетам включается в Fondy отдельно
+        let file_set = file.settlement;
+        let settlement = SettlementConfig{
+            enabled: setting(&mut errors, &env, "SETTLEMENT_ENABLED", file_set.enabled, false),
+            interval: Duration::from_secs(setting(&mut errors, &env, "SETTLEMENT_INTERVAL_SECS", file_set.interval_secs, DEFAULT_SETTLEMENT_INTERVAL_SECS))
+        };
+        if settlement.interval.as_secs() == 0 {
+            errors.push("SETTLEMENT_INTERVAL_SECS must be greater than zero".to_owned());
+        }
+
+        // Сессии админки
+        let auth = AuthConfig{
+            session_lifetime: Duration::from_secs(setting(&mut errors, &env, "AUTH_SESSION_LIFETIME_SECS", file.auth.session_lifetime_secs, DEFAULT_SESSION_LIFETIME_SECS))
+        };
+        if auth.session_lifetime.as_secs() == 0 {
+            errors.push("AUTH_SESSION_LIFETIME_SECS must be greater than zero".to_owned());
+        }
+
+        // Проверки готовности
+        let health = HealthConfig{
+            check_fondy: setting(&mut errors, &env, "HEALTH_CHECK_FONDY", file.health.check_fondy, false),
+            timeout: Duration::from_secs(setting(&mut errors, &env, "HEALTH_TIMEOUT_SECS", file.health.timeout_secs, DEFAULT_HEALTH_TIMEOUT_SECS))
+        };
+        if health.timeout.as_secs() == 0 {
+            errors.push("HEALTH_TIMEOUT_SECS must be greater than zero".to_owned());
+        }
+
+        // Защита /buy
+        let buy = BuyConfig{
+            allow_get: setting(&mut errors, &env, "BUY_ALLOW_GET", file.buy.allow_get, false),
+            rate_limit_burst: setting(&mut errors, &env, "BUY_RATE_LIMIT_BURST", file.buy.rate_limit_burst, DEFAULT_BUY_RATE_LIMIT_BURST),
+            rate_limit_per_minute: setting(&mut errors, &env, "BUY_RATE_LIMIT_PER_MINUTE", file.buy.rate_limit_per_minute, DEFAULT_BUY_RATE_LIMIT_PER_MINUTE)
+        };
+        if buy.rate_limit_burst == 0 {
+            errors.push("BUY_RATE_LIMIT_BURST must be greater than zero".to_owned());
+        }
+        if buy.rate_limit_per_minute == 0 {
+            errors.push("BUY_RATE_LIMIT_PER_MINUTE must be greater than zero".to_owned());
+        }
+
+        // Источники серверных коллбеков
+        let callbacks = CallbacksConfig{
+            allowed_ips: env("CALLBACK_ALLOWED_IPS")
+                .or(file.callbacks.allowed_ips)
+                .map(|text| parse_networks(&mut errors, "CALLBACK_ALLOWED_IPS", &text))
+                .unwrap_or_default()
+        };
+
+        // Адрес, на котором слушает сервер
+        // Адреса через запятую, например "0.0.0.0:80, https://0.0.0.0:443, unix:/run/fondy.sock"
+        let bind_address = env("BIND_ADDRESS")
+            .or(file.server.bind_address)
+            .unwrap_or_else(|| DEFAULT_BIND_ADDRESS.to_owned());
+        let listen: Vec<ListenAddress> = bind_address
+            .split(',')
+            .filter(|address| !address.trim().is_empty())
+            .filter_map(|address| parse_value::<ListenAddress>(&mut errors, "BIND_ADDRESS", address))
+            .collect();
+        if listen.is_empty() && !errors.iter().any(|err| err.starts_with("BIND_ADDRESS")) {
+            errors.push("BIND_ADDRESS must contain at least one address".to_owned());
+        }
+        let shutdown_timeout = Duration::from_secs(setting(&mut errors, &env, "SHUTDOWN_TIMEOUT_SECS", file.server.shutdown_timeout_secs, DEFAULT_SHUTDOWN_TIMEOUT_SECS));
+
+        // Прокси перед сервером, например "10.0.0.0/8, 127.0.0.1"
+        let trusted_proxies = env("TRUSTED_PROXIES")
+            .or(file.server.trusted_proxies)
+            .map(|text| parse_networks(&mut errors, "TRUSTED_PROXIES", &text))
+            .unwrap_or_default();
+
+        // Сертификат для https
+        let file_tls = file.server.tls;
+        let tls_cert_path = env("TLS_CERT_PATH")
+            .map(PathBuf::from)
+            .or(file_tls.cert_path);
+        let tls_key_path = env("TLS_KEY_PATH")
+            .map(PathBuf::from)
+            .or(file_tls.key_path);
+        let tls_reload_interval = Duration::from_secs(setting(&mut errors, &env, "TLS_RELOAD_INTERVAL_SECS", file_tls.reload_interval_secs, DEFAULT_TLS_RELOAD_INTERVAL_SECS));
+        if tls_reload_interval.as_secs() == 0 {
+            errors.push("TLS_RELOAD_INTERVAL_SECS must be greater than zero".to_owned());
+        }
+        let tls = match (tls_cert_path, tls_key_path) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig{
+                cert_path,
+                key_path,
+                reload_interval: tls_reload_interval
+            }),
+            (None, None) => None,
+            _ => {
+                errors.push("TLS_CERT_PATH and TLS_KEY_PATH must be set together".to_owned());
+                None
+            }
+        };
+        let has_https = listen
+            .iter()
+            .any(|address| matches!(address, ListenAddress::Https(_)));
+        if has_https && tls.is_none() {
+            errors.push("BIND_ADDRESS with https:// requires TLS_CERT_PATH and TLS_KEY_PATH".to_owned());
+        }
+        if !has_https && tls.is_some() {
+            errors.push("TLS_CERT_PATH is set, but BIND_ADDRESS has no https:// address".to_owned());
+        }
+
+        // Обязательные значения
+        let site_url = require(&mut errors, "SITE_URL", site_url);
+        let merchant_id = require(&mut errors, "MERCHANT_ID", merchant_id);
+        let merchant_password = require(&mut errors, "MERCHANT_PASSWORD", merchant_password);
+        let database_url = require(&mut errors, "DATABASE_URL", database_url);
+
+        match (site_url, merchant_id, merchant_password, fondy_api_url, database_url) {
+            (Some(site_url), Some(merchant_id), Some(merchant_password), Some(fondy_api_url), Some(database_url)) if errors.is_empty() => {
+                Ok(AppConfig{
+                    site_url,
+                    merchant_id,
+                    merchant_password,
+                    fondy_api_url,
+                    fondy,
+                    database: DatabaseConfig{
+                        url: database_url,
+                        ..database_settings
+                    },
+                    server: ServerConfig{
+                        listen,
+                        tls,
+                        shutdown_timeout,
+                        trusted_proxies
+                    },
+                    reconciliation,
+                    orders,
+                    expiry,
+                    settlement,
+                    auth,
+                    health,
+                    buy,
+                    callbacks
+                })
+            },
+            _ => {
+                Err(FondyError::ConfigError(errors))
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+fn parse_value<T>(errors: &mut Vec<String>, name: &str, text: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display
+{
+    match T::from_str(text) {
+        Ok(val) => Some(val),
+        Err(err) => {
+            errors.push(format!("{} is invalid ({}): {}", name, text, err));
+            None
+        }
+    }
+}
+
+/// Список адресов и сетей через запятую: 1.2.3.4, 10.0.0.0/8, ::1
+fn parse_networks(errors: &mut Vec<String>, name: &str, text: &str) -> Vec<IpNet> {
+    text
+        .split(',')
+        .map(str::trim)
+        .filter(|val| !val.is_empty())
+        .filter_map(|val| {
+            if val.contains('/') {
+                parse_value::<IpNet>(errors, name, val)
+            }else{
+                parse_value::<IpAddr>(errors, name, val).map(IpNet::from)
+            }
+        })
+        .collect()
+}
+
+/// Значение из окружения, либо из файла, либо значение по-умолчанию
+fn setting<T, E>(errors: &mut Vec<String>, env: &E, name: &str, file_value: Option<T>, default: T) -> T
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+    E: Fn(&str) -> Option<String>
+{
+    match env(name) {
+        Some(text) => parse_value(errors, name, &text).unwrap_or(default),
+        None => file_value.unwrap_or(default)
+    }
+}
+
+fn require<T>(errors: &mut Vec<String>, name: &str, value: Option<T>) -> Option<T> {
+    if value.is_none() && !errors.iter().any(|err| err.starts_with(name)) {
+        errors.push(format!("{} is missing", name));
+    }
+    value
+}
+
+/// Пароль продавца можно передать напрямую или файлом, например через Docker secret
+fn merchant_password<E>(errors: &mut Vec<String>, env: &E, file_password: Option<Secret>, file_password_path: Option<PathBuf>) -> Option<Secret>
+where
+    E: Fn(&str) -> Option<String>
+{
+    let password = if let Some(path) = env("MERCHANT_PASSWORD_FILE") {
+        read_secret(errors, Path::new(&path))
+    } else if let Some(password) = env("MERCHANT_PASSWORD") {
+        Some(Secret::new(password))
+    } else if let Some(path) = file_password_path {
+        read_secret(errors, &path)
+    } else {
+        file_password
+    };
+    if matches!(&password, Some(password) if password.expose().is_empty()) {
+        errors.push("MERCHANT_PASSWORD must not be empty".to_owned());
+    }
+    password
+}
+
+fn read_secret(errors: &mut Vec<String>, path: &Path) -> Option<Secret> {
+    match Secret::from_file(path) {
+        Ok(secret) => Some(secret),
+        Err(err) => {
+            errors.push(format!("MERCHANT_PASSWORD file read failed: {}", err));
+            None
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use std::{
+        collections::{
+            HashMap
+        }
+    };
+    use super::*;
+
+    fn load(file: &str, env: &[(&str, &str)]) -> Result<AppConfig, FondyError> {
+        let file: FileConfig = toml::from_str(file).unwrap();
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        AppConfig::from_sources(file, |name| env.get(name).cloned())
+    }
+
+    #[test]
+    fn test_env_overrides_file(){
+        let file = r#"
+            site_url = "https://example.com"
+            [merchant]
+            id = 1
+            password = "file_password"
+            [database]
+            url = "sqlite://db/file.sqlite"
+        "#;
+        let config = load(file, &[("MERCHANT_ID", "1396424"), ("BIND_ADDRESS", "127.0.0.1:9000"), ("DATABASE_MAX_CONNECTIONS", "3")]).unwrap();
+        assert_eq!(config.site_url.as_str(), "https://example.com/");
+        assert_eq!(config.merchant_id, 1396424);
+        assert_eq!(config.merchant_password.expose(), "file_password");
+        assert_eq!(config.fondy_api_url.as_str(), "https://pay.fondy.eu/api/");
+        assert_eq!(config.database.url, "sqlite://db/file.sqlite");
+        assert_eq!(config.database.max_connections, 3);
+        assert_eq!(config.database.journal_mode, "wal");
+        assert_eq!(config.server.listen, vec![ListenAddress::Http("127.0.0.1:9000".parse().unwrap())]);
+        assert!(config.server.tls.is_none());
+    }
+
+    #[test]
+    #[cfg(all(feature = "sqlite", feature = "postgres"))]
+    fn test_journal_mode_sqlite_only(){
+        let env = |url: &'static str| vec![("SITE_URL", "https://example.com"), ("MERCHANT_ID", "1"), ("MERCHANT_PASSWORD", "test"),
+                                           ("DATABASE_URL", url), ("DATABASE_JOURNAL_MODE", "unknown")];
+        assert!(matches!(load("", &env("sqlite::memory:")), Err(FondyError::ConfigError(_))));
+        assert!(load("", &env("postgres://localhost/fondy")).is_ok());
+    }
+
+    #[test]
+    fn test_listen_addresses(){
+        assert_eq!("0.0.0.0:8080".parse::<ListenAddress>().unwrap(), ListenAddress::Http("0.0.0.0:8080".parse().unwrap()));
+        assert_eq!("http://[::1]:80".parse::<ListenAddress>().unwrap(), ListenAddress::Http("[::1]:80".parse().unwrap()));
+        assert_eq!("https://0.0.0.0:443".parse::<ListenAddress>().unwrap(), ListenAddress::Https("0.0.0.0:443".parse().unwrap()));
+        assert_eq!("unix:/run/fondy.sock".parse::<ListenAddress>().unwrap(), ListenAddress::Unix(PathBuf::from("/run/fondy.sock")));
+        assert!("unix:".parse::<ListenAddress>().is_err());
+        assert!("https://localhost".parse::<ListenAddress>().is_err());
+
+        let env = [("SITE_URL", "https://example.com"), ("MERCHANT_ID", "1"), ("MERCHANT_PASSWORD", "test"), ("DATABASE_URL", "sqlite::memory:")];
+        let with_bind = |bind: &'static str, extra: &[(&'static str, &'static str)]| {
+            let mut env = env.to_vec();
+            env.push(("BIND_ADDRESS", bind));
+            env.extend_from_slice(extra);
+            load("", &env)
+        };
+
+        // https без сертификата не запустится
+        assert!(matches!(with_bind("0.0.0.0:80, https://0.0.0.0:443", &[]), Err(FondyError::ConfigError(_))));
+
+        let config = with_bind("0.0.0.0:80, https://0.0.0.0:443, unix:/tmp/fondy.sock", &[("TLS_CERT_PATH", "cert.pem"), ("TLS_KEY_PATH", "key.pem")])
+            .unwrap();
+        assert_eq!(config.server.listen.len(), 3);
+        let tls = config.server.tls.unwrap();
+        assert_eq!(tls.key_path, PathBuf::from("key.pem"));
+        assert_eq!(tls.reload_interval, Duration::from_secs(DEFAULT_TLS_RELOAD_INTERVAL_SECS));
+    }
+
+    #[test]
+    fn test_parse_networks(){
+        let mut errors = Vec::new();
+        let networks = parse_networks(&mut errors, "TRUSTED_PROXIES", "10.0.0.0/8, 127.0.0.1,,::1");
+        assert!(errors.is_empty());
+        assert_eq!(networks.len(), 3);
+        assert!(networks[0].contains(&"10.1.2.3".parse::<IpAddr>().unwrap()));
+        assert_eq!(networks[1], "127.0.0.1/32".parse::<IpNet>().unwrap());
+
+        parse_networks(&mut errors, "TRUSTED_PROXIES", "10.0.0.0/33, localhost");
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn test_all_errors_reported(){
+        let err = load("", &[("SITE_URL", "not url"), ("MERCHANT_ID", "abc"), ("DATABASE_URL", "mysql://db")])
+            .unwrap_err();
+        match err {
+            FondyError::ConfigError(errors) => {
+                assert_eq!(errors.len(), 4, "{:?}", errors);
+                assert!(errors.iter().any(|e| e.starts_with("SITE_URL is invalid")));
+                assert!(errors.iter().any(|e| e.starts_with("MERCHANT_ID is invalid")));
+                assert!(errors.iter().any(|e| e.starts_with("DATABASE_URL must")));
+                assert!(errors.iter().any(|e| e.starts_with("MERCHANT_PASSWORD is missing")));
+            },
+            err => panic!("Unexpected error: {}", err)
+        }
+    }
+}
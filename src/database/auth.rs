@@ -0,0 +1,305 @@
+use tracing::{
+    instrument
+};
+use crate::{
+    error::{
+        FondyError
+    }
+};
+use super::{
+    Database,
+    unix_now
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct UserRecord{
+    pub user_id: i64,
+    pub username: String,
+    pub password_hash: String,
+    pub user_role: String,
+    pub created_at: i64,
+    pub disabled_at: Option<i64>
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ApiKeyRecord{
+    pub key_id: i64,
+    pub key_name: String,
+    pub key_role: String,
+    pub created_at: i64,
+    pub revoked_at: Option<i64>
+}
+
+/// Запись журнала привилегированных действий
+#[derive(Debug)]
+pub struct NewAuditRecord<'a>{
+    /// Пользователь, ключ API или cli
+    pub actor: &'a str,
+    pub action: &'a str,
+    pub target: Option<&'a str>,
+    pub details: Option<&'a str>,
+    /// Результат действия, если оно обращается к внешним сервисам
+    pub outcome: Option<&'a str>
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct AuditRecord{
+    pub audit_id: i64,
+    pub created_at: i64,
+    pub actor: String,
+    pub action: String,
+    pub target: Option<String>,
+    pub details: Option<String>,
+    pub outcome: Option<String>
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+impl Database {
+    #[instrument(skip(self, password_hash))]
+    pub async fn insert_user(&self, username: &str, password_hash: &str, user_role: &str) -> Result<i64, FondyError> {
+        let user_id = sqlx::query_scalar("INSERT INTO users (username, password_hash, user_role, created_at) \
+                                          VALUES ($1, $2, $3, $4) RETURNING user_id")
+            .bind(username)
+            .bind(password_hash)
+            .bind(user_role)
+            .bind(unix_now())
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(user_id)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_user(&self, username: &str) -> Result<Option<UserRecord>, FondyError> {
+        let user = sqlx::query_as::<_, UserRecord>("SELECT * FROM users WHERE username = $1")
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(user)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_users(&self) -> Result<Vec<UserRecord>, FondyError> {
+        let users = sqlx::query_as::<_, UserRecord>("SELECT * FROM users ORDER BY user_id")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(users)
+    }
+
+    /// Новый пароль, все сессии пользователя при этом закрываются
+    #[instrument(skip(self, password_hash))]
+    pub async fn set_user_password(&self, username: &str, password_hash: &str) -> Result<bool, FondyError> {
+        let mut transaction = self.pool.begin().await?;
+        let result = sqlx::query("UPDATE users SET password_hash = $1 WHERE username = $2")
+            .bind(password_hash)
+            .bind(username)
+            .execute(&mut transaction)
+            .await?;
+        sqlx::query("DELETE FROM sessions WHERE user_id IN (SELECT user_id FROM users WHERE username = $1)")
+            .bind(username)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Отключает пользователя и закрывает все его сессии
+    #[instrument(skip(self))]
+    pub async fn disable_user(&self, username: &str) -> Result<bool, FondyError> {
+        let mut transaction = self.pool.begin().await?;
+        let result = sqlx::query("UPDATE users SET disabled_at = $1 WHERE username = $2 AND disabled_at IS NULL")
+            .bind(unix_now())
+            .bind(username)
+            .execute(&mut transaction)
+            .await?;
+        sqlx::query("DELETE FROM sessions WHERE user_id IN (SELECT user_id FROM users WHERE username = $1)")
+            .bind(username)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    ////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+    #[instrument(skip(self, session_hash))]
+    pub async fn insert_session(&self, session_hash: &str, user_id: i64, expires_at: i64) -> Result<(), FondyError> {
+        sqlx::query("INSERT INTO sessions (session_hash, user_id, created_at, expires_at) VALUES ($1, $2, $3, $4)")
+            .bind(session_hash)
+            .bind(user_id)
+            .bind(unix_now())
+            .bind(expires_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Пользователь действующей сессии, отключенные пользователи не возвращаются
+    #[instrument(skip(self, session_hash))]
+    pub async fn get_session_user(&self, session_hash: &str) -> Result<Option<UserRecord>, FondyError> {
+        let user = sqlx::query_as::<_, UserRecord>("SELECT u.* FROM sessions s JOIN users u ON u.user_id = s.user_id \
+                                                    WHERE s.session_hash = $1 AND s.expires_at > $2 AND u.disabled_at IS NULL")
+            .bind(session_hash)
+            .bind(unix_now())
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(user)
+    }
+
+    #[instrument(skip(self, session_hash))]
+    pub async fn delete_session(&self, session_hash: &str) -> Result<(), FondyError> {
+        sqlx::query("DELETE FROM sessions WHERE session_hash = $1")
+            .bind(session_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn delete_expired_sessions(&self) -> Result<u64, FondyError> {
+        let result = sqlx::query("DELETE FROM sessions WHERE expires_at <= $1")
+            .bind(unix_now())
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    ////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+    #[instrument(skip(self, key_hash))]
+    pub async fn insert_api_key(&self, key_hash: &str, key_name: &str, key_role: &str) -> Result<i64, FondyError> {
+        let key_id = sqlx::query_scalar("INSERT INTO api_keys (key_hash, key_name, key_role, created_at) \
+                                         VALUES ($1, $2, $3, $4) RETURNING key_id")
+            .bind(key_hash)
+            .bind(key_name)
+            .bind(key_role)
+            .bind(unix_now())
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(key_id)
+    }
+
+    /// Действующий ключ по хешу, отозванные не возвращаются
+    #[instrument(skip(self, key_hash))]
+    pub async fn get_api_key(&self, key_hash: &str) -> Result<Option<ApiKeyRecord>, FondyError> {
+        let key = sqlx::query_as::<_, ApiKeyRecord>("SELECT key_id, key_name, key_role, created_at, revoked_at FROM api_keys \
+                                                     WHERE key_hash = $1 AND revoked_at IS NULL")
+            .bind(key_hash)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(key)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_api_keys(&self) -> Result<Vec<ApiKeyRecord>, FondyError> {
+        let keys = sqlx::query_as::<_, ApiKeyRecord>("SELECT key_id, key_name, key_role, created_at, revoked_at FROM api_keys ORDER BY key_id")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(keys)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn revoke_api_key(&self, key_id: i64) -> Result<bool, FondyError> {
+        let result = sqlx::query("UPDATE api_keys SET revoked_at = $1 WHERE key_id = $2 AND revoked_at IS NULL")
+            .bind(unix_now())
+            .bind(key_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    ////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+    #[instrument(skip(self))]
+    pub async fn insert_audit_record(&self, record: NewAuditRecord<'_>) -> Result<i64, FondyError> {
+        let audit_id = sqlx::query_scalar("INSERT INTO audit_log (created_at, actor, action, target, details, outcome) \
+                                           VALUES ($1, $2, $3, $4, $5, $6) RETURNING audit_id")
+            .bind(unix_now())
+            .bind(record.actor)
+            .bind(record.action)
+            .bind(record.target)
+            .bind(record.details)
+            .bind(record.outcome)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(audit_id)
+    }
+
+    /// Результат действия, записанного в журнал до его выполнения
+    #[instrument(skip(self))]
+    pub async fn update_audit_outcome(&self, audit_id: i64, outcome: &str) -> Result<(), FondyError> {
+        sqlx::query("UPDATE audit_log SET outcome = $1 WHERE audit_id = $2")
+            .bind(outcome)
+            .bind(audit_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Последние записи журнала действий
+    #[instrument(skip(self))]
+    pub async fn list_audit_records(&self, limit: i64) -> Result<Vec<AuditRecord>, FondyError> {
+        let records = sqlx::query_as::<_, AuditRecord>("SELECT * FROM audit_log ORDER BY audit_id DESC LIMIT $1")
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(records)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use super::*;
+    use super::super::tests::{
+        memory_database
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sessions(){
+        let db = memory_database().await;
+        let user_id = db.insert_user("admin", "hash", "admin").await.unwrap();
+        db.insert_session("live", user_id, unix_now() + 60).await.unwrap();
+        db.insert_session("old", user_id, unix_now() - 60).await.unwrap();
+        assert_eq!(db.get_session_user("live").await.unwrap().map(|user| user.user_id), Some(user_id));
+        assert!(db.get_session_user("old").await.unwrap().is_none());
+        assert_eq!(db.delete_expired_sessions().await.unwrap(), 1);
+
+        // Отключенный пользователь теряет и уже открытые сессии
+        assert!(db.disable_user("admin").await.unwrap());
+        assert!(db.get_session_user("live").await.unwrap().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_api_keys(){
+        let db = memory_database().await;
+        let key_id = db.insert_api_key("key", "shop", "support").await.unwrap();
+        assert!(db.get_api_key("key").await.unwrap().is_some());
+        assert!(db.revoke_api_key(key_id).await.unwrap());
+        assert!(db.get_api_key("key").await.unwrap().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_audit_log(){
+        let db = memory_database().await;
+        db.insert_audit_record(NewAuditRecord{ actor: "admin", action: "login", target: None, details: None, outcome: None })
+            .await
+            .unwrap();
+        let audit_id = db
+            .insert_audit_record(NewAuditRecord{ actor: "admin", action: "refund", target: Some("order-1"), details: Some("100 USD"), outcome: Some("requested") })
+            .await
+            .unwrap();
+        db.update_audit_outcome(audit_id, "succeeded").await.unwrap();
+
+        // Последние записи первыми
+        let records = db.list_audit_records(10).await.unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].audit_id, audit_id);
+        assert_eq!(records[0].outcome.as_deref(), Some("succeeded"));
+        assert_eq!(records[1].outcome, None);
+        assert_eq!(db.list_audit_records(1).await.unwrap().len(), 1);
+    }
+}
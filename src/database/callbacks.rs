@@ -0,0 +1,151 @@
+use tracing::{
+    instrument
+};
+use crate::{
+    error::{
+        FondyError
+    }
+};
+use super::{
+    Database,
+    unix_now
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Тип коллбека от Fondy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallbackKind{
+    /// Серверный коллбек на server_callback_url
+    Server,
+
+    /// Редирект браузера покупателя на response_url
+    Browser
+}
+
+impl CallbackKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CallbackKind::Server => "server",
+            CallbackKind::Browser => "browser"
+        }
+    }
+}
+
+/// Новая запись журнала коллбеков, сохраняется до начала обработки
+#[derive(Debug)]
+pub struct NewCallbackLog<'a>{
+    pub kind: CallbackKind,
+    pub source_ip: Option<String>,
+    /// JSON объект с заголовками запроса
+    pub headers: String,
+    pub body: &'a str
+}
+
+/// Сохраненная запись журнала коллбеков
+#[derive(Debug, sqlx::FromRow)]
+pub struct CallbackLogRecord{
+    pub callback_id: i64,
+    pub received_at: i64,
+    pub callback_kind: String,
+    pub source_ip: Option<String>,
+    pub headers: String,
+    pub body: String,
+    pub order_id: Option<String>,
+    pub signature_valid: Option<bool>,
+    pub processing_result: Option<String>,
+    pub replayed_at: Option<i64>
+}
+
+/// Значение processing_result для успешно обработанного коллбека
+pub const CALLBACK_RESULT_OK: &str = "ok";
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+impl Database {
+    /// Сохраняет коллбек в исходном виде, возвращает идентификатор записи
+    #[instrument(skip(self, callback), fields(kind = ?callback.kind))]
+    pub async fn insert_callback_log(&self, callback: NewCallbackLog<'_>) -> Result<i64, FondyError> {
+        let id = sqlx::query_scalar("INSERT INTO callback_log (received_at, callback_kind, source_ip, headers, body) \
+                                     VALUES ($1, $2, $3, $4, $5) RETURNING callback_id")
+            .bind(unix_now())
+            .bind(callback.kind.as_str())
+            .bind(callback.source_ip)
+            .bind(callback.headers)
+            .bind(callback.body)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(id)
+    }
+
+    /// Записывает результат обработки коллбека
+    #[instrument(skip(self))]
+    pub async fn finish_callback_log(&self, callback_id: i64, order_id: Option<&str>, signature_valid: Option<bool>, result: &str) -> Result<(), FondyError> {
+        sqlx::query("UPDATE callback_log \
+                     SET order_id = COALESCE($1, order_id), signature_valid = COALESCE($2, signature_valid), processing_result = $3 \
+                     WHERE callback_id = $4")
+            .bind(order_id)
+            .bind(signature_valid)
+            .bind(result)
+            .bind(callback_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Отмечает время повторной обработки коллбека
+    #[instrument(skip(self))]
+    pub async fn mark_callback_replayed(&self, callback_id: i64) -> Result<(), FondyError> {
+        sqlx::query("UPDATE callback_log SET replayed_at = $1 WHERE callback_id = $2")
+            .bind(unix_now())
+            .bind(callback_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_callback_log(&self, callback_id: i64) -> Result<Option<CallbackLogRecord>, FondyError> {
+        let record = sqlx::query_as::<_, CallbackLogRecord>("SELECT * FROM callback_log WHERE callback_id = $1")
+            .bind(callback_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(record)
+    }
+
+    /// Последние коллбеки, при `only_failed` только те, обработка которых завершилась ошибкой
+    #[instrument(skip(self))]
+    pub async fn list_callback_logs(&self, only_failed: bool, limit: i64) -> Result<Vec<CallbackLogRecord>, FondyError> {
+        let records = sqlx::query_as::<_, CallbackLogRecord>("SELECT * FROM callback_log \
+                                                              WHERE $1 = 0 OR processing_result IS NULL OR processing_result <> $2 \
+                                                              ORDER BY callback_id DESC LIMIT $3")
+            .bind(only_failed as i32)
+            .bind(CALLBACK_RESULT_OK)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(records)
+    }
+
+    /// Серверные коллбеки, обработка которых завершилась ошибкой и еще не исправлена повтором
+    #[instrument(skip(self))]
+    pub async fn count_failed_server_callbacks(&self) -> Result<i64, FondyError> {
+        let count = sqlx::query_scalar("SELECT CAST(COUNT(*) AS BIGINT) FROM callback_log \
+                                        WHERE callback_kind = $1 AND (processing_result IS NULL OR processing_result <> $2)")
+            .bind(CallbackKind::Server.as_str())
+            .bind(CALLBACK_RESULT_OK)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(count)
+    }
+
+    /// Все коллбеки заказа в порядке получения
+    #[instrument(skip(self))]
+    pub async fn list_order_callback_logs(&self, order_id: &str) -> Result<Vec<CallbackLogRecord>, FondyError> {
+        let records = sqlx::query_as::<_, CallbackLogRecord>("SELECT * FROM callback_log WHERE order_id = $1 ORDER BY callback_id")
+            .bind(order_id)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(records)
+    }
+}
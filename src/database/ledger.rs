@@ -0,0 +1,125 @@
+use tracing::{
+    instrument
+};
+use crate::{
+    error::{
+        FondyError
+    }
+};
+use super::{
+    Database,
+    unix_now
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Новая проводка, баланс строк проверяется модулем ledger до сохранения
+#[derive(Debug)]
+pub struct NewLedgerEntry<'a>{
+    /// Уникальный ключ, повторная запись с тем же ключом игнорируется
+    pub entry_key: &'a str,
+    pub entry_kind: &'a str,
+    pub order_id: Option<&'a str>,
+    pub currency: &'a str,
+    /// Счет и сумма, дебет положительный, кредит отрицательный
+    pub postings: &'a [(&'a str, i64)]
+}
+
+/// Сумма строк по счету за один день
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct LedgerDailyTotal{
+    /// Номер дня от начала эпохи unix
+    pub day: i64,
+    pub account: String,
+    pub currency: String,
+    pub amount: i64
+}
+
+/// Проводка, сумма строк которой не равна нулю
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct UnbalancedEntryRecord{
+    pub entry_key: String,
+    pub amount: i64
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+impl Database {
+    /// Сохраняет проводку вместе со строками в одной транзакции.
+    /// Возвращает false, если проводка с таким ключом уже была записана.
+    #[instrument(skip(self))]
+    pub async fn insert_ledger_entry(&self, entry: NewLedgerEntry<'_>) -> Result<bool, FondyError> {
+        let mut transaction = self.pool.begin().await?;
+
+        let entry_id: Option<i64> = sqlx::query_scalar("INSERT INTO ledger_entries (entry_key, entry_kind, order_id, currency, created_at) \
+                                                        VALUES ($1, $2, $3, $4, $5) \
+                                                        ON CONFLICT(entry_key) DO NOTHING \
+                                                        RETURNING entry_id")
+            .bind(entry.entry_key)
+            .bind(entry.entry_kind)
+            .bind(entry.order_id)
+            .bind(entry.currency)
+            .bind(unix_now())
+            .fetch_optional(&mut transaction)
+            .await?;
+        let entry_id = match entry_id {
+            Some(entry_id) => entry_id,
+            None => {
+                transaction.rollback().await?;
+                return Ok(false);
+            }
+        };
+
+        for (account, amount) in entry.postings {
+            sqlx::query("INSERT INTO ledger_postings (entry_id, account, amount) VALUES ($1, $2, $3)")
+                .bind(entry_id)
+                .bind(*account)
+                .bind(*amount)
+                .execute(&mut transaction)
+                .await?;
+        }
+
+        transaction.commit().await?;
+        Ok(true)
+    }
+
+    /// Сумма строк по счету во всех проводках заказа
+    #[instrument(skip(self))]
+    pub async fn ledger_order_account_total(&self, order_id: &str, account: &str) -> Result<i64, FondyError> {
+        let total = sqlx::query_scalar("SELECT CAST(COALESCE(SUM(p.amount), 0) AS BIGINT) \
+                                        FROM ledger_postings p JOIN ledger_entries e ON e.entry_id = p.entry_id \
+                                        WHERE e.order_id = $1 AND p.account = $2")
+            .bind(order_id)
+            .bind(account)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(total)
+    }
+
+    /// Обороты по счетам за каждый день до `before`, дни в UTC
+    #[instrument(skip(self))]
+    pub async fn ledger_daily_totals(&self, before: i64) -> Result<Vec<LedgerDailyTotal>, FondyError> {
+        let totals = sqlx::query_as::<_, LedgerDailyTotal>("SELECT CAST(e.created_at / 86400 AS BIGINT) AS day, p.account AS account, e.currency AS currency, \
+                                                                   CAST(SUM(p.amount) AS BIGINT) AS amount \
+                                                            FROM ledger_postings p JOIN ledger_entries e ON e.entry_id = p.entry_id \
+                                                            WHERE e.created_at < $1 \
+                                                            GROUP BY CAST(e.created_at / 86400 AS BIGINT), p.account, e.currency \
+                                                            ORDER BY day, account, currency")
+            .bind(before)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(totals)
+    }
+
+    /// Проводки, нарушающие правило двойной записи
+    #[instrument(skip(self))]
+    pub async fn unbalanced_ledger_entries(&self) -> Result<Vec<UnbalancedEntryRecord>, FondyError> {
+        let entries = sqlx::query_as::<_, UnbalancedEntryRecord>("SELECT e.entry_key AS entry_key, CAST(COALESCE(SUM(p.amount), 0) AS BIGINT) AS amount \
+                                                                  FROM ledger_entries e LEFT JOIN ledger_postings p ON e.entry_id = p.entry_id \
+                                                                  GROUP BY e.entry_id, e.entry_key \
+                                                                  HAVING COUNT(p.posting_id) < 2 OR COALESCE(SUM(p.amount), 0) <> 0")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(entries)
+    }
+}
@@ -1,59 +1,414 @@
+mod callbacks;
+mod orders;
+mod settlements;
+mod ledger;
+mod auth;
+
+use std::{
+    str::{
+        FromStr
+    },
+    time::{
+        Duration
+    }
+};
 use sqlx::{
-    sqlite::{
-        SqlitePoolOptions,
-        SqlitePool
+    any::{
+        AnyConnectOptions,
+        AnyPoolOptions,
+        AnyPool
+    },
+    migrate::{
+        Migrate,
+        Migrator
+    }
+};
+use crate::{
+    config::{
+        DatabaseConfig
     },
-    // migrate
+    error::{
+        FondyError
+    }
 };
 use tracing::{
     instrument,
-    debug
+    debug,
+    warn,
+    error
+};
+
+pub use self::{
+    callbacks::{
+        CallbackKind,
+        NewCallbackLog,
+        CALLBACK_RESULT_OK
+    },
+    orders::{
+        NewOrder,
+        NewOrderItem,
+        OrderRecord,
+        OrderFilter
+    },
+    settlements::{
+        NewSettlement,
+        SettlementRecord
+    },
+    ledger::{
+        NewLedgerEntry,
+        LedgerDailyTotal
+    },
+    auth::{
+        NewAuditRecord
+    }
+};
+
+#[cfg(feature = "sqlite")]
+use sqlx::sqlite::{
+    SqliteJournalMode
 };
 
+#[cfg(not(any(feature = "sqlite", feature = "postgres")))]
+compile_error!("At least one database backend feature must be enabled: sqlite or postgres");
+
+/// Миграции базы, встраиваются в бинарник при сборке.
+/// Для каждого бекенда свой набор, так как синтаксис SQL отличается.
+#[cfg(feature = "sqlite")]
+static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+#[cfg(feature = "postgres")]
+static POSTGRES_MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// Задержки между повторными попытками подключения к базе
+const CONNECT_RETRY_INITIAL_DELAY: Duration = Duration::from_millis(500);
+const CONNECT_RETRY_MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// Продукт из каталога, цена в минимальных единицах валюты
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ProductRecord{
+    pub product_id: i32,
+    pub product_name: String,
+    pub price: i64,
+    pub checkout_lifetime_secs: Option<i64>
+}
+
+/// Используемый бекенд базы данных, определяется по схеме DATABASE_URL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DatabaseKind{
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+
+    #[cfg(feature = "postgres")]
+    Postgres
+}
+
+impl DatabaseKind {
+    /// Определяет бекенд по адресу базы, ошибка если схема неизвестна или бекенд не включен при сборке
+    pub fn from_url(url: &str) -> Result<DatabaseKind, String> {
+        if url.starts_with("sqlite:") {
+            #[cfg(feature = "sqlite")]
+            return Ok(DatabaseKind::Sqlite);
+
+            #[cfg(not(feature = "sqlite"))]
+            return Err("DATABASE_URL is sqlite, but sqlite feature is disabled".to_owned());
+        }
+
+        if url.starts_with("postgres:") || url.starts_with("postgresql:") {
+            #[cfg(feature = "postgres")]
+            return Ok(DatabaseKind::Postgres);
+
+            #[cfg(not(feature = "postgres"))]
+            return Err("DATABASE_URL is postgres, but postgres feature is disabled".to_owned());
+        }
+
+        Err("DATABASE_URL must starts with sqlite:// or postgres://".to_owned())
+    }
+
+    /// Настройки журнала и блокировок есть только у SQLite
+    pub fn is_sqlite(self) -> bool {
+        match self {
+            #[cfg(feature = "sqlite")]
+            DatabaseKind::Sqlite => true,
+
+            #[cfg(feature = "postgres")]
+            DatabaseKind::Postgres => false
+        }
+    }
+
+    fn migrator(self) -> &'static Migrator {
+        match self {
+            #[cfg(feature = "sqlite")]
+            DatabaseKind::Sqlite => &SQLITE_MIGRATOR,
+
+            #[cfg(feature = "postgres")]
+            DatabaseKind::Postgres => &POSTGRES_MIGRATOR
+        }
+    }
+}
+
+/// Текущее время в секундах unix, время в базе храним числом для совместимости бекендов
+pub fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|val| val.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(feature = "sqlite")]
+fn is_sqlite_memory(url: &str) -> bool {
+    url.contains(":memory:") || url.contains("mode=memory")
+}
+
+/// Настройки пула и подключения для конкретного бекенда
+fn build_options(kind: DatabaseKind, config: &DatabaseConfig) -> Result<(AnyPoolOptions, AnyConnectOptions), FondyError> {
+    let pool_options = AnyPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .connect_timeout(config.connect_timeout);
+
+    match kind {
+        #[cfg(feature = "sqlite")]
+        DatabaseKind::Sqlite => {
+            let options = sqlx::sqlite::SqliteConnectOptions::from_str(&config.url)?
+                .busy_timeout(config.busy_timeout)
+                .foreign_keys(config.foreign_keys);
+
+            if is_sqlite_memory(&config.url) {
+                // База в памяти живет пока есть хотя бы одно соединение,
+                // поэтому соединение держим всегда и не закрываем по таймаутам
+                let pool_options = pool_options
+                    .min_connections(1)
+                    .idle_timeout(None)
+                    .max_lifetime(None);
+                let options = options
+                    .journal_mode(SqliteJournalMode::Memory);
+                Ok((pool_options, AnyConnectOptions::from(options)))
+            }else{
+                let options = options
+                    .journal_mode(SqliteJournalMode::from_str(&config.journal_mode)?)
+                    .create_if_missing(true);
+                Ok((pool_options, AnyConnectOptions::from(options)))
+            }
+        },
+
+        #[cfg(feature = "postgres")]
+        DatabaseKind::Postgres => {
+            Ok((pool_options, AnyConnectOptions::from_str(&config.url)?))
+        }
+    }
+}
+
+/// Состояние конкретной миграции
+#[derive(Debug)]
+pub struct MigrationStatus{
+    pub version: i64,
+    pub description: String,
+    pub applied: bool
+}
+
 #[derive(Debug)]
 pub struct Database{
-    pool: SqlitePool
+    kind: DatabaseKind,
+    pool: AnyPool
 }
 
 impl Database {
     /// Открывает базу данных и выполняет миграцию
     #[instrument]
-    pub async fn open_database() -> Database {
-        let db_url = std::env::var("DATABASE_URL")
-            .expect("Missing DATABASE_URL variable");
-
-        // Создаем файлик с пустой базой данных если его нету
-        {
-            const PREFIX: &str = "sqlite://";
-            assert!(db_url.starts_with(PREFIX), "DATABASE_URL must stats with {}", PREFIX);
-
-            let file_path = std::path::Path::new(db_url.trim_start_matches(PREFIX));
-            if !file_path.exists() {
-                if let Some(dir) = file_path.parent(){
-                    std::fs::create_dir_all(dir)
-                        .expect("Database directory create failed");
-                }
-                std::fs::File::create(file_path)
-                    .expect("Database file create failed");
+    pub async fn open_database(config: &DatabaseConfig) -> Result<Database, FondyError> {
+        let db = Database::connect(config)
+            .await?;
+
+        // Миграция базы
+        db.migrate()
+            .await?;
+
+        Ok(db)
+    }
+
+    /// Открывает базу данных без выполнения миграций.
+    /// При неудаче подключение повторяется с экспоненциальной задержкой.
+    #[instrument]
+    pub async fn connect(config: &DatabaseConfig) -> Result<Database, FondyError> {
+        let db_url = &config.url;
+
+        let kind = DatabaseKind::from_url(db_url)
+            .map_err(|err| FondyError::ConfigError(vec![err]))?;
+
+        // Создаем директорию для файла базы если ее нету, сам файл создаст драйвер
+        #[cfg(feature = "sqlite")]
+        if kind == DatabaseKind::Sqlite && !is_sqlite_memory(db_url) {
+            let file_path = std::path::Path::new(db_url.trim_start_matches("sqlite://"));
+            if let Some(dir) = file_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir)?;
             }
         }
 
-        // Пулл соединений
-        let pool = SqlitePoolOptions::new()
-            .connect(&db_url)
-            .await
-            .expect("Database connection failed");
-        debug!("Database pool created");
-        
-        // Миграция базы
-        sqlx::migrate!("./migrations")
-            .run(&pool)
-            .await
-            .expect("SQLx migration failed");
-        debug!("Migration complete");
+        // Пулл соединений, опции не клонируются, поэтому создаем их на каждую попытку
+        let mut delay = CONNECT_RETRY_INITIAL_DELAY;
+        let mut attempt = 0;
+        let pool = loop {
+            let (pool_options, connect_options) = build_options(kind, config)?;
+            match pool_options.connect_with(connect_options).await {
+                Ok(pool) => break pool,
+                Err(err) if attempt < config.connect_retries => {
+                    attempt += 1;
+                    warn!(%err, attempt, ?delay, "Database connection failed, retry");
+                    tokio::time::sleep(delay).await;
+                    delay = std::cmp::min(delay * 2, CONNECT_RETRY_MAX_DELAY);
+                },
+                Err(err) => {
+                    error!(%err, "Database connection failed");
+                    return Err(err.into());
+                }
+            }
+        };
+        debug!(?kind, "Database pool created");
 
-        Database{
+        Ok(Database{
+            kind,
             pool
-        }
+        })
+    }
+
+    pub fn kind(&self) -> DatabaseKind {
+        self.kind
+    }
+
+    /// Проверка соединения с базой
+    #[instrument(skip(self))]
+    pub async fn ping(&self) -> Result<(), FondyError> {
+        sqlx::query("SELECT 1")
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Закрывает пул, дожидаясь возврата соединений.
+    /// Для SQLite при этом завершаются транзакции и сбрасывается журнал WAL.
+    #[instrument(skip(self))]
+    pub async fn close(&self) {
+        self.pool.close().await;
+        debug!("Database pool closed");
+    }
+
+    /// Применяет все еще не примененные миграции
+    #[instrument(skip(self))]
+    pub async fn migrate(&self) -> Result<(), FondyError> {
+        self.kind
+            .migrator()
+            .run(&self.pool)
+            .await?;
+        debug!("Migration complete");
+        Ok(())
     }
-}
\ No newline at end of file
+
+    /// Список всех известных миграций с отметкой о применении
+    #[instrument(skip(self))]
+    pub async fn migration_status(&self) -> Result<Vec<MigrationStatus>, FondyError> {
+        let mut conn = self.pool.acquire().await?;
+        conn.ensure_migrations_table().await?;
+        let applied = conn.list_applied_migrations().await?;
+
+        let result = self
+            .kind
+            .migrator()
+            .iter()
+            .map(|migration|{
+                MigrationStatus{
+                    version: migration.version,
+                    description: migration.description.to_string(),
+                    applied: applied.iter().any(|val| val.version == migration.version)
+                }
+            })
+            .collect();
+
+        Ok(result)
+    }
+
+    /// Добавляет продукт, либо обновляет цену и время жизни оплаты уже существующего с таким же именем
+    #[instrument(skip(self))]
+    pub async fn upsert_product(&self, product_name: &str, price: i64, checkout_lifetime_secs: Option<i64>) -> Result<(), FondyError> {
+        sqlx::query("INSERT INTO products (product_name, price, checkout_lifetime_secs) VALUES ($1, $2, $3) \
+                     ON CONFLICT(product_name) DO UPDATE SET price = excluded.price, checkout_lifetime_secs = excluded.checkout_lifetime_secs")
+            .bind(product_name)
+            .bind(price)
+            .bind(checkout_lifetime_secs)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_product(&self, product_id: i32) -> Result<Option<ProductRecord>, FondyError> {
+        let product = sqlx::query_as::<_, ProductRecord>("SELECT product_id, product_name, price, checkout_lifetime_secs FROM products WHERE product_id = $1")
+            .bind(product_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(product)
+    }
+
+    /// Весь каталог продуктов
+    #[instrument(skip(self))]
+    pub async fn list_products(&self) -> Result<Vec<ProductRecord>, FondyError> {
+        let products = sqlx::query_as::<_, ProductRecord>("SELECT product_id, product_name, price, checkout_lifetime_secs FROM products ORDER BY product_id")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(products)
+    }
+
+    /// Обновляет продукт по идентификатору, возвращает false если продукта нет
+    #[instrument(skip(self))]
+    pub async fn update_product(&self, product: &ProductRecord) -> Result<bool, FondyError> {
+        let result = sqlx::query("UPDATE products SET product_name = $1, price = $2, checkout_lifetime_secs = $3 WHERE product_id = $4")
+            .bind(&product.product_name)
+            .bind(product.price)
+            .bind(product.checkout_lifetime_secs)
+            .bind(product.product_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use super::*;
+
+    /// База в памяти для тестов, на диске ничего не создается.
+    /// У каждого теста своя база, соединение с памятью не разделяется между пулами.
+    pub(super) async fn memory_database() -> Database {
+        Database::open_database(&DatabaseConfig::with_url("sqlite::memory:".to_owned()))
+            .await
+            .unwrap()
+    }
+
+    /// Продукт для тестов заказов, возвращает его идентификатор
+    pub(super) async fn test_product(db: &Database) -> i32 {
+        db.upsert_product("test", 200, None).await.unwrap();
+        db.list_products().await.unwrap()[0].product_id
+    }
+
+    // Драйвер SQLite использует block_in_place, поэтому нужен многопоточный runtime
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_migrations(){
+        let db = memory_database().await;
+        assert!(db.migration_status().await.unwrap().iter().all(|m| m.applied));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_products(){
+        let db = memory_database().await;
+        db.upsert_product("test", 100, None).await.unwrap();
+        db.upsert_product("test", 200, Some(600)).await.unwrap();
+        let products = db.list_products().await.unwrap();
+        assert_eq!(products.len(), 1);
+        assert_eq!(products[0].price, 200);
+
+        let product_id = products[0].product_id;
+        assert_eq!(db.get_product(product_id).await.unwrap().unwrap().checkout_lifetime_secs, Some(600));
+        assert!(db.get_product(product_id + 1).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,483 @@
+use tracing::{
+    instrument
+};
+use crate::{
+    error::{
+        FondyError
+    }
+};
+use super::{
+    Database,
+    unix_now
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Новый заказ, сохраняется до обращения к Fondy
+#[derive(Debug)]
+pub struct NewOrder<'a>{
+    pub order_id: &'a str,
+    pub product_id: Option<i32>,
+    pub amount: i64,
+    pub currency: &'a str,
+    /// Крайний срок оплаты, секунды unix
+    pub expires_at: Option<i64>,
+    pub customer_email: Option<&'a str>,
+    pub items: &'a [NewOrderItem],
+    /// Ключ идемпотентности вместе с хешем параметров запроса
+    pub idempotency: Option<(&'a str, &'a str)>
+}
+
+/// Позиция заказа, цена в минимальных единицах валюты
+#[derive(Debug, Clone)]
+pub struct NewOrderItem{
+    pub product_id: i32,
+    pub quantity: i64,
+    pub unit_price: i64
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct OrderRecord{
+    pub order_id: String,
+    pub product_id: Option<i32>,
+    pub amount: i64,
+    pub currency: String,
+    pub order_status: String,
+    pub payment_id: Option<String>,
+    pub checkout_url: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub expires_at: Option<i64>,
+    pub customer_email: Option<String>,
+    pub idempotency_hash: Option<String>,
+    /// Токен встроенной оплаты
+    pub checkout_token: Option<String>,
+    /// Последняя попытка сверки со статусом в Fondy
+    pub reconciled_at: Option<i64>
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct OrderItemRecord{
+    pub product_id: i32,
+    pub quantity: i64,
+    pub unit_price: i64
+}
+
+/// Фильтр списка заказов, незаданные поля не ограничивают выборку
+#[derive(Debug, Default)]
+pub struct OrderFilter{
+    pub order_status: Option<String>,
+    /// Полуинтервал [created_from, created_to), секунды unix
+    pub created_from: Option<i64>,
+    pub created_to: Option<i64>,
+    /// Продукт заказа или любой из его позиций
+    pub product_id: Option<i32>,
+    /// Часть адреса почты без учета регистра
+    pub customer_email: Option<String>,
+    pub amount_min: Option<i64>,
+    pub amount_max: Option<i64>
+}
+
+/// Количество и сумма заказов одного статуса
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct OrderStatusTotal{
+    pub order_status: String,
+    pub currency: String,
+    pub orders_count: i64,
+    pub amount: i64
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct OrderEventRecord{
+    pub from_status: Option<String>,
+    pub to_status: String,
+    pub event_source: String,
+    pub created_at: i64
+}
+
+/// Условие фильтра заказов, параметры с $1 по $7 соответствуют полям `OrderFilter`
+const ORDER_FILTER_CONDITION: &str = "($1 IS NULL OR order_status = $1) \
+                                      AND ($2 IS NULL OR created_at >= $2) \
+                                      AND ($3 IS NULL OR created_at < $3) \
+                                      AND ($4 IS NULL OR product_id = $4 OR EXISTS (SELECT 1 FROM order_items i WHERE i.order_id = orders.order_id AND i.product_id = $4)) \
+                                      AND ($5 IS NULL OR LOWER(customer_email) LIKE $5) \
+                                      AND ($6 IS NULL OR amount >= $6) \
+                                      AND ($7 IS NULL OR amount <= $7)";
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+impl Database {
+    /// Сохраняет новый заказ в статусе created вместе с первым событием истории.
+    /// Возвращает false, если заказ с тем же ключом идемпотентности уже есть.
+    /// Ключи заказов, созданных раньше `idempotency_since`, освобождаются для повторного использования.
+    #[instrument(skip(self))]
+    pub async fn insert_order(&self, order: NewOrder<'_>, event_source: &str, idempotency_since: i64) -> Result<bool, FondyError> {
+        let now = unix_now();
+        let mut transaction = self.pool.begin().await?;
+
+        let (idempotency_key, idempotency_hash) = order.idempotency.unzip();
+        if let Some(key) = idempotency_key {
+            sqlx::query("UPDATE orders SET idempotency_key = NULL WHERE idempotency_key = $1 AND created_at < $2")
+                .bind(key)
+                .bind(idempotency_since)
+                .execute(&mut transaction)
+                .await?;
+        }
+
+        // Параллельный запрос с тем же ключом упрется в уникальный индекс
+        let inserted = sqlx::query("INSERT INTO orders (order_id, product_id, amount, currency, order_status, created_at, updated_at, expires_at, customer_email, idempotency_key, idempotency_hash) \
+                                    VALUES ($1, $2, $3, $4, 'created', $5, $5, $6, $7, $8, $9) \
+                                    ON CONFLICT DO NOTHING")
+            .bind(order.order_id)
+            .bind(order.product_id)
+            .bind(order.amount)
+            .bind(order.currency)
+            .bind(now)
+            .bind(order.expires_at)
+            .bind(order.customer_email)
+            .bind(idempotency_key)
+            .bind(idempotency_hash)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected();
+        if inserted == 0 {
+            transaction.rollback().await?;
+            return Ok(false);
+        }
+
+        for item in order.items {
+            sqlx::query("INSERT INTO order_items (order_id, product_id, quantity, unit_price) VALUES ($1, $2, $3, $4)")
+                .bind(order.order_id)
+                .bind(item.product_id)
+                .bind(item.quantity)
+                .bind(item.unit_price)
+                .execute(&mut transaction)
+                .await?;
+        }
+
+        sqlx::query("INSERT INTO order_events (order_id, from_status, to_status, event_source, created_at) \
+                     VALUES ($1, NULL, 'created', $2, $3)")
+            .bind(order.order_id)
+            .bind(event_source)
+            .bind(now)
+            .execute(&mut transaction)
+            .await?;
+
+        transaction.commit().await?;
+        Ok(true)
+    }
+
+    /// Заказ по ключу идемпотентности, созданный не раньше `created_since`
+    #[instrument(skip(self))]
+    pub async fn get_order_by_idempotency_key(&self, idempotency_key: &str, created_since: i64) -> Result<Option<OrderRecord>, FondyError> {
+        let order = sqlx::query_as::<_, OrderRecord>("SELECT * FROM orders WHERE idempotency_key = $1 AND created_at >= $2")
+            .bind(idempotency_key)
+            .bind(created_since)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(order)
+    }
+
+    /// Освобождает ключ идемпотентности заказа, например если платеж создать не удалось
+    #[instrument(skip(self))]
+    pub async fn release_order_idempotency_key(&self, order_id: &str) -> Result<(), FondyError> {
+        sqlx::query("UPDATE orders SET idempotency_key = NULL WHERE order_id = $1")
+            .bind(order_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Сохраняет токен встроенной оплаты
+    #[instrument(skip(self))]
+    pub async fn set_order_checkout_token(&self, order_id: &str, checkout_token: &str) -> Result<(), FondyError> {
+        sqlx::query("UPDATE orders SET checkout_token = $1, updated_at = $2 WHERE order_id = $3")
+            .bind(checkout_token)
+            .bind(unix_now())
+            .bind(order_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Сохраняет данные созданного в Fondy платежа
+    #[instrument(skip(self))]
+    pub async fn set_order_checkout(&self, order_id: &str, payment_id: &str, checkout_url: &str) -> Result<(), FondyError> {
+        sqlx::query("UPDATE orders SET payment_id = $1, checkout_url = $2, updated_at = $3 WHERE order_id = $4")
+            .bind(payment_id)
+            .bind(checkout_url)
+            .bind(unix_now())
+            .bind(order_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_order(&self, order_id: &str) -> Result<Option<OrderRecord>, FondyError> {
+        let order = sqlx::query_as::<_, OrderRecord>("SELECT * FROM orders WHERE order_id = $1")
+            .bind(order_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(order)
+    }
+
+    /// Меняет статус заказа, только если текущий статус равен `from_status`.
+    /// Вместе со статусом в той же транзакции пишется событие истории.
+    /// Возвращает false, если статус успели поменять параллельно.
+    #[instrument(skip(self))]
+    pub async fn update_order_status(&self, order_id: &str, from_status: &str, to_status: &str, event_source: &str) -> Result<bool, FondyError> {
+        let now = unix_now();
+        let mut transaction = self.pool.begin().await?;
+
+        let updated = sqlx::query("UPDATE orders SET order_status = $1, updated_at = $2 WHERE order_id = $3 AND order_status = $4")
+            .bind(to_status)
+            .bind(now)
+            .bind(order_id)
+            .bind(from_status)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected();
+        if updated == 0 {
+            transaction.rollback().await?;
+            return Ok(false);
+        }
+
+        sqlx::query("INSERT INTO order_events (order_id, from_status, to_status, event_source, created_at) \
+                     VALUES ($1, $2, $3, $4, $5)")
+            .bind(order_id)
+            .bind(from_status)
+            .bind(to_status)
+            .bind(event_source)
+            .bind(now)
+            .execute(&mut transaction)
+            .await?;
+
+        transaction.commit().await?;
+        Ok(true)
+    }
+
+    /// Заказы в указанных статусах, созданные раньше `created_before`.
+    /// Сначала еще не сверявшиеся, затем дольше всех не сверявшиеся, среди равных самые старые.
+    #[instrument(skip(self))]
+    pub async fn list_orders_in_statuses(&self, statuses: &[&str], created_before: i64, limit: i64) -> Result<Vec<OrderRecord>, FondyError> {
+        // Список статусов короткий и фиксированный, поэтому просто перебираем их по одному
+        let mut result = Vec::new();
+        for status in statuses {
+            let mut orders = sqlx::query_as::<_, OrderRecord>("SELECT * FROM orders \
+                                                               WHERE order_status = $1 AND created_at < $2 \
+                                                               ORDER BY COALESCE(reconciled_at, 0), created_at LIMIT $3")
+                .bind(*status)
+                .bind(created_before)
+                .bind(limit)
+                .fetch_all(&self.pool)
+                .await?;
+            result.append(&mut orders);
+        }
+        result.sort_by_key(|order| (order.reconciled_at.unwrap_or(0), order.created_at));
+        result.truncate(limit.max(0) as usize);
+        Ok(result)
+    }
+
+    /// Отмечает попытку сверки заказа, следующий проход возьмет его после остальных
+    #[instrument(skip(self))]
+    pub async fn set_order_reconciled_at(&self, order_id: &str) -> Result<(), FondyError> {
+        sqlx::query("UPDATE orders SET reconciled_at = $1 WHERE order_id = $2")
+            .bind(unix_now())
+            .bind(order_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Заказы в указанных статусах, срок оплаты которых истек раньше `expired_before`
+    #[instrument(skip(self))]
+    pub async fn list_expired_orders(&self, statuses: &[&str], expired_before: i64, limit: i64) -> Result<Vec<OrderRecord>, FondyError> {
+        let mut result = Vec::new();
+        for status in statuses {
+            let mut orders = sqlx::query_as::<_, OrderRecord>("SELECT * FROM orders \
+                                                               WHERE order_status = $1 AND expires_at IS NOT NULL AND expires_at < $2 \
+                                                               ORDER BY expires_at LIMIT $3")
+                .bind(*status)
+                .bind(expired_before)
+                .bind(limit)
+                .fetch_all(&self.pool)
+                .await?;
+            result.append(&mut orders);
+        }
+        result.sort_by_key(|order| order.expires_at);
+        result.truncate(limit.max(0) as usize);
+        Ok(result)
+    }
+
+    /// Заказы, созданные в полуинтервале [from, to)
+    #[instrument(skip(self))]
+    pub async fn list_orders_created_between(&self, from: i64, to: i64) -> Result<Vec<OrderRecord>, FondyError> {
+        let orders = sqlx::query_as::<_, OrderRecord>("SELECT * FROM orders \
+                                                       WHERE created_at >= $1 AND created_at < $2 \
+                                                       ORDER BY created_at")
+            .bind(from)
+            .bind(to)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(orders)
+    }
+
+    /// Позиции заказа
+    #[instrument(skip(self))]
+    pub async fn list_order_items(&self, order_id: &str) -> Result<Vec<OrderItemRecord>, FondyError> {
+        let items = sqlx::query_as::<_, OrderItemRecord>("SELECT product_id, quantity, unit_price FROM order_items \
+                                                          WHERE order_id = $1 ORDER BY item_id")
+            .bind(order_id)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(items)
+    }
+
+    /// История изменения статусов заказа
+    #[instrument(skip(self))]
+    pub async fn list_order_events(&self, order_id: &str) -> Result<Vec<OrderEventRecord>, FondyError> {
+        let events = sqlx::query_as::<_, OrderEventRecord>("SELECT from_status, to_status, event_source, created_at FROM order_events \
+                                                                  WHERE order_id = $1 ORDER BY event_id")
+            .bind(order_id)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(events)
+    }
+
+    /// Страница заказов по фильтру, новые первыми
+    #[instrument(skip(self))]
+    pub async fn list_orders(&self, filter: &OrderFilter, limit: i64, offset: i64) -> Result<Vec<OrderRecord>, FondyError> {
+        let email = filter
+            .customer_email
+            .as_ref()
+            .map(|val| format!("%{}%", val.to_lowercase()));
+        let orders = sqlx::query_as::<_, OrderRecord>(&format!("SELECT * FROM orders WHERE {} \
+                                                                ORDER BY created_at DESC, order_id LIMIT $8 OFFSET $9", ORDER_FILTER_CONDITION))
+            .bind(filter.order_status.as_deref())
+            .bind(filter.created_from)
+            .bind(filter.created_to)
+            .bind(filter.product_id)
+            .bind(email)
+            .bind(filter.amount_min)
+            .bind(filter.amount_max)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(orders)
+    }
+
+    /// Количество заказов по фильтру
+    #[instrument(skip(self))]
+    pub async fn count_orders(&self, filter: &OrderFilter) -> Result<i64, FondyError> {
+        let email = filter
+            .customer_email
+            .as_ref()
+            .map(|val| format!("%{}%", val.to_lowercase()));
+        let count = sqlx::query_scalar(&format!("SELECT CAST(COUNT(*) AS BIGINT) FROM orders WHERE {}", ORDER_FILTER_CONDITION))
+            .bind(filter.order_status.as_deref())
+            .bind(filter.created_from)
+            .bind(filter.created_to)
+            .bind(filter.product_id)
+            .bind(email)
+            .bind(filter.amount_min)
+            .bind(filter.amount_max)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(count)
+    }
+
+    /// Количество и сумма заказов по статусам, созданных в полуинтервале [from, to)
+    #[instrument(skip(self))]
+    pub async fn order_status_totals(&self, from: i64, to: i64) -> Result<Vec<OrderStatusTotal>, FondyError> {
+        let totals = sqlx::query_as::<_, OrderStatusTotal>("SELECT order_status, currency, CAST(COUNT(*) AS BIGINT) AS orders_count, \
+                                                                   CAST(SUM(amount) AS BIGINT) AS amount \
+                                                            FROM orders WHERE created_at >= $1 AND created_at < $2 \
+                                                            GROUP BY order_status, currency ORDER BY order_status, currency")
+            .bind(from)
+            .bind(to)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(totals)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use super::*;
+    use super::super::tests::{
+        memory_database,
+        test_product
+    };
+
+    fn new_order<'a>(order_id: &'a str, product_id: i32, items: &'a [NewOrderItem], idempotency: Option<(&'a str, &'a str)>) -> NewOrder<'a> {
+        NewOrder{
+            order_id,
+            product_id: Some(product_id),
+            amount: 400,
+            currency: "USD",
+            expires_at: None,
+            customer_email: Some("test@gmail.com"),
+            items,
+            idempotency
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_orders(){
+        let db = memory_database().await;
+        let product_id = test_product(&db).await;
+
+        let items = [NewOrderItem{ product_id, quantity: 2, unit_price: 200 }];
+        assert!(db.insert_order(new_order("order-1", product_id, &items, None), "checkout", 0).await.unwrap());
+        let order = db.get_order("order-1").await.unwrap().unwrap();
+        assert_eq!(order.customer_email.as_deref(), Some("test@gmail.com"));
+        let items = db.list_order_items("order-1").await.unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!((items[0].quantity, items[0].unit_price), (2, 200));
+
+        let by_email = OrderFilter{ customer_email: Some("TEST@".to_owned()), ..Default::default() };
+        assert_eq!(db.count_orders(&by_email).await.unwrap(), 1);
+        let by_product = OrderFilter{ product_id: Some(product_id), amount_min: Some(400), ..Default::default() };
+        assert_eq!(db.list_orders(&by_product, 10, 0).await.unwrap().len(), 1);
+        let by_status = OrderFilter{ order_status: Some("approved".to_owned()), ..Default::default() };
+        assert_eq!(db.count_orders(&by_status).await.unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reconciliation_order(){
+        let db = memory_database().await;
+        let product_id = test_product(&db).await;
+        let items = [NewOrderItem{ product_id, quantity: 2, unit_price: 200 }];
+        for order_id in ["order-1", "order-2"].iter() {
+            db.insert_order(new_order(order_id, product_id, &items, None), "checkout", 0).await.unwrap();
+        }
+
+        // Уже сверявшийся заказ уходит в конец очереди
+        db.set_order_reconciled_at("order-1").await.unwrap();
+        let orders = db.list_orders_in_statuses(&["created"], unix_now() + 1, 1).await.unwrap();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].order_id, "order-2");
+        assert!(db.get_order("order-1").await.unwrap().unwrap().reconciled_at.is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_idempotency(){
+        let db = memory_database().await;
+        let product_id = test_product(&db).await;
+        let items = [NewOrderItem{ product_id, quantity: 2, unit_price: 200 }];
+        let key = Some(("buy:key", "hash"));
+        assert!(db.insert_order(new_order("order-1", product_id, &items, key), "checkout", 0).await.unwrap());
+
+        // Второй заказ с тем же ключом не сохраняется, пока ключ не устарел
+        assert!(!db.insert_order(new_order("order-2", product_id, &items, key), "checkout", 0).await.unwrap());
+        assert_eq!(db.get_order_by_idempotency_key("buy:key", 0).await.unwrap().unwrap().order_id, "order-1");
+        assert!(db.insert_order(new_order("order-2", product_id, &items, key), "checkout", unix_now() + 1).await.unwrap());
+        assert_eq!(db.get_order_by_idempotency_key("buy:key", 0).await.unwrap().unwrap().order_id, "order-2");
+        db.release_order_idempotency_key("order-2").await.unwrap();
+        assert!(db.get_order_by_idempotency_key("buy:key", 0).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,169 @@
+use tracing::{
+    instrument
+};
+use crate::{
+    error::{
+        FondyError
+    }
+};
+use super::{
+    Database,
+    unix_now
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Транзакция из отчета Fondy для сохранения, суммы в минимальных единицах валюты
+#[derive(Debug)]
+pub struct NewSettlement<'a>{
+    pub order_id: &'a str,
+    pub payment_id: &'a str,
+    pub tran_type: &'a str,
+    pub order_status: &'a str,
+    pub currency: &'a str,
+    pub amount: Option<i64>,
+    pub actual_amount: Option<i64>,
+    pub settlement_amount: Option<i64>,
+    pub settlement_currency: Option<&'a str>,
+    pub settlement_date: Option<&'a str>,
+    pub fee: Option<i64>,
+    /// Время транзакции, секунды unix
+    pub order_time: Option<i64>
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct SettlementRecord{
+    pub order_id: String,
+    pub payment_id: String,
+    pub tran_type: String,
+    pub order_status: String,
+    pub currency: String,
+    pub amount: Option<i64>,
+    pub actual_amount: Option<i64>,
+    pub settlement_amount: Option<i64>,
+    pub settlement_currency: Option<String>,
+    pub settlement_date: Option<String>,
+    pub fee: Option<i64>
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+impl Database {
+    /// Сохраняет транзакцию, повторный импорт того же периода обновляет уже сохраненные
+    #[instrument(skip(self))]
+    pub async fn upsert_settlement(&self, settlement: NewSettlement<'_>) -> Result<(), FondyError> {
+        sqlx::query("INSERT INTO settlements (order_id, payment_id, tran_type, order_status, currency, amount, actual_amount, \
+                                              settlement_amount, settlement_currency, settlement_date, fee, order_time, imported_at) \
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13) \
+                     ON CONFLICT(payment_id, tran_type) DO UPDATE SET \
+                        order_id = excluded.order_id, order_status = excluded.order_status, currency = excluded.currency, \
+                        amount = excluded.amount, actual_amount = excluded.actual_amount, \
+                        settlement_amount = excluded.settlement_amount, settlement_currency = excluded.settlement_currency, \
+                        settlement_date = excluded.settlement_date, fee = excluded.fee, order_time = excluded.order_time, \
+                        imported_at = excluded.imported_at")
+            .bind(settlement.order_id)
+            .bind(settlement.payment_id)
+            .bind(settlement.tran_type)
+            .bind(settlement.order_status)
+            .bind(settlement.currency)
+            .bind(settlement.amount)
+            .bind(settlement.actual_amount)
+            .bind(settlement.settlement_amount)
+            .bind(settlement.settlement_currency)
+            .bind(settlement.settlement_date)
+            .bind(settlement.fee)
+            .bind(settlement.order_time)
+            .bind(unix_now())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Транзакции, время которых попадает в полуинтервал [from, to)
+    #[instrument(skip(self))]
+    pub async fn list_settlements(&self, from: i64, to: i64) -> Result<Vec<SettlementRecord>, FondyError> {
+        let records = sqlx::query_as::<_, SettlementRecord>("SELECT * FROM settlements \
+                                                             WHERE order_time >= $1 AND order_time < $2 \
+                                                             ORDER BY order_time")
+            .bind(from)
+            .bind(to)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(records)
+    }
+
+    /// Отмечает день, отчет за который загружен, день в формате YYYY-MM-DD
+    #[instrument(skip(self))]
+    pub async fn mark_settlement_day_imported(&self, import_day: &str, transactions: i64) -> Result<(), FondyError> {
+        sqlx::query("INSERT INTO settlement_imports (import_day, transactions, imported_at) VALUES ($1, $2, $3) \
+                     ON CONFLICT(import_day) DO UPDATE SET transactions = excluded.transactions, imported_at = excluded.imported_at")
+            .bind(import_day)
+            .bind(transactions)
+            .bind(unix_now())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Последний загруженный день, строки YYYY-MM-DD сравниваются как даты
+    #[instrument(skip(self))]
+    pub async fn last_imported_settlement_day(&self) -> Result<Option<String>, FondyError> {
+        let day = sqlx::query_scalar("SELECT MAX(import_day) FROM settlement_imports")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(day)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use super::*;
+    use super::super::tests::{
+        memory_database
+    };
+
+    fn settlement(order_status: &str, order_time: i64) -> NewSettlement<'_> {
+        NewSettlement{
+            order_id: "order-1",
+            payment_id: "111",
+            tran_type: "purchase",
+            order_status,
+            currency: "USD",
+            amount: Some(1000),
+            actual_amount: Some(1000),
+            settlement_amount: Some(980),
+            settlement_currency: Some("USD"),
+            settlement_date: Some("17.05.2021"),
+            fee: Some(20),
+            order_time: Some(order_time)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_settlements(){
+        let db = memory_database().await;
+        db.upsert_settlement(settlement("processing", 100)).await.unwrap();
+
+        // Повторный импорт той же транзакции обновляет запись
+        db.upsert_settlement(settlement("approved", 150)).await.unwrap();
+        let records = db.list_settlements(100, 200).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].order_status, "approved");
+        assert_eq!(records[0].fee, Some(20));
+
+        // Правая граница периода не включается
+        assert!(db.list_settlements(0, 150).await.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_settlement_imports(){
+        let db = memory_database().await;
+        assert_eq!(db.last_imported_settlement_day().await.unwrap(), None);
+        db.mark_settlement_day_imported("2021-05-16", 2).await.unwrap();
+        db.mark_settlement_day_imported("2021-05-17", 0).await.unwrap();
+        db.mark_settlement_day_imported("2021-05-16", 3).await.unwrap();
+        assert_eq!(db.last_imported_settlement_day().await.unwrap().as_deref(), Some("2021-05-17"));
+    }
+}
@@ -1,6 +1,11 @@
 use quick_error::{
     quick_error
 };
+use warp::{
+    http::{
+        StatusCode
+    }
+};
 
 quick_error!{
     #[derive(Debug)]
@@ -17,11 +22,16 @@ quick_error!{
             from()
         }
 
+        // Ошибка шаблона большая, храним в куче, чтобы не раздувать все остальные варианты
+        TemplateFileError(err: Box<handlebars::TemplateFileError>){
+            from(err: handlebars::TemplateFileError) -> (Box::new(err))
+        }
+
         UrlError(err: url::ParseError){
             from()
         }
 
-        InvalidAPIResponse(err: crate::http::FondyInvalidResponse){
+        InvalidAPIResponse(err: crate::fondy::FondyInvalidResponse){
             from()
         }
 
@@ -32,6 +42,85 @@ quick_error!{
         SignatureCalculateError(desc: String){
         }
 
+        FondyCircuitOpen(retry_after: std::time::Duration){
+            display("Fondy requests are paused after failures, retry after {} seconds", retry_after.as_secs().max(1))
+        }
+
+        InvalidCallbackSignature{
+            display("Callback signature is invalid")
+        }
+
+        DatabaseError(err: sqlx::Error){
+            from()
+        }
+
+        MigrationError(err: sqlx::migrate::MigrateError){
+            from()
+        }
+
+        IOError(err: std::io::Error){
+            from()
+        }
+
+        CsvError(err: csv::Error){
+            from()
+        }
+
+        UnknownOrder(order_id: String){
+            display("Order {} is unknown", order_id)
+        }
+
+        UnknownProduct(product_id: i32){
+            display("Product {} is unknown", product_id)
+        }
+
+        InvalidRequest(desc: String){
+            display("Invalid request: {}", desc)
+        }
+
+        IdempotencyKeyReused{
+            display("Idempotency key was already used with different request parameters")
+        }
+
+        IdempotentRequestInProgress{
+            display("Request with this idempotency key is still in progress, retry later")
+        }
+
+        InvalidCsrfToken{
+            display("Form token is missing or expired, reload the page and try again")
+        }
+
+        RateLimited(retry_after: std::time::Duration){
+            display("Too many requests, retry after {} seconds", retry_after.as_secs().max(1))
+        }
+
+        Unauthorized{
+            display("Authentication required")
+        }
+
+        Forbidden(action: String){
+            display("Not enough permissions to {}", action)
+        }
+
+        ForbiddenSource(source: String){
+            display("Requests from {} are not allowed", source)
+        }
+
+        PasswordHashError(desc: String){
+            display("Password hash error: {}", desc)
+        }
+
+        UnbalancedLedgerEntry(entry_key: String){
+            display("Ledger entry {} is unbalanced", entry_key)
+        }
+
+        SecretLoadError(desc: String){
+        }
+
+        ConfigError(errors: Vec<String>){
+            display("Invalid config: {}", errors.join("; "))
+        }
+
         UTF8ParseError(err: std::str::Utf8Error){
             from()
         }
@@ -41,5 +130,80 @@ quick_error!{
     }
 }
 
+impl FondyError {
+    /// Стабильный код ошибки для клиентов, в отличие от текста не меняется
+    pub fn code(&self) -> &'static str {
+        match self {
+            FondyError::RequestError(_) => "fondy_unavailable",
+            FondyError::JsonParseError(_) => "fondy_bad_response",
+            FondyError::InvalidAPIResponse(_) => "fondy_error",
+            FondyError::FondyCircuitOpen(_) => "payments_unavailable",
+            FondyError::InvalidCallbackSignature => "invalid_signature",
+            FondyError::UnknownOrder(_) => "unknown_order",
+            FondyError::UnknownProduct(_) => "unknown_product",
+            FondyError::InvalidRequest(_) => "invalid_request",
+            FondyError::IdempotencyKeyReused => "idempotency_key_reused",
+            FondyError::IdempotentRequestInProgress => "request_in_progress",
+            FondyError::InvalidCsrfToken => "invalid_csrf_token",
+            FondyError::RateLimited(_) => "rate_limited",
+            FondyError::Unauthorized => "unauthorized",
+            FondyError::Forbidden(_) => "forbidden",
+            FondyError::ForbiddenSource(_) => "forbidden_source",
+            _ => "internal_error"
+        }
+    }
 
+    /// HTTP-код ответа
+    pub fn status(&self) -> StatusCode {
+        match self {
+            FondyError::RequestError(_) |
+            FondyError::JsonParseError(_) |
+            FondyError::InvalidAPIResponse(_) => StatusCode::BAD_GATEWAY,
+            FondyError::FondyCircuitOpen(_) => StatusCode::SERVICE_UNAVAILABLE,
+            FondyError::InvalidCallbackSignature |
+            FondyError::Unauthorized => StatusCode::UNAUTHORIZED,
+            FondyError::UnknownOrder(_) |
+            FondyError::UnknownProduct(_) => StatusCode::NOT_FOUND,
+            FondyError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            FondyError::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            FondyError::IdempotentRequestInProgress => StatusCode::CONFLICT,
+            FondyError::Forbidden(_) |
+            FondyError::ForbiddenSource(_) |
+            FondyError::InvalidCsrfToken => StatusCode::FORBIDDEN,
+            FondyError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
 
+    /// Текст для клиента, подробности внутренних ошибок остаются только в логе
+    pub fn public_message(&self) -> String {
+        match self {
+            FondyError::InvalidAPIResponse(response) => response.code().user_message().to_owned(),
+            FondyError::FondyCircuitOpen(_) => "Payments are temporarily unavailable, please try again in a few minutes".to_owned(),
+            _ => match self.status() {
+                StatusCode::INTERNAL_SERVER_ERROR => "Internal server error".to_owned(),
+                StatusCode::BAD_GATEWAY => "Payment provider request failed".to_owned(),
+                _ => self.to_string()
+            }
+        }
+    }
+
+    /// Через сколько можно повторить запрос, для заголовка Retry-After
+    pub fn retry_after(&self) -> Option<std::time::Duration> {
+        match self {
+            FondyError::FondyCircuitOpen(retry_after) |
+            FondyError::RateLimited(retry_after) => Some(*retry_after),
+            _ => None
+        }
+    }
+
+    /// Повтор того же запроса к Fondy может пройти: сетевой сбой или временная ошибка на стороне Fondy
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            FondyError::RequestError(err) => err.is_timeout() || err.is_connect() || err.is_request(),
+            FondyError::InvalidAPIResponse(response) => response.code().is_retryable(),
+            FondyError::FondyCircuitOpen(_) => true,
+            _ => false
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    sync::{
+        Arc
+    },
+    time::{
+        Duration
+    }
+};
+use tracing::{
+    debug,
+    error,
+    info,
+    warn,
+    instrument
+};
+use crate::{
+    health::{
+        Heartbeat
+    },
+    shutdown::{
+        ShutdownListener
+    },
+    config::{
+        ExpiryConfig
+    },
+    database::{
+        Database,
+        unix_now
+    },
+    error::{
+        FondyError
+    },
+    fondy::{
+        FondyClient,
+        OrderStatus,
+        FondyErrorCode
+    },
+    ledger::{
+        PaymentAmounts,
+        record_payment_amounts
+    },
+    orders::{
+        EventSource,
+        TransitionOutcome,
+        PENDING_STATUSES,
+        apply_status
+    }
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Итог одного прохода отметки просроченных заказов
+#[derive(Debug, Default)]
+pub struct ExpiryReport{
+    pub checked: usize,
+    /// Заказы, помеченные как expired
+    pub expired: usize,
+    /// Заказы, для которых Fondy вернул другой финальный статус, например оплата все же прошла
+    pub updated: usize,
+    /// Заказы, оплата которых еще в процессе, проверим на следующем проходе
+    pub still_processing: usize,
+    /// Заказы, которые не удалось проверить, с текстом ошибки
+    pub failed: Vec<(String, String)>
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Какой статус выставить просроченному заказу по данным Fondy.
+/// None, если платеж еще в процессе и заказ трогать рано.
+fn resolve_expired_status(remote: OrderStatus) -> Option<OrderStatus> {
+    match remote {
+        // Оплата началась до истечения срока, ждем финального статуса
+        OrderStatus::Processing => None,
+        // Fondy не принимает оплату после lifetime, даже если еще не поменял статус
+        OrderStatus::Created => Some(OrderStatus::Expired),
+        status => Some(status)
+    }
+}
+
+/// Один проход: берет заказы с истекшим сроком оплаты, подтверждает статус в Fondy
+/// и помечает их expired через машину состояний заказа
+#[instrument(skip(db, fondy))]
+pub async fn expire_orders(db: &Database, fondy: &FondyClient, grace: Duration, batch_size: i64) -> Result<ExpiryReport, FondyError> {
+    let expired_before = unix_now() - grace.as_secs() as i64;
+    let statuses: Vec<&str> = PENDING_STATUSES
+        .iter()
+        .map(|status| status.as_str())
+        .collect();
+    let orders = db
+        .list_expired_orders(&statuses, expired_before, batch_size)
+        .await?;
+    debug!(count = orders.len(), "Orders with expired checkout");
+
+    let mut report = ExpiryReport::default();
+    for order in orders {
+        report.checked += 1;
+
+        // Перед отметкой подтверждаем статус в Fondy, оплата могла пройти без коллбека
+        let (status, remote) = match fondy.order_status(&order.order_id).await {
+            Ok(remote) => (resolve_expired_status(remote.order_status), Some(remote)),
+            // Платеж в Fondy так и не был создан
+            Err(FondyError::InvalidAPIResponse(err)) if err.code() == FondyErrorCode::OrderNotFound => (Some(OrderStatus::Expired), None),
+            Err(err) => {
+                if err.is_retryable() {
+                    warn!(order_id = %order.order_id, %err, "Order status request failed, will retry on next pass");
+                }else{
+                    error!(order_id = %order.order_id, %err, "Order status request failed");
+                }
+                report.failed.push((order.order_id, err.to_string()));
+                continue;
+            }
+        };
+        let status = match status {
+            Some(status) => status,
+            None => {
+                debug!(order_id = %order.order_id, "Expired order is still processing");
+                report.still_processing += 1;
+                continue;
+            }
+        };
+
+        match apply_status(db, &order.order_id, status, EventSource::Expiry).await {
+            Ok(TransitionOutcome::Applied{ to: OrderStatus::Expired, .. }) => {
+                info!(order_id = %order.order_id, "Order expired");
+                // Резервов под заказ пока не делаем, поэтому освобождать нечего.
+                // Когда появятся, освобождать их нужно здесь.
+                report.expired += 1;
+            },
+            Ok(TransitionOutcome::Applied{ from, to }) => {
+                info!(order_id = %order.order_id, %from, %to, "Expired order status updated from Fondy");
+                report.updated += 1;
+
+                // Оплата или возврат прошли без коллбека, учитываем суммы по ответу статуса
+                if let Some(remote) = remote {
+                    let ledger_result = record_payment_amounts(db, PaymentAmounts{
+                            order_id: &order.order_id,
+                            order_status: to,
+                            currency: &remote.currency,
+                            actual_amount: remote.actual_amount as i64,
+                            reversal_amount: remote.reversal_amount as i64,
+                            fee: None
+                        })
+                        .await;
+                    if let Err(err) = ledger_result {
+                        error!(order_id = %order.order_id, %err, "Ledger update failed");
+                        report.failed.push((order.order_id, err.to_string()));
+                    }
+                }
+            },
+            Ok(outcome) => {
+                warn!(order_id = %order.order_id, ?outcome, "Expired order status is not changed");
+            },
+            Err(err) => {
+                error!(order_id = %order.order_id, %err, "Order status apply failed");
+                report.failed.push((order.order_id, err.to_string()));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Фоновая задача, периодически отмечающая просроченные заказы
+pub async fn run_expiry_worker(db: Arc<Database>, fondy: FondyClient, config: ExpiryConfig, heartbeat: Heartbeat, mut shutdown: ShutdownListener) {
+    info!(interval = ?config.interval, grace = ?config.grace, "Expiry worker started");
+
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        tokio::select!{
+            _ = interval.tick() => {},
+            _ = shutdown.wait() => break
+        }
+
+        match expire_orders(&db, &fondy, config.grace, config.batch_size).await {
+            Ok(report) => {
+                info!(checked = report.checked,
+                      expired = report.expired,
+                      updated = report.updated,
+                      still_processing = report.still_processing,
+                      failed = report.failed.len(),
+                      "Expiry check finished");
+            },
+            Err(err) => {
+                error!(%err, "Expiry check failed");
+            }
+        }
+
+        heartbeat.beat();
+    }
+
+    info!("Expiry worker stopped");
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_resolve_expired_status(){
+        assert_eq!(resolve_expired_status(OrderStatus::Created), Some(OrderStatus::Expired));
+        assert_eq!(resolve_expired_status(OrderStatus::Processing), None);
+        assert_eq!(resolve_expired_status(OrderStatus::Approved), Some(OrderStatus::Approved));
+        assert_eq!(resolve_expired_status(OrderStatus::Expired), Some(OrderStatus::Expired));
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    sync::{
+        Arc,
+        Mutex
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+use tracing::{
+    info,
+    warn
+};
+use crate::{
+    error::{
+        FondyError
+    },
+    metrics::{
+        fondy_circuit_changed
+    }
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+struct BreakerState{
+    /// Сбоев подряд
+    failures: u32,
+    /// Момент отключения запросов, None пока Fondy считается доступным
+    opened_at: Option<Instant>,
+    /// После паузы пропускаем только одну пробную попытку.
+    /// Если ее запрос был отменен и результата нет, через ту же паузу пропускаем новую
+    trial_started_at: Option<Instant>
+}
+
+/// Отключает запросы к Fondy после серии сбоев, чтобы не ждать таймаутов на каждом запросе.
+/// Через паузу пропускает одну пробную попытку, успех снова включает запросы.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker{
+    failure_threshold: u32,
+    open_duration: Duration,
+    state: Arc<Mutex<BreakerState>>
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_duration: Duration) -> CircuitBreaker {
+        CircuitBreaker{
+            failure_threshold,
+            open_duration,
+            state: Arc::new(Mutex::new(BreakerState{
+                failures: 0,
+                opened_at: None,
+                trial_started_at: None
+            }))
+        }
+    }
+
+    /// Можно ли отправлять запрос, иначе ошибка со временем до следующей попытки
+    pub fn check(&self) -> Result<(), FondyError> {
+        let mut state = self.state.lock().expect("Circuit breaker lock poisoned");
+        let opened_at = match state.opened_at {
+            Some(opened_at) => opened_at,
+            None => return Ok(())
+        };
+        // Пауза отсчитывается от отключения, либо от начала последней пробы
+        let elapsed = state.trial_started_at.unwrap_or(opened_at).elapsed();
+        if elapsed >= self.open_duration {
+            state.trial_started_at = Some(Instant::now());
+            return Ok(());
+        }
+        let retry_after = self.open_duration
+            .checked_sub(elapsed)
+            .unwrap_or(self.open_duration);
+        Err(FondyError::FondyCircuitOpen(retry_after))
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().expect("Circuit breaker lock poisoned");
+        if state.opened_at.is_some() {
+            info!("Fondy is available again, requests are resumed");
+            fondy_circuit_changed(false);
+        }
+        state.failures = 0;
+        state.opened_at = None;
+        state.trial_started_at = None;
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().expect("Circuit breaker lock poisoned");
+        state.failures += 1;
+        if state.trial_started_at.is_some() || (state.opened_at.is_none() && state.failures >= self.failure_threshold) {
+            warn!(failures = state.failures, pause = ?self.open_duration, "Fondy is unavailable, requests are paused");
+            if state.opened_at.is_none() {
+                fondy_circuit_changed(true);
+            }
+            state.opened_at = Some(Instant::now());
+            state.trial_started_at = None;
+        }
+    }
+}
+
+/// Сбой доступности Fondy в отличие от отказа по конкретному запросу
+pub fn is_outage(err: &FondyError) -> bool {
+    match err {
+        FondyError::RequestError(_) | FondyError::JsonParseError(_) => true,
+        FondyError::InvalidAPIResponse(response) => response.code().is_retryable(),
+        _ => false
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker(){
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(50));
+        breaker.record_failure();
+        assert!(breaker.check().is_ok());
+        breaker.record_failure();
+        assert!(matches!(breaker.check(), Err(FondyError::FondyCircuitOpen(_))));
+
+        // После паузы проходит только одна пробная попытка
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_err());
+
+        // Неудачная проба снова отключает запросы
+        breaker.record_failure();
+        assert!(breaker.check().is_err());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.check().is_ok());
+        breaker.record_success();
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn test_circuit_breaker_dropped_trial(){
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(60));
+
+        // Пробный запрос отменен без результата
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_err());
+
+        // Через паузу пропускается новая проба, а не отказ навсегда
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_err());
+        breaker.record_success();
+        assert!(breaker.check().is_ok());
+    }
+}
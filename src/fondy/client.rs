@@ -0,0 +1,290 @@
+use std::{
+    time::{
+        Duration,
+        Instant
+    }
+};
+use serde::{
+    de::{
+        DeserializeOwned
+    }
+};
+use serde_json::{
+    json
+};
+use tracing::{
+    debug,
+    error,
+    warn,
+    instrument
+};
+use tap::{
+    prelude::{
+        *
+    }
+};
+use reqwest_inspect_json::{
+    InspectJson
+};
+use url::{
+    Url
+};
+use chrono::{
+    NaiveDateTime
+};
+use rand_core::{
+    OsRng,
+    RngCore
+};
+use crate::{
+    error::{
+        FondyError
+    },
+    metrics::{
+        fondy_request_finished,
+        fondy_request_retried
+    },
+    config::{
+        AppConfig,
+        FondyConfig
+    },
+    secret::{
+        Secret
+    }
+};
+use super::{
+    messages::{
+        FondyDataOrErrorResponse,
+        FondyInvalidResponse,
+        FondyRedirectUrlResponse,
+        FondyTokenResponse,
+        FondyOrderStatusResponse,
+        FondyReverseResponse,
+        FondyCaptureResponse,
+        FondyReportTransaction
+    },
+    signature::{
+        calculate_signature
+    },
+    breaker::{
+        CircuitBreaker,
+        is_outage
+    }
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Версия протокола Fondy, которую мы используем
+pub const FONDY_PROTOCOL_VERSION: &str = "1.0.1";
+
+/// Формат даты в запросах отчетов
+const FONDY_REPORT_DATE_FORMAT: &str = "%d.%m.%Y %H:%M:%S";
+
+/// Клиент API Fondy, сам подставляет идентификатор продавца и подпись к запросам.
+/// Повторяет запросы при временных сбоях и перестает их отправлять, пока Fondy недоступен.
+#[derive(Debug, Clone)]
+pub struct FondyClient{
+    http_client: reqwest::Client, // Arc inside
+    api_url: Url,
+    merchant_id: u64,
+    merchant_password: Secret,
+    retry: FondyConfig,
+    breaker: CircuitBreaker // Arc inside
+}
+
+/// Можно ли повторять запрос, если ответ на него мог не дойти
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Idempotency{
+    /// Повтор ничего не меняет, например запрос статуса
+    Idempotent,
+    /// Повторяем только если соединение так и не установилось и запрос точно не ушел
+    NotIdempotent
+}
+
+/// Пауза перед повтором: экспоненциальный рост со случайным разбросом,
+/// чтобы повторы от многих запросов не приходили в Fondy одновременно
+fn retry_delay(config: &FondyConfig, attempt: u32) -> Duration {
+    let max_delay = config
+        .retry_base_delay
+        .checked_mul(1 << attempt.min(16))
+        .unwrap_or(config.retry_max_delay)
+        .min(config.retry_max_delay);
+    let half = max_delay / 2;
+    let jitter_ms = match half.as_millis() as u64 {
+        0 => 0,
+        range => OsRng.next_u64() % (range + 1)
+    };
+    half + Duration::from_millis(jitter_ms)
+}
+
+impl FondyClient {
+    pub fn new(config: &AppConfig) -> Result<FondyClient, FondyError> {
+        let http_client = reqwest::Client::builder()
+            .connect_timeout(config.fondy.connect_timeout)
+            .timeout(config.fondy.request_timeout)
+            .build()?;
+        Ok(FondyClient{
+            http_client,
+            api_url: config.fondy_api_url.clone(),
+            merchant_id: config.merchant_id,
+            merchant_password: config.merchant_password.clone(),
+            retry: config.fondy.clone(),
+            breaker: CircuitBreaker::new(config.fondy.breaker_failure_threshold, config.fondy.breaker_open_duration)
+        })
+    }
+
+    /// Выполняет подписанный запрос к методу API, `method` - путь относительно базового адреса API
+    #[instrument(skip(self, parameters))]
+    async fn request<R>(&self, method: &str, mut parameters: serde_json::Value, idempotency: Idempotency) -> Result<R, FondyError>
+    where
+        R: DeserializeOwned + std::fmt::Debug
+    {
+        let url = self
+            .api_url
+            .join(method)
+            .map_err(FondyError::from)
+            .tap_err(|err| { error!("Url join error: {}", err); })?;
+
+        // Идентификатор продавца и подпись добавляем ко всем запросам
+        parameters["merchant_id"] = json!(self.merchant_id);
+        let signature = calculate_signature(&self.merchant_password, &parameters, &["signature"])
+            .tap_err(|err| { error!("Signature calculate error: {}", err); })?;
+        parameters["signature"] = serde_json::Value::String(signature);
+
+        debug!("Fondy request params: {:#?}", &parameters);
+
+        let mut attempt = 0;
+        loop {
+            self.breaker
+                .check()
+                .tap_err(|err| { warn!("Fondy request skipped: {}", err); })?;
+
+            let response = self
+                .send::<R>(method, url.clone(), &parameters)
+                .await;
+            match &response {
+                Err(err) if is_outage(err) => self.breaker.record_failure(),
+                // Отказ по самому запросу тоже означает, что Fondy отвечает
+                _ => self.breaker.record_success()
+            }
+
+            let retry = match (&response, idempotency) {
+                (Err(err), Idempotency::Idempotent) => err.is_retryable(),
+                (Err(FondyError::RequestError(err)), Idempotency::NotIdempotent) => err.is_connect(),
+                _ => false
+            };
+            if !retry || attempt >= self.retry.max_retries {
+                return response;
+            }
+
+            let delay = retry_delay(&self.retry, attempt);
+            attempt += 1;
+            warn!(attempt, ?delay, "Fondy request failed, retrying");
+            fondy_request_retried(method);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Одна попытка запроса
+    async fn send<R>(&self, method: &str, url: Url, parameters: &serde_json::Value) -> Result<R, FondyError>
+    where
+        R: DeserializeOwned + std::fmt::Debug
+    {
+        // Замеряем весь запрос вместе с разбором ответа, ошибки на любом шаге тоже учитываются
+        let started = Instant::now();
+        let response = async {
+                self.http_client
+                    .post(url)
+                    .json(&json!({
+                        "request": parameters
+                    }))
+                    .send()
+                    .await
+                    .map_err(FondyError::from)
+                    .tap_err(|err|{ error!("Fondy request send failed: {}", err); })?
+                    .inspect_json::<FondyDataOrErrorResponse<R, FondyInvalidResponse>,
+                                    FondyError>(|data|{
+                        debug!("Fondy received data: {}", data)
+                    })
+                    .await
+                    .tap_err(|err| { error!("Fondy response parsing failed: {}", err); })?
+                    .into_result()
+                    .map_err(FondyError::from)
+                    .tap_err(|err| { error!("Fondy fail response: {:#?}", err); })
+            }
+            .await;
+        fondy_request_finished(method, started.elapsed(), response.as_ref().map(|_| ()));
+        let response = response?;
+
+        debug!("Received reponse: {:#?}", response);
+
+        Ok(response)
+    }
+
+    /// Проверка доступности API Fondy, любой HTTP ответ считается успехом
+    #[instrument(skip(self))]
+    pub async fn ping(&self, timeout: Duration) -> Result<(), FondyError> {
+        self.http_client
+            .get(self.api_url.clone())
+            .timeout(timeout)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Создание платежа с переходом на страницу оплаты Fondy
+    /// Параметры: https://docs.fondy.eu/ru/docs/page/3/
+    pub async fn checkout_url(&self, parameters: serde_json::Value) -> Result<FondyRedirectUrlResponse, FondyError> {
+        self.request("checkout/url", parameters, Idempotency::NotIdempotent)
+            .await
+    }
+
+    /// Создание платежа для встроенного на нашу страницу виджета оплаты
+    /// Параметры те же, что и для `checkout_url`
+    pub async fn checkout_token(&self, parameters: serde_json::Value) -> Result<FondyTokenResponse, FondyError> {
+        self.request("checkout/token", parameters, Idempotency::NotIdempotent)
+            .await
+    }
+
+    /// Запрос статуса заказа
+    pub async fn order_status(&self, order_id: &str) -> Result<FondyOrderStatusResponse, FondyError> {
+        self.request("status/order_id", json!({
+                "order_id": order_id,
+                "version": FONDY_PROTOCOL_VERSION
+            }), Idempotency::Idempotent)
+            .await
+    }
+
+    /// Возврат средств по заказу, сумма в минимальных единицах валюты
+    pub async fn reverse(&self, order_id: &str, amount: u64, currency: &str, comment: &str) -> Result<FondyReverseResponse, FondyError> {
+        self.request("reverse/order_id", json!({
+                "order_id": order_id,
+                "amount": amount,
+                "currency": currency,
+                "comment": comment,
+                "version": FONDY_PROTOCOL_VERSION
+            }), Idempotency::NotIdempotent)
+            .await
+    }
+
+    /// Списание ранее заблокированных (preauth) средств
+    pub async fn capture(&self, order_id: &str, amount: u64, currency: &str) -> Result<FondyCaptureResponse, FondyError> {
+        self.request("capture/order_id", json!({
+                "order_id": order_id,
+                "amount": amount,
+                "currency": currency,
+                "version": FONDY_PROTOCOL_VERSION
+            }), Idempotency::NotIdempotent)
+            .await
+    }
+
+    /// Список транзакций за период, время в UTC
+    pub async fn report_transactions(&self, date_from: NaiveDateTime, date_to: NaiveDateTime) -> Result<Vec<FondyReportTransaction>, FondyError> {
+        self.request("reports/", json!({
+                "date_from": date_from.format(FONDY_REPORT_DATE_FORMAT).to_string(),
+                "date_to": date_to.format(FONDY_REPORT_DATE_FORMAT).to_string(),
+                "version": FONDY_PROTOCOL_VERSION
+            }), Idempotency::Idempotent)
+            .await
+    }
+}
@@ -0,0 +1,80 @@
+/// Коды ошибок из ответов API Fondy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FondyErrorCode{
+    /// Внутренняя ошибка Fondy
+    InternalError,
+    /// Ошибка приложения Fondy
+    ApplicationError,
+    /// Терминал продавца заблокирован
+    TerminalBlocked,
+    /// Ограничения продавца, в том числе лимиты суммы и валюты
+    MerchantRestriction,
+    /// Не передан обязательный параметр или он пустой
+    MissingParameter,
+    /// Заказ с таким order_id у продавца уже есть
+    DuplicateOrderId,
+    /// Подпись запроса не сошлась, обычно неверный пароль продавца
+    InvalidSignature,
+    /// Неизвестный merchant_id
+    MerchantNotFound,
+    /// Заказа нет в Fondy
+    OrderNotFound,
+    /// Код, которого нет в списке выше
+    Other(i32)
+}
+
+impl FondyErrorCode {
+    pub fn from_code(code: i32) -> FondyErrorCode {
+        match code {
+            1000 => FondyErrorCode::InternalError,
+            1002 => FondyErrorCode::ApplicationError,
+            1004 => FondyErrorCode::TerminalBlocked,
+            1006 => FondyErrorCode::MerchantRestriction,
+            1011 => FondyErrorCode::MissingParameter,
+            1013 => FondyErrorCode::DuplicateOrderId,
+            1014 => FondyErrorCode::InvalidSignature,
+            1016 => FondyErrorCode::MerchantNotFound,
+            1018 => FondyErrorCode::OrderNotFound,
+            other => FondyErrorCode::Other(other)
+        }
+    }
+
+    /// Временный сбой на стороне Fondy, тот же запрос позже может пройти.
+    /// Остальные ошибки повторять бесполезно, нужно исправлять запрос или настройки продавца.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, FondyErrorCode::InternalError | FondyErrorCode::ApplicationError)
+    }
+
+    /// Текст для покупателя, подробности ошибки ему не нужны
+    pub fn user_message(&self) -> &'static str {
+        match self {
+            FondyErrorCode::InternalError |
+            FondyErrorCode::ApplicationError => "Payment service is temporarily unavailable, please try again in a few minutes",
+            FondyErrorCode::MerchantRestriction => "This payment can not be accepted, the amount or currency is outside the allowed limits",
+            FondyErrorCode::DuplicateOrderId => "This order has already been submitted, please start the purchase again",
+            FondyErrorCode::OrderNotFound => "Payment for this order is not found",
+            FondyErrorCode::TerminalBlocked |
+            FondyErrorCode::MissingParameter |
+            FondyErrorCode::InvalidSignature |
+            FondyErrorCode::MerchantNotFound |
+            FondyErrorCode::Other(_) => "Payments are temporarily unavailable, please try again later"
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_error_codes(){
+        assert_eq!(FondyErrorCode::from_code(1018), FondyErrorCode::OrderNotFound);
+        assert_eq!(FondyErrorCode::from_code(1013), FondyErrorCode::DuplicateOrderId);
+        assert_eq!(FondyErrorCode::from_code(4242), FondyErrorCode::Other(4242));
+        assert!(FondyErrorCode::from_code(1000).is_retryable());
+        assert!(!FondyErrorCode::from_code(1014).is_retryable());
+        assert!(!FondyErrorCode::Other(4242).is_retryable());
+    }
+}
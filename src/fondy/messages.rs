@@ -0,0 +1,403 @@
+use serde::{
+    Deserialize,
+    Serialize
+};
+use serde_with::{
+    serde_as,
+    DisplayFromStr,
+    NoneAsEmptyString
+};
+use super::{
+    errors::{
+        FondyErrorCode
+    }
+};
+
+/*
+/// Специальный шаблонный тип, чтобы можно было парсить возвращаемые ошибки в ответах.
+/// А после этого - конвертировать в результаты.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum DataOrErrorResponse<D, E>{
+    Ok(D),
+    Err(E)
+}
+impl<D, E> DataOrErrorResponse<D, E> {
+    pub fn into_result(self) -> Result<D, E> {
+        match self {
+            DataOrErrorResponse::Ok(ok) => Ok(ok),
+            DataOrErrorResponse::Err(err) => Err(err),
+        }
+    }
+}*/
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Deserialize, Debug)]
+pub struct FondyResponse<D>{
+    pub response: D
+}
+impl<D> FondyResponse<D> {
+    pub fn into_response(self) -> D {
+        self.response
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Специальный шаблонный тип, чтобы можно было парсить возвращаемые ошибки в ответах.
+/// А после этого - конвертировать в результаты.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum FondyDataOrErrorResponse<D, E>{
+    Ok(FondyResponse<D>),
+    Err(FondyResponse<E>)
+}
+impl<D, E> FondyDataOrErrorResponse<D, E> {
+    pub fn into_result(self) -> Result<D, E> {
+        match self {
+            FondyDataOrErrorResponse::Ok(ok) => Ok(ok.into_response()),
+            FondyDataOrErrorResponse::Err(err) => Err(err.into_response()),
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ResponseStatus {
+    #[serde(rename = "success")]
+    Success,
+
+    #[serde(rename = "failure")]
+    Failure
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct FondyInvalidResponse{
+    pub response_status: ResponseStatus,
+    pub error_code: i32,
+    pub error_message: String
+}
+impl FondyInvalidResponse {
+    pub fn code(&self) -> FondyErrorCode {
+        FondyErrorCode::from_code(self.error_code)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct FondyRedirectUrlResponse{
+    pub response_status: ResponseStatus,
+    pub checkout_url: String, // TODO: Decode as url
+    pub payment_id: String
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct FondyTokenResponse{
+    pub response_status: ResponseStatus,
+    pub token: String
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderStatus {
+    #[serde(rename = "created")]
+    Created,
+
+    #[serde(rename = "processing")]
+    Processing,
+
+    #[serde(rename = "declined")]
+    Declined,
+
+    #[serde(rename = "approved")]
+    Approved,
+    
+    #[serde(rename = "expired")]
+    Expired,
+
+    #[serde(rename = "reversed")]
+    Reversed
+}
+
+impl OrderStatus {
+    pub const ALL: [OrderStatus; 6] = [
+        OrderStatus::Created,
+        OrderStatus::Processing,
+        OrderStatus::Declined,
+        OrderStatus::Approved,
+        OrderStatus::Expired,
+        OrderStatus::Reversed
+    ];
+
+    /// Строковое значение как в API Fondy и в базе
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OrderStatus::Created => "created",
+            OrderStatus::Processing => "processing",
+            OrderStatus::Declined => "declined",
+            OrderStatus::Approved => "approved",
+            OrderStatus::Expired => "expired",
+            OrderStatus::Reversed => "reversed"
+        }
+    }
+}
+
+impl std::str::FromStr for OrderStatus {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        OrderStatus::ALL
+            .iter()
+            .find(|status| status.as_str() == text)
+            .copied()
+            .ok_or_else(|| format!("Unknown order status: {}", text))
+    }
+}
+
+impl std::fmt::Display for OrderStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum TransactionType {
+    #[serde(rename = "purchase")]
+    Purchase,
+
+    #[serde(rename = "reverse")]
+    Reverse
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize, Debug)]
+pub enum VerificationStatus {
+    #[serde(rename = "verified")]
+    Varified,
+
+    #[serde(rename = "incorrect")]
+    Incorrect,
+
+    #[serde(rename = "failed")]
+    Failed,
+
+    #[serde(rename = "created")]
+    Created,
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// TODO: Десереализация строк в enum
+// Описание: https://docs.fondy.eu/ru/docs/page/3/
+#[allow(dead_code)]
+#[serde_as]
+#[derive(Debug, Deserialize)]
+pub struct FondyPaymentResponse{
+    #[serde_as(as = "DisplayFromStr")]
+    pub amount: u64,
+    
+    #[serde_as(as = "NoneAsEmptyString")]
+    pub response_code: Option<String>,
+    
+    #[serde_as(as = "DisplayFromStr")]
+    pub reversal_amount: u64,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub settlement_amount: u64,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub actual_amount: u64,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub approval_code: u64,
+
+    pub order_id: String,
+    pub merchant_id: u64,
+    pub currency: String,
+    pub order_status: OrderStatus,
+    pub response_status: ResponseStatus,
+    pub signature: String,
+    pub tran_type: TransactionType,
+    pub sender_cell_phone: String,
+    pub sender_account: String,
+    pub masked_card: String,
+    pub card_bin: u64,
+    pub card_type: String,
+    pub rrn: String,
+    pub response_description: String,
+    pub settlement_currency: String,
+    pub order_time: String,
+    pub settlement_date: String,
+    pub eci: String,
+    pub fee: String,
+    pub payment_system: String,
+    pub sender_email: String,
+    pub payment_id: u32,
+    pub actual_currency: String,
+    pub product_id: String,
+    pub merchant_data: String,
+    pub verification_status: String, // VerificationStatus,
+    pub rectoken: String,
+    pub rectoken_lifetime: String,
+    // pub additional_info: serde_json::Value
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Ответ на запрос статуса заказа
+/// Описание: https://docs.fondy.eu/ru/docs/page/10/
+#[allow(dead_code)]
+#[serde_as]
+#[derive(Debug, Deserialize)]
+pub struct FondyOrderStatusResponse{
+    pub response_status: ResponseStatus,
+    pub order_id: String,
+    pub order_status: OrderStatus,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub amount: u64,
+
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub actual_amount: u64,
+
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub reversal_amount: u64,
+
+    pub currency: String,
+
+    #[serde(default)]
+    pub tran_type: Option<TransactionType>
+}
+
+impl FondyOrderStatusResponse {
+    /// Сумма, которую еще можно вернуть или списать: фактически оплаченная минус уже возвращенная
+    pub fn remaining_amount(&self) -> u64 {
+        self.actual_amount.saturating_sub(self.reversal_amount)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Ответ на запрос возврата средств
+/// Описание: https://docs.fondy.eu/ru/docs/page/11/
+#[allow(dead_code)]
+#[serde_as]
+#[derive(Debug, Deserialize)]
+pub struct FondyReverseResponse{
+    pub response_status: ResponseStatus,
+    pub order_id: String,
+    pub reverse_status: String,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub reversal_amount: u64,
+
+    pub currency: String,
+
+    #[serde(default)]
+    pub transaction_id: Option<String>
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Ответ на запрос списания ранее заблокированных средств
+/// Описание: https://docs.fondy.eu/ru/docs/page/12/
+#[allow(dead_code)]
+#[serde_as]
+#[derive(Debug, Deserialize)]
+pub struct FondyCaptureResponse{
+    pub response_status: ResponseStatus,
+    pub order_id: String,
+    pub capture_status: String,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub capture_amount: u64
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Сумма в отчетах Fondy приходит то числом, то строкой, а если ее нет - пустой строкой
+fn deserialize_optional_amount<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: serde::Deserializer<'de>
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::Number(number) => number
+            .as_i64()
+            .map(Some)
+            .ok_or_else(|| serde::de::Error::custom(format!("Invalid amount: {}", number))),
+        serde_json::Value::String(text) if text.trim().is_empty() => Ok(None),
+        serde_json::Value::String(text) => text
+            .trim()
+            .parse::<i64>()
+            .map(Some)
+            .map_err(|err| serde::de::Error::custom(format!("Invalid amount {}: {}", text, err))),
+        other => Err(serde::de::Error::custom(format!("Invalid amount: {}", other)))
+    }
+}
+
+/// Идентификатор платежа тоже бывает и числом, и строкой
+fn deserialize_string_or_number<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(text) => Ok(text),
+        serde_json::Value::Number(number) => Ok(number.to_string()),
+        other => Err(serde::de::Error::custom(format!("Invalid identifier: {}", other)))
+    }
+}
+
+/// Транзакция из отчета Fondy за период, суммы в минимальных единицах валюты
+#[derive(Debug, Clone, Deserialize)]
+pub struct FondyReportTransaction{
+    pub order_id: String,
+
+    #[serde(deserialize_with = "deserialize_string_or_number")]
+    pub payment_id: String,
+
+    pub tran_type: String,
+
+    pub order_status: String,
+
+    pub currency: String,
+
+    #[serde(deserialize_with = "deserialize_optional_amount")]
+    pub amount: Option<i64>,
+
+    #[serde(default, deserialize_with = "deserialize_optional_amount")]
+    pub actual_amount: Option<i64>,
+
+    #[serde(default, deserialize_with = "deserialize_optional_amount")]
+    pub settlement_amount: Option<i64>,
+
+    #[serde(default)]
+    pub settlement_currency: Option<String>,
+
+    #[serde(default)]
+    pub settlement_date: Option<String>,
+
+    #[serde(default, deserialize_with = "deserialize_optional_amount")]
+    pub fee: Option<i64>,
+
+    #[serde(default)]
+    pub order_time: Option<String>
+}
@@ -0,0 +1,25 @@
+mod messages;
+mod errors;
+mod signature;
+mod client;
+mod breaker;
+
+pub use self::{
+    client::{
+        FondyClient,
+        FONDY_PROTOCOL_VERSION
+    },
+    messages::{
+        FondyInvalidResponse,
+        FondyPaymentResponse,
+        FondyReportTransaction,
+        OrderStatus
+    },
+    errors::{
+        FondyErrorCode
+    },
+    signature::{
+        calculate_signature,
+        verify_callback_signature
+    }
+};
@@ -0,0 +1,139 @@
+use sha1::{
+    Digest
+};
+use tracing::{
+    debug, 
+    instrument
+};
+use crate::{
+    error::{
+        FondyError
+    },
+    secret::{
+        Secret
+    }
+};
+
+#[instrument(err, skip(json_data, skip_keys, password))]
+pub fn calculate_signature(password: &Secret, json_data: &serde_json::Value, skip_keys: &[&str]) -> Result<String, FondyError> {
+    let data_map = json_data
+        .as_object()
+        .ok_or_else(||{
+            FondyError::SignatureCalculateError("Json data must be dictionary".to_owned())
+        })?;
+
+    let mut key_value_vec: Vec<(&String, &serde_json::Value)> = data_map
+        .iter()
+        .collect();
+
+    key_value_vec
+        .sort_by(|v1, v2|{
+            v1.0.cmp(v2.0)
+        });
+
+    // Пароль в начало строки не добавляем, чтобы строка могла безопасно попасть в лог
+    let joined_values = key_value_vec
+        .iter()
+        .filter(|val|{
+            // Фильтруем на всякий пожарный ключ сигнатуры
+            !skip_keys.contains(&val.0.as_str())
+        })
+        .fold(String::new(), |mut prev, val|{
+            match val.1 {
+                serde_json::Value::Bool(_) |
+                serde_json::Value::Number(_) => {
+                    prev.push('|');
+                    prev.push_str(val.1.to_string().trim_matches('\"'));
+                },
+                serde_json::Value::String(text) if !text.is_empty() => {
+                    prev.push('|');
+                    prev.push_str(val.1.to_string().trim_matches('\"'));
+                }
+                _ =>{
+                }
+            }
+            prev
+        });
+    debug!("Joined values without password: {}", joined_values);
+
+    // TODO: Может быть проще в цикле просто вызывать update для каждого значения?
+    let mut sha = sha1::Sha1::new();
+    sha.update(password.expose());
+    sha.update(joined_values);
+    // Саму подпись не логируем: для данных коллбека это готовая подпись мерчанта
+    Ok(format!("{:x}", sha.finalize()))
+}
+
+/// Проверяет подпись пришедших от Fondy данных коллбека
+pub fn verify_callback_signature(password: &Secret, json_data: &serde_json::Value) -> Result<bool, FondyError> {
+    // Текущая полученная подпись
+    let received_signature = json_data
+        .as_object()
+        .ok_or_else(||{
+            FondyError::InvalidRequest("callback data must be dictionary".to_string())
+        })?
+        .get("signature")
+        .ok_or_else(||{
+            FondyError::InvalidRequest("signature field is missing".to_string())
+        })?
+        .as_str()
+        .ok_or_else(||{
+            FondyError::InvalidRequest("signature must be string".to_string())
+        })?;
+
+    // Вычисляем подпись, пропуская поля для сигнатуры
+    let calculated_signature = calculate_signature(password, json_data, &["signature", "response_signature_string"])?;
+
+    // Сравниваем хеши, чтобы время сравнения не зависело от совпавшего префикса
+    if sha1::Sha1::digest(received_signature.as_bytes()) == sha1::Sha1::digest(calculated_signature.as_bytes()) {
+        Ok(true)
+    }else{
+        let order_id = json_data.get("order_id").and_then(|val| val.as_str());
+        debug!(?order_id, received_signature, "Callback signature does not match");
+        Ok(false)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use serde_json::{
+        json
+    };
+    use super::*;
+
+    #[test]
+    fn test_signature(){
+        let password = Secret::new("test".to_owned());
+        let data = json!({
+            "order_id": "test123",
+            "order_desc": "test order",
+            "currency": "USD",
+            "amount": 125,
+            "merchant_id": 1396424,
+            "merchant_data": "",
+            "signature": "skipped"
+        });
+        let signature = calculate_signature(&password, &data, &["signature"]).unwrap();
+        assert_eq!(signature, "57443c35cbeb44d68e42eeaadd62222a573bde28");
+    }
+
+    #[test]
+    fn test_verify_callback_signature(){
+        let password = Secret::new("test".to_owned());
+        let mut data = json!({
+            "order_id": "test123",
+            "order_desc": "test order",
+            "currency": "USD",
+            "amount": 125,
+            "merchant_id": 1396424,
+            "response_signature_string": "**********|125|USD",
+            "signature": "57443c35cbeb44d68e42eeaadd62222a573bde28"
+        });
+        assert!(verify_callback_signature(&password, &data).unwrap());
+
+        data["amount"] = json!(126);
+        assert!(!verify_callback_signature(&password, &data).unwrap());
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    collections::{
+        BTreeMap
+    },
+    future::{
+        Future
+    },
+    sync::{
+        Arc,
+        Mutex
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+use serde_json::{
+    json
+};
+use tracing::{
+    warn,
+    instrument
+};
+use crate::{
+    application::{
+        Application
+    },
+    error::{
+        FondyError
+    }
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Запас сверх интервала фоновой задачи, после которого она считается зависшей
+const HEARTBEAT_GRACE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+struct WorkerBeat{
+    interval: Duration,
+    last_beat: Instant
+}
+
+/// Отметки о живости фоновых задач
+#[derive(Debug, Clone, Default)]
+pub struct Heartbeats{
+    workers: Arc<Mutex<BTreeMap<&'static str, WorkerBeat>>>
+}
+
+/// Отметка одной фоновой задачи, задача обновляет ее после каждого прохода
+#[derive(Debug, Clone)]
+pub struct Heartbeat{
+    name: &'static str,
+    workers: Arc<Mutex<BTreeMap<&'static str, WorkerBeat>>>
+}
+
+impl Heartbeats {
+    /// Регистрирует задачу, запуск считается первой отметкой
+    pub fn register(&self, name: &'static str, interval: Duration) -> Heartbeat {
+        self.workers
+            .lock()
+            .expect("Heartbeats lock poisoned")
+            .insert(name, WorkerBeat{
+                interval,
+                last_beat: Instant::now()
+            });
+        Heartbeat{
+            name,
+            workers: self.workers.clone()
+        }
+    }
+
+    fn snapshot(&self) -> Vec<(&'static str, WorkerBeat)> {
+        self.workers
+            .lock()
+            .expect("Heartbeats lock poisoned")
+            .iter()
+            .map(|(name, beat)| (*name, *beat))
+            .collect()
+    }
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        if let Some(worker) = self.workers.lock().expect("Heartbeats lock poisoned").get_mut(self.name) {
+            worker.last_beat = Instant::now();
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Результат одной проверки готовности
+fn check_result(ok: bool, started: Instant, details: serde_json::Value) -> serde_json::Value {
+    let mut result = json!({
+        "ok": ok,
+        "duration_ms": started.elapsed().as_millis() as u64
+    });
+    if let (Some(result), Some(details)) = (result.as_object_mut(), details.as_object()) {
+        result.extend(details.clone());
+    }
+    result
+}
+
+/// Ограничивает время проверки, зависшая база или сеть не должны вешать сам запрос проверки
+async fn with_timeout<T, F>(timeout: Duration, future: F) -> Result<T, FondyError>
+where
+    F: Future<Output = Result<T, FondyError>>
+{
+    tokio::time::timeout(timeout, future)
+        .await
+        .map_err(|_| FondyError::Custom(format!("Timed out after {:?}", timeout)))?
+}
+
+async fn check_database(app: &Application) -> serde_json::Value {
+    let started = Instant::now();
+    match with_timeout(app.config.health.timeout, app.db.ping()).await {
+        Ok(()) => check_result(true, started, json!({})),
+        Err(err) => check_result(false, started, json!({ "error": err.to_string() }))
+    }
+}
+
+async fn check_migrations(app: &Application) -> serde_json::Value {
+    let started = Instant::now();
+    match with_timeout(app.config.health.timeout, app.db.migration_status()).await {
+        Ok(migrations) => {
+            let pending: Vec<i64> = migrations
+                .iter()
+                .filter(|migration| !migration.applied)
+                .map(|migration| migration.version)
+                .collect();
+            check_result(pending.is_empty(), started, json!({ "pending": pending }))
+        },
+        Err(err) => check_result(false, started, json!({ "error": err.to_string() }))
+    }
+}
+
+fn check_templates(app: &Application, required: &[String]) -> serde_json::Value {
+    let started = Instant::now();
+    let missing: Vec<&String> = required
+        .iter()
+        .filter(|name| !app.templates.has_template(name))
+        .collect();
+    check_result(missing.is_empty(), started, json!({ "missing": missing }))
+}
+
+fn check_workers(app: &Application) -> serde_json::Value {
+    let started = Instant::now();
+    let mut all_alive = true;
+    let workers: Vec<serde_json::Value> = app
+        .heartbeats
+        .snapshot()
+        .into_iter()
+        .map(|(name, beat)| {
+            let since_last_beat = beat.last_beat.elapsed();
+            let alive = since_last_beat <= beat.interval + HEARTBEAT_GRACE;
+            all_alive &= alive;
+            json!({
+                "name": name,
+                "alive": alive,
+                "interval_secs": beat.interval.as_secs(),
+                "last_beat_secs_ago": since_last_beat.as_secs()
+            })
+        })
+        .collect();
+    check_result(all_alive, started, json!({ "workers": workers }))
+}
+
+async fn check_fondy(app: &Application) -> serde_json::Value {
+    let started = Instant::now();
+    match app.fondy.ping(app.config.health.timeout).await {
+        Ok(()) => check_result(true, started, json!({ "url": app.config.fondy_api_url.as_str() })),
+        Err(err) => check_result(false, started, json!({
+            "url": app.config.fondy_api_url.as_str(),
+            "error": err.to_string()
+        }))
+    }
+}
+
+/// Все проверки готовности принимать запросы, вместе с признаком общего успеха
+#[instrument(skip(app, required_templates))]
+pub async fn readiness(app: &Application, required_templates: &[String]) -> (bool, serde_json::Value) {
+    let mut checks = serde_json::Map::new();
+    checks.insert("database".to_owned(), check_database(app).await);
+    checks.insert("migrations".to_owned(), check_migrations(app).await);
+    checks.insert("templates".to_owned(), check_templates(app, required_templates));
+    checks.insert("workers".to_owned(), check_workers(app));
+    if app.config.health.check_fondy {
+        checks.insert("fondy".to_owned(), check_fondy(app).await);
+    }
+
+    let ready = checks
+        .values()
+        .all(|check| check["ok"] == json!(true));
+    if !ready {
+        warn!(?checks, "Service is not ready");
+    }
+
+    (ready, json!({
+        "status": if ready { "ok" } else { "fail" },
+        "checks": checks
+    }))
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_heartbeats(){
+        let heartbeats = Heartbeats::default();
+        let heartbeat = heartbeats.register("test", Duration::from_secs(10));
+        let before = heartbeats.snapshot()[0].1.last_beat;
+        heartbeat.beat();
+        let after = heartbeats.snapshot()[0].1.last_beat;
+        assert!(after >= before);
+        assert_eq!(heartbeats.snapshot()[0].0, "test");
+    }
+}
@@ -0,0 +1,652 @@
+use std::{
+    collections::{
+        BTreeMap
+    },
+    sync::{
+        Arc
+    }
+};
+use chrono::{
+    NaiveDate,
+    NaiveDateTime,
+    Utc
+};
+use serde::{
+    Deserialize
+};
+use serde_json::{
+    json
+};
+use tracing::{
+    error,
+    info,
+    instrument
+};
+use tap::{
+    prelude::{
+        *
+    }
+};
+use warp::{
+    Filter,
+    Reply,
+    Rejection,
+    http::{
+        StatusCode,
+        header::{
+            SET_COOKIE
+        }
+    }
+};
+use crate::{
+    application::{
+        Application
+    },
+    auth::{
+        Permission,
+        Principal,
+        audit,
+        audit_outcome,
+        audit_requested,
+        login,
+        logout
+    },
+    database::{
+        OrderFilter,
+        OrderRecord,
+        ProductRecord
+    },
+    error::{
+        FondyError
+    },
+    fondy::{
+        OrderStatus
+    }
+};
+use super::{
+    auth::{
+        SESSION_COOKIE,
+        authorized,
+        session_cookie
+    }
+};
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+/// Заказов на одной странице списка
+const ORDERS_PAGE_SIZE: i64 = 50;
+
+/// Сумма в минимальных единицах валюты для показа человеку
+fn format_amount(amount: i64) -> String {
+    let sign = if amount < 0 { "-" } else { "" };
+    format!("{}{}.{:02}", sign, amount.abs() / 100, amount.abs() % 100)
+}
+
+/// Время unix в UTC для показа человеку
+fn format_time(timestamp: i64) -> String {
+    NaiveDateTime::from_timestamp_opt(timestamp, 0)
+        .map(|val| val.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+fn order_to_json(order: &OrderRecord) -> serde_json::Value {
+    json!({
+        "order_id": order.order_id,
+        "product_id": order.product_id,
+        "amount": format_amount(order.amount),
+        "currency": order.currency,
+        "order_status": order.order_status,
+        "payment_id": order.payment_id,
+        "checkout_url": order.checkout_url,
+        "customer_email": order.customer_email,
+        "created_at": format_time(order.created_at),
+        "updated_at": format_time(order.updated_at),
+        "expires_at": order.expires_at.map(format_time)
+    })
+}
+
+/// Пустые поля HTML формы приходят пустыми строками, считаем их незаданными
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value
+        .as_deref()
+        .map(str::trim)
+        .filter(|val| !val.is_empty())
+}
+
+fn parse_field<T: std::str::FromStr>(name: &str, value: &Option<String>) -> Result<Option<T>, FondyError> {
+    non_empty(value)
+        .map(|val| val.parse::<T>())
+        .transpose()
+        .map_err(|_| FondyError::InvalidRequest(format!("invalid {}", name)))
+}
+
+fn parse_date(name: &str, value: &Option<String>) -> Result<Option<i64>, FondyError> {
+    non_empty(value)
+        .map(|val| NaiveDate::parse_from_str(val, "%Y-%m-%d"))
+        .transpose()
+        .map(|date| date.map(|val| val.and_hms(0, 0, 0).timestamp()))
+        .map_err(|_| FondyError::InvalidRequest(format!("invalid {}, expected YYYY-MM-DD", name)))
+}
+
+/// Страница админки, в шапке показывается текущий пользователь
+fn render(app: &Application, user: Option<&Principal>, name: &str, mut data: serde_json::Value) -> Result<warp::reply::Html<String>, Rejection> {
+    if let (Some(user), Some(fields)) = (user, data.as_object_mut()) {
+        fields.insert("user".to_owned(), json!({
+            "actor": user.actor,
+            "role": user.role.as_str()
+        }));
+    }
+    let html = app
+        .templates
+        .render(name, &data)
+        .map_err(FondyError::from)
+        .tap_err(|err| { error!("Admin template {} rendering failed: {}", name, err); })?;
+    Ok(warp::reply::html(html))
+}
+
+/// После действия возвращаемся на страницу, чтобы обновление не повторяло запрос
+fn redirect_to(path: &str) -> Result<impl Reply, Rejection> {
+    use std::str::FromStr;
+    let uri = warp::http::Uri::from_str(path)
+        .map_err(FondyError::from)?;
+    Ok(warp::redirect::see_other(uri))
+}
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+/// Выручка и доля одобренных платежей среди завершенных за сегодня, день в UTC
+#[instrument(skip(app))]
+async fn dashboard(app: Arc<Application>, principal: Principal) -> Result<impl Reply, Rejection>{
+    let today = Utc::now().date().naive_utc();
+    let from = today.and_hms(0, 0, 0).timestamp();
+    let to = from + 86400;
+    let totals = app
+        .db
+        .order_status_totals(from, to)
+        .await
+        .tap_err(|err| { error!("Order totals read failed: {}", err); })?;
+
+    let mut revenue: BTreeMap<&str, i64> = BTreeMap::new();
+    let mut approved = 0;
+    let mut finished = 0;
+    for total in totals.iter() {
+        match total.order_status.parse::<OrderStatus>().ok() {
+            Some(OrderStatus::Approved) => {
+                *revenue.entry(total.currency.as_str()).or_default() += total.amount;
+                approved += total.orders_count;
+                finished += total.orders_count;
+            },
+            // Возвращенный платеж тоже был одобрен
+            Some(OrderStatus::Reversed) => {
+                approved += total.orders_count;
+                finished += total.orders_count;
+            },
+            Some(OrderStatus::Declined) | Some(OrderStatus::Expired) => {
+                finished += total.orders_count;
+            },
+            _ => {}
+        }
+    }
+    let approval_rate = if finished > 0 {
+        format!("{:.1}%", approved as f64 * 100.0 / finished as f64)
+    }else{
+        "-".to_owned()
+    };
+
+    let revenue: Vec<serde_json::Value> = revenue
+        .into_iter()
+        .map(|(currency, amount)| json!({
+            "currency": currency,
+            "amount": format_amount(amount)
+        }))
+        .collect();
+    let totals: Vec<serde_json::Value> = totals
+        .iter()
+        .map(|total| json!({
+            "order_status": total.order_status,
+            "currency": total.currency,
+            "orders_count": total.orders_count,
+            "amount": format_amount(total.amount)
+        }))
+        .collect();
+
+    render(&app, Some(&principal), "admin/dashboard", json!({
+        "date": today.to_string(),
+        "revenue": revenue,
+        "approval_rate": approval_rate,
+        "totals": totals
+    }))
+}
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+/// Параметры фильтра из строки запроса, даты в UTC, суммы в минимальных единицах
+#[derive(Debug, Deserialize)]
+struct OrdersQuery{
+    status: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    product_id: Option<String>,
+    email: Option<String>,
+    amount_min: Option<String>,
+    amount_max: Option<String>,
+    page: Option<String>
+}
+
+impl OrdersQuery {
+    fn filter(&self) -> Result<OrderFilter, FondyError> {
+        let order_status = match non_empty(&self.status) {
+            Some(status) => {
+                let status: OrderStatus = status
+                    .parse()
+                    .map_err(FondyError::InvalidRequest)?;
+                Some(status.as_str().to_owned())
+            },
+            None => None
+        };
+        Ok(OrderFilter{
+            order_status,
+            created_from: parse_date("from", &self.from)?,
+            // Дата окончания включительно
+            created_to: parse_date("to", &self.to)?.map(|val| val + 86400),
+            product_id: parse_field("product_id", &self.product_id)?,
+            customer_email: non_empty(&self.email).map(|val| val.to_owned()),
+            amount_min: parse_field("amount_min", &self.amount_min)?,
+            amount_max: parse_field("amount_max", &self.amount_max)?
+        })
+    }
+
+    /// Строка запроса с тем же фильтром для ссылок на другие страницы
+    fn query_for_page(&self, page: i64) -> String {
+        let fields = [
+            ("status", &self.status),
+            ("from", &self.from),
+            ("to", &self.to),
+            ("product_id", &self.product_id),
+            ("email", &self.email),
+            ("amount_min", &self.amount_min),
+            ("amount_max", &self.amount_max)
+        ];
+        let mut pairs: Vec<(&str, String)> = fields
+            .iter()
+            .filter_map(|(name, value)| non_empty(value).map(|val| (*name, val.to_owned())))
+            .collect();
+        pairs.push(("page", page.to_string()));
+        serde_urlencoded::to_string(pairs).unwrap_or_default()
+    }
+}
+
+#[instrument(skip(app))]
+async fn orders(app: Arc<Application>, principal: Principal, query: OrdersQuery) -> Result<impl Reply, Rejection>{
+    let filter = query.filter()?;
+    let total = app
+        .db
+        .count_orders(&filter)
+        .await
+        .tap_err(|err| { error!("Orders count failed: {}", err); })?;
+    let pages = ((total + ORDERS_PAGE_SIZE - 1) / ORDERS_PAGE_SIZE).max(1);
+    let page = parse_field::<i64>("page", &query.page)?
+        .unwrap_or(1)
+        .max(1)
+        .min(pages);
+
+    let orders = app
+        .db
+        .list_orders(&filter, ORDERS_PAGE_SIZE, (page - 1) * ORDERS_PAGE_SIZE)
+        .await
+        .tap_err(|err| { error!("Orders read failed: {}", err); })?;
+
+    let statuses: Vec<serde_json::Value> = OrderStatus::ALL
+        .iter()
+        .map(|status| json!({
+            "value": status.as_str(),
+            "selected": filter.order_status.as_deref() == Some(status.as_str())
+        }))
+        .collect();
+
+    render(&app, Some(&principal), "admin/orders", json!({
+        "orders": orders.iter().map(order_to_json).collect::<Vec<_>>(),
+        "statuses": statuses,
+        "query": {
+            "from": non_empty(&query.from),
+            "to": non_empty(&query.to),
+            "product_id": non_empty(&query.product_id),
+            "email": non_empty(&query.email),
+            "amount_min": non_empty(&query.amount_min),
+            "amount_max": non_empty(&query.amount_max)
+        },
+        "total": total,
+        "page": page,
+        "pages": pages,
+        "prev_query": if page > 1 { Some(query.query_for_page(page - 1)) } else { None },
+        "next_query": if page < pages { Some(query.query_for_page(page + 1)) } else { None }
+    }))
+}
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+/// Заказ с позициями, историей статусов и пришедшими по нему коллбеками
+#[instrument(skip(app))]
+async fn order(order_id: String, app: Arc<Application>, principal: Principal) -> Result<impl Reply, Rejection>{
+    let order = app
+        .db
+        .get_order(&order_id)
+        .await
+        .tap_err(|err| { error!("Order read failed: {}", err); })?
+        .ok_or_else(|| FondyError::UnknownOrder(order_id.clone()))?;
+    let items = app
+        .db
+        .list_order_items(&order_id)
+        .await?;
+    let events = app
+        .db
+        .list_order_events(&order_id)
+        .await?;
+    let callbacks = app
+        .db
+        .list_order_callback_logs(&order_id)
+        .await?;
+
+    let items: Vec<serde_json::Value> = items
+        .iter()
+        .map(|item| json!({
+            "product_id": item.product_id,
+            "quantity": item.quantity,
+            "unit_price": format_amount(item.unit_price)
+        }))
+        .collect();
+    let events: Vec<serde_json::Value> = events
+        .iter()
+        .map(|event| json!({
+            "created_at": format_time(event.created_at),
+            "event_source": event.event_source,
+            "from_status": event.from_status,
+            "to_status": event.to_status
+        }))
+        .collect();
+    let callbacks: Vec<serde_json::Value> = callbacks
+        .iter()
+        .map(|callback| json!({
+            "callback_id": callback.callback_id,
+            "received_at": format_time(callback.received_at),
+            "callback_kind": callback.callback_kind,
+            "source_ip": callback.source_ip,
+            "signature_valid": callback.signature_valid,
+            "processing_result": callback.processing_result,
+            "replayed_at": callback.replayed_at.map(format_time),
+            "headers": callback.headers,
+            "body": callback.body
+        }))
+        .collect();
+
+    // Кнопки действий показываем только тем, кому они доступны
+    let approved = order.order_status.parse::<OrderStatus>().ok() == Some(OrderStatus::Approved);
+    let can_refund = approved && principal.role.allows(Permission::RefundOrders);
+    render(&app, Some(&principal), "admin/order", json!({
+        "order": order_to_json(&order),
+        "items": items,
+        "events": events,
+        "callbacks": callbacks,
+        "can_refund": can_refund,
+        "can_capture": can_refund
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct RefundForm{
+    amount: Option<String>,
+    comment: Option<String>
+}
+
+/// Возврат через API Fondy, статус и проводки обновятся по коллбеку
+#[instrument(skip(app))]
+async fn order_refund(order_id: String, app: Arc<Application>, principal: Principal, form: RefundForm) -> Result<impl Reply, Rejection>{
+    let amount = parse_field::<u64>("amount", &form.amount)?;
+    let comment = non_empty(&form.comment).unwrap_or("Refund");
+
+    let status = app.fondy.order_status(&order_id).await?;
+    let amount = amount.unwrap_or_else(|| status.remaining_amount());
+    info!("Refund {} {} for order {}", amount, status.currency, order_id);
+    let audit_id = audit_requested(&app.db, &principal.actor, "refund", Some(&order_id), Some(&format!("{} {}: {}", amount, status.currency, comment)))
+        .await?;
+    let result = app.fondy
+        .reverse(&order_id, amount, &status.currency, comment)
+        .await;
+    audit_outcome(&app.db, audit_id, &result).await;
+    result?;
+
+    redirect_to(&format!("/admin/orders/{}", order_id))
+}
+
+#[derive(Debug, Deserialize)]
+struct CaptureForm{
+    amount: Option<String>
+}
+
+/// Списание заблокированных средств через API Fondy
+#[instrument(skip(app))]
+async fn order_capture(order_id: String, app: Arc<Application>, principal: Principal, form: CaptureForm) -> Result<impl Reply, Rejection>{
+    let amount = parse_field::<u64>("amount", &form.amount)?;
+
+    let status = app.fondy.order_status(&order_id).await?;
+    let amount = amount.unwrap_or_else(|| status.remaining_amount());
+    info!("Capture {} {} for order {}", amount, status.currency, order_id);
+    let audit_id = audit_requested(&app.db, &principal.actor, "capture", Some(&order_id), Some(&format!("{} {}", amount, status.currency)))
+        .await?;
+    let result = app.fondy
+        .capture(&order_id, amount, &status.currency)
+        .await;
+    audit_outcome(&app.db, audit_id, &result).await;
+    result?;
+
+    redirect_to(&format!("/admin/orders/{}", order_id))
+}
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+#[instrument(skip(app))]
+async fn products(app: Arc<Application>, principal: Principal) -> Result<impl Reply, Rejection>{
+    let products = app
+        .db
+        .list_products()
+        .await
+        .tap_err(|err| { error!("Products read failed: {}", err); })?;
+
+    let products: Vec<serde_json::Value> = products
+        .iter()
+        .map(|product| json!({
+            "product_id": product.product_id,
+            "product_name": product.product_name,
+            "price": product.price,
+            "checkout_lifetime_secs": product.checkout_lifetime_secs
+        }))
+        .collect();
+
+    render(&app, Some(&principal), "admin/products", json!({
+        "products": products
+    }))
+}
+
+/// Без идентификатора продукт добавляется, с идентификатором - обновляется
+#[derive(Debug, Deserialize)]
+struct ProductForm{
+    product_id: Option<String>,
+    product_name: String,
+    price: String,
+    checkout_lifetime_secs: Option<String>
+}
+
+#[instrument(skip(app))]
+async fn product_save(app: Arc<Application>, principal: Principal, form: ProductForm) -> Result<impl Reply, Rejection>{
+    let product_name = form.product_name.trim();
+    if product_name.is_empty() {
+        return Err(FondyError::InvalidRequest("product name is empty".to_owned()).into());
+    }
+    let price = parse_field::<i64>("price", &Some(form.price.clone()))?
+        .filter(|val| *val > 0)
+        .ok_or_else(|| FondyError::InvalidRequest("price must be greater than zero".to_owned()))?;
+    let checkout_lifetime_secs = parse_field::<i64>("checkout_lifetime_secs", &form.checkout_lifetime_secs)?;
+
+    let details = format!("{}, price {}, lifetime {:?}", product_name, price, checkout_lifetime_secs);
+    match parse_field::<i32>("product_id", &form.product_id)? {
+        Some(product_id) => {
+            let updated = app
+                .db
+                .update_product(&ProductRecord{
+                    product_id,
+                    product_name: product_name.to_owned(),
+                    price,
+                    checkout_lifetime_secs
+                })
+                .await?;
+            if !updated {
+                return Err(FondyError::UnknownProduct(product_id).into());
+            }
+            audit(&app.db, &principal.actor, "product_update", Some(&product_id.to_string()), Some(&details))
+                .await?;
+        },
+        None => {
+            app.db
+                .upsert_product(product_name, price, checkout_lifetime_secs)
+                .await?;
+            audit(&app.db, &principal.actor, "product_save", Some(product_name), Some(&details))
+                .await?;
+        }
+    }
+
+    redirect_to("/admin/products")
+}
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+#[instrument(skip(app))]
+async fn login_page(app: Arc<Application>) -> Result<impl Reply, Rejection>{
+    render(&app, None, "admin/login", json!({}))
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginForm{
+    username: String,
+    password: String
+}
+
+#[instrument(skip(app, form), fields(username = %form.username))]
+async fn login_submit(app: Arc<Application>, form: LoginForm) -> Result<warp::reply::Response, Rejection>{
+    let session_lifetime = app.config.auth.session_lifetime;
+    let token = login(&app.db, form.username.trim(), &form.password, session_lifetime)
+        .await
+        .tap_err(|err| { error!("Login failed: {}", err); })?;
+
+    match token {
+        Some(token) => {
+            info!("User logged in");
+            let cookie = session_cookie(&app, &token, session_lifetime.as_secs());
+            let reply = warp::reply::with_header(redirect_to("/admin")?, SET_COOKIE, cookie);
+            Ok(reply.into_response())
+        },
+        None => {
+            let html = render(&app, None, "admin/login", json!({
+                "username": form.username,
+                "error": "Invalid username or password"
+            }))?;
+            Ok(warp::reply::with_status(html, StatusCode::UNAUTHORIZED).into_response())
+        }
+    }
+}
+
+#[instrument(skip(app, session))]
+async fn logout_submit(app: Arc<Application>, session: Option<String>) -> Result<impl Reply, Rejection>{
+    if let Some(token) = session {
+        logout(&app.db, &token)
+            .await
+            .tap_err(|err| { error!("Logout failed: {}", err); })?;
+    }
+    let cookie = session_cookie(&app, "", 0);
+    Ok(warp::reply::with_header(redirect_to("/admin/login")?, SET_COOKIE, cookie))
+}
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+/// Маршруты админки под /admin
+pub(super) fn admin_routes(app: Arc<Application>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let with_app = warp::any().map({
+        let app = app.clone();
+        move || {
+            app.clone()
+        }
+    });
+
+    let login_page = warp::path!("admin" / "login")
+        .and(warp::get())
+        .and(with_app.clone())
+        .and_then(login_page);
+
+    let login_submit = warp::path!("admin" / "login")
+        .and(warp::post())
+        .and(with_app.clone())
+        .and(warp::body::form())
+        .and_then(login_submit);
+
+    let logout_submit = warp::path!("admin" / "logout")
+        .and(warp::post())
+        .and(with_app.clone())
+        .and(warp::cookie::optional(SESSION_COOKIE))
+        .and_then(logout_submit);
+
+    let dashboard = warp::path!("admin")
+        .and(warp::get())
+        .and(with_app.clone())
+        .and(authorized(app.clone(), Permission::View))
+        .and_then(dashboard);
+
+    let orders = warp::path!("admin" / "orders")
+        .and(warp::get())
+        .and(with_app.clone())
+        .and(authorized(app.clone(), Permission::View))
+        .and(warp::query())
+        .and_then(orders);
+
+    let order = warp::path!("admin" / "orders" / String)
+        .and(warp::get())
+        .and(with_app.clone())
+        .and(authorized(app.clone(), Permission::View))
+        .and_then(order);
+
+    let order_refund = warp::path!("admin" / "orders" / String / "refund")
+        .and(warp::post())
+        .and(with_app.clone())
+        .and(authorized(app.clone(), Permission::RefundOrders))
+        .and(warp::body::form())
+        .and_then(order_refund);
+
+    let order_capture = warp::path!("admin" / "orders" / String / "capture")
+        .and(warp::post())
+        .and(with_app.clone())
+        .and(authorized(app.clone(), Permission::RefundOrders))
+        .and(warp::body::form())
+        .and_then(order_capture);
+
+    let products = warp::path!("admin" / "products")
+        .and(warp::get())
+        .and(with_app.clone())
+        .and(authorized(app.clone(), Permission::View))
+        .and_then(products);
+
+    let product_save = warp::path!("admin" / "products")
+        .and(warp::post())
+        .and(with_app.clone())
+        .and(authorized(app.clone(), Permission::ManageProducts))
+        .and(warp::body::form())
+        .and_then(product_save);
+
+    login_page
+        .or(login_submit)
+        .or(logout_submit)
+        .or(dashboard)
+        .or(orders)
+        .or(order)
+        .or(order_refund)
+        .or(order_capture)
+        .or(products)
+        .or(product_save)
+}
@@ -0,0 +1,79 @@
+use std::{
+    sync::{
+        Arc
+    }
+};
+use tracing::{
+    error,
+    instrument
+};
+use tap::{
+    prelude::{
+        *
+    }
+};
+use warp::{
+    Filter,
+    Rejection
+};
+use crate::{
+    application::{
+        Application
+    },
+    auth::{
+        Permission,
+        Principal,
+        authenticate_api_key,
+        authenticate_session
+    },
+    error::{
+        FondyError
+    }
+};
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+/// Имя cookie с токеном сессии админки
+pub(super) const SESSION_COOKIE: &str = "session";
+
+/// Заголовок Set-Cookie для сессии, Secure только если сайт работает по https
+pub(super) fn session_cookie(app: &Application, token: &str, max_age: u64) -> String {
+    let secure = if app.config.site_url.scheme() == "https" { "; Secure" } else { "" };
+    format!("{}={}; HttpOnly; SameSite=Lax; Path=/; Max-Age={}{}", SESSION_COOKIE, token, max_age, secure)
+}
+
+/// Ключ API берется из Authorization: Bearer или X-Api-Key, иначе пользователь из cookie сессии
+#[instrument(skip(app, session, authorization, api_key))]
+async fn authenticate(app: Arc<Application>,
+                      permission: Permission,
+                      session: Option<String>,
+                      authorization: Option<String>,
+                      api_key: Option<String>) -> Result<Principal, Rejection> {
+    let bearer = authorization
+        .as_deref()
+        .and_then(|val| val.strip_prefix("Bearer "))
+        .map(str::trim)
+        .map(str::to_owned);
+
+    let principal = match (api_key.or(bearer), session) {
+        (Some(key), _) => authenticate_api_key(&app.db, &key).await,
+        (None, Some(token)) => authenticate_session(&app.db, &token).await,
+        (None, None) => Ok(None)
+    };
+    let principal = principal
+        .tap_err(|err| { error!("Authentication failed: {}", err); })?
+        .ok_or(FondyError::Unauthorized)?;
+
+    principal.require(permission)?;
+    Ok(principal)
+}
+
+/// Фильтр, пропускающий только запросы с нужными правами
+pub(super) fn authorized(app: Arc<Application>, permission: Permission) -> impl Filter<Extract = (Principal,), Error = Rejection> + Clone {
+    warp::cookie::optional::<String>(SESSION_COOKIE)
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and_then(move |session, authorization, api_key| {
+            authenticate(app.clone(), permission, session, authorization, api_key)
+        })
+}
@@ -0,0 +1,142 @@
+use std::{
+    net::{
+        IpAddr,
+        SocketAddr
+    },
+    sync::{
+        Arc
+    }
+};
+use ipnet::{
+    IpNet
+};
+use tap::{
+    prelude::{
+        *
+    }
+};
+use tracing::{
+    error,
+    warn
+};
+use warp::{
+    Filter,
+    Rejection
+};
+use super::{
+    listeners::{
+        remote_addr
+    }
+};
+use crate::{
+    application::{
+        Application
+    },
+    auth::{
+        audit
+    },
+    config::{
+        AppConfig
+    },
+    error::{
+        FondyError
+    },
+    metrics::{
+        callback_source_rejected
+    }
+};
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+/// Адрес клиента с учетом прокси.
+/// X-Forwarded-For читается справа налево, пока адреса принадлежат доверенным прокси.
+/// Через Unix сокет подключаются только локальные процессы, поэтому его тоже считаем доверенным прокси.
+fn resolve_client_ip(remote_addr: Option<SocketAddr>, forwarded_for: Option<&str>, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+    let peer = remote_addr.map(|addr| addr.ip());
+    if matches!(peer, Some(ip) if !is_trusted(&ip)) {
+        return peer;
+    }
+
+    let mut client = peer;
+    let hops = forwarded_for
+        .unwrap_or_default()
+        .rsplit(',')
+        .map(str::trim)
+        .filter(|val| !val.is_empty());
+    for hop in hops {
+        // Неразборчивый адрес мог подставить сам клиент, дальше цепочке не верим
+        let ip = match hop.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => return None
+        };
+        client = Some(ip);
+        if !is_trusted(&ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// Фильтр адреса клиента, для Unix сокета без X-Forwarded-For отсутствует
+pub(super) fn client_ip(config: Arc<AppConfig>) -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Clone {
+    remote_addr()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(move |remote_addr: Option<SocketAddr>, forwarded_for: Option<String>| {
+            resolve_client_ip(remote_addr, forwarded_for.as_deref(), &config.server.trusted_proxies)
+        })
+}
+
+/// Фильтр, пропускающий только клиентов из списка адресов и сетей, пустой список пропускает всех.
+/// Отказы считаются в метриках и пишутся в журнал действий.
+pub(super) fn allowed_sources(app: Arc<Application>, allowed: Arc<Vec<IpNet>>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    client_ip(app.config.clone())
+        .and(warp::path::full())
+        .and_then(move |ip: Option<IpAddr>, path: warp::path::FullPath| {
+            let app = app.clone();
+            let allowed = allowed.clone();
+            async move {
+                let is_allowed = allowed.is_empty() || matches!(ip, Some(ip) if allowed.iter().any(|net| net.contains(&ip)));
+                if is_allowed {
+                    return Ok(());
+                }
+
+                let source = ip.map_or_else(|| "unknown".to_owned(), |ip| ip.to_string());
+                warn!(%source, path = path.as_str(), "Request from not allowed source rejected");
+                callback_source_rejected();
+                audit(&app.db, &format!("ip:{}", source), "source_rejected", Some(path.as_str()), None)
+                    .await
+                    .tap_err(|err| { error!("Audit record save failed: {}", err); })
+                    .ok();
+                Err(warp::reject::custom(FondyError::ForbiddenSource(source)))
+            }
+        })
+        .untuple_one()
+}
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_resolve_client_ip(){
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let ip = |val: &str| Some(val.parse::<IpAddr>().unwrap());
+        let peer = |val: &str| Some(val.parse::<SocketAddr>().unwrap());
+
+        // Заголовок от клиента напрямую игнорируется
+        assert_eq!(resolve_client_ip(peer("1.1.1.1:5000"), Some("2.2.2.2"), &trusted), ip("1.1.1.1"));
+
+        // От прокси берем первый недоверенный адрес справа, подставленное клиентом левее не важно
+        assert_eq!(resolve_client_ip(peer("10.0.0.1:5000"), Some("6.6.6.6, 2.2.2.2, 10.0.0.2"), &trusted), ip("2.2.2.2"));
+        assert_eq!(resolve_client_ip(peer("10.0.0.1:5000"), None, &trusted), ip("10.0.0.1"));
+        assert_eq!(resolve_client_ip(peer("10.0.0.1:5000"), Some("garbage, 10.0.0.2"), &trusted), None);
+
+        // Unix сокет
+        assert_eq!(resolve_client_ip(None, Some("2.2.2.2"), &trusted), ip("2.2.2.2"));
+        assert_eq!(resolve_client_ip(None, None, &trusted), None);
+    }
+}
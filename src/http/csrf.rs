@@ -0,0 +1,64 @@
+use crate::{
+    application::{
+        Application
+    },
+    auth::{
+        generate_token,
+        token_hash
+    },
+    error::{
+        FondyError
+    }
+};
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+/// Имя cookie с CSRF токеном формы покупки
+pub(super) const CSRF_COOKIE: &str = "csrf";
+
+/// Время жизни CSRF cookie, продлевается при каждом открытии главной страницы
+const CSRF_COOKIE_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+/// Токен для формы: уже выданный браузеру, иначе новый.
+/// Один токен на браузер, чтобы формы в нескольких вкладках оставались рабочими.
+pub(super) fn csrf_token(cookie: Option<String>) -> String {
+    cookie
+        .filter(|token| token.len() == 64 && token.chars().all(|c| c.is_ascii_hexdigit()))
+        .unwrap_or_else(generate_token)
+}
+
+/// Заголовок Set-Cookie для CSRF токена.
+/// SameSite=Strict: на запрос формы с чужого сайта браузер cookie не пришлет.
+pub(super) fn csrf_cookie(app: &Application, token: &str) -> String {
+    let secure = if app.config.site_url.scheme() == "https" { "; Secure" } else { "" };
+    format!("{}={}; HttpOnly; SameSite=Strict; Path=/; Max-Age={}{}", CSRF_COOKIE, token, CSRF_COOKIE_MAX_AGE_SECS, secure)
+}
+
+/// Токен из формы должен совпасть с токеном из cookie.
+/// Чужой сайт не может ни прочитать cookie, ни подставить свою.
+pub(super) fn verify_csrf_token(cookie: Option<&str>, form: Option<&str>) -> Result<(), FondyError> {
+    match (cookie, form) {
+        // Сравниваем хеши, чтобы время сравнения не зависело от совпавшего префикса
+        (Some(cookie), Some(form)) if !cookie.is_empty() && token_hash(cookie) == token_hash(form) => Ok(()),
+        _ => Err(FondyError::InvalidCsrfToken)
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_csrf_token(){
+        let token = csrf_token(None);
+        assert_eq!(csrf_token(Some(token.clone())), token);
+        assert_ne!(csrf_token(Some("forged".to_owned())), "forged");
+
+        assert!(verify_csrf_token(Some(&token), Some(&token)).is_ok());
+        assert!(verify_csrf_token(Some(&token), Some("other")).is_err());
+        assert!(verify_csrf_token(None, Some(&token)).is_err());
+        assert!(verify_csrf_token(Some(""), Some("")).is_err());
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    convert::{
+        Infallible
+    },
+    sync::{
+        Arc
+    }
+};
+use serde_json::{
+    json
+};
+use tracing::{
+    error
+};
+use warp::{
+    Filter,
+    Reply,
+    Rejection,
+    http::{
+        HeaderMap,
+        HeaderValue,
+        StatusCode,
+        header::{
+            ACCEPT,
+            RETRY_AFTER
+        }
+    },
+    path::{
+        FullPath
+    },
+    reject::{
+        InvalidHeader,
+        InvalidQuery,
+        LengthRequired,
+        MethodNotAllowed,
+        MissingCookie,
+        MissingHeader,
+        PayloadTooLarge,
+        Reject,
+        UnsupportedMediaType
+    },
+    filters::{
+        body::{
+            BodyDeserializeError
+        }
+    },
+    reply::{
+        Response
+    }
+};
+use crate::{
+    application::{
+        Application
+    },
+    error::{
+        FondyError
+    }
+};
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+impl Reject for FondyError {
+}
+
+/// Ошибка запроса в виде, одинаковом для JSON и HTML
+#[derive(Debug)]
+struct ErrorDetails{
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    /// Через сколько секунд имеет смысл повторить, для заголовка Retry-After
+    retry_after: Option<u64>
+}
+
+impl ErrorDetails {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> ErrorDetails {
+        ErrorDetails{
+            status,
+            code,
+            message: message.into(),
+            retry_after: None
+        }
+    }
+}
+
+fn error_details(rejection: &Rejection) -> ErrorDetails {
+    if let Some(err) = rejection.find::<FondyError>(){
+        let mut details = ErrorDetails::new(err.status(), err.code(), err.public_message());
+        details.retry_after = err
+            .retry_after()
+            .map(|retry_after| retry_after.as_secs().max(1));
+        details
+    }else if rejection.is_not_found(){
+        ErrorDetails::new(StatusCode::NOT_FOUND, "not_found", "Not found")
+    }else if let Some(err) = rejection.find::<BodyDeserializeError>(){
+        ErrorDetails::new(StatusCode::BAD_REQUEST, "invalid_request", err.to_string())
+    }else if let Some(err) = rejection.find::<InvalidQuery>(){
+        ErrorDetails::new(StatusCode::BAD_REQUEST, "invalid_request", err.to_string())
+    }else if let Some(err) = rejection.find::<MissingHeader>(){
+        ErrorDetails::new(StatusCode::BAD_REQUEST, "invalid_request", err.to_string())
+    }else if let Some(err) = rejection.find::<InvalidHeader>(){
+        ErrorDetails::new(StatusCode::BAD_REQUEST, "invalid_request", err.to_string())
+    }else if let Some(err) = rejection.find::<MissingCookie>(){
+        ErrorDetails::new(StatusCode::BAD_REQUEST, "invalid_request", err.to_string())
+    }else if rejection.find::<MethodNotAllowed>().is_some(){
+        ErrorDetails::new(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", "Method not allowed")
+    }else if rejection.find::<LengthRequired>().is_some(){
+        ErrorDetails::new(StatusCode::LENGTH_REQUIRED, "length_required", "Content-Length required")
+    }else if rejection.find::<PayloadTooLarge>().is_some(){
+        ErrorDetails::new(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", "Payload too large")
+    }else if rejection.find::<UnsupportedMediaType>().is_some(){
+        ErrorDetails::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", "Unsupported content type")
+    }else{
+        error!(?rejection, "Unhandled rejection");
+        ErrorDetails::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "Internal server error")
+    }
+}
+
+/// Браузер явно просит HTML, клиенты API и все остальные получают JSON
+fn wants_html(headers: &HeaderMap) -> bool {
+    let accept = headers
+        .get(ACCEPT)
+        .and_then(|val| val.to_str().ok())
+        .unwrap_or_default();
+    let accepts = |media: &str| {
+        accept
+            .split(',')
+            .any(|val| val.trim().starts_with(media))
+    };
+    accepts("text/html") && !accepts("application/json")
+}
+
+fn with_retry_after(mut response: Response, details: &ErrorDetails) -> Response {
+    if let Some(retry_after) = details.retry_after {
+        response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    }
+    response
+}
+
+fn json_response(details: &ErrorDetails) -> Response {
+    let reply = warp::reply::json(&json!({
+        "code": details.code,
+        "status": details.status.as_u16(),
+        "message": details.message
+    }));
+    with_retry_after(warp::reply::with_status(reply, details.status).into_response(), details)
+}
+
+fn html_response(app: &Application, path: &str, details: &ErrorDetails) -> Response {
+    // Без входа в админку отправляем на страницу логина
+    let is_admin = path == "/admin" || path.starts_with("/admin/");
+    if is_admin && details.status == StatusCode::UNAUTHORIZED {
+        return warp::redirect::see_other(warp::http::Uri::from_static("/admin/login")).into_response();
+    }
+
+    // Пока Fondy недоступен, покупатель видит страницу технических работ
+    let template = if is_admin {
+        "admin/error"
+    }else if details.status == StatusCode::SERVICE_UNAVAILABLE {
+        "maintenance"
+    }else{
+        "error"
+    };
+    let rendered = app
+        .templates
+        .render(template, &json!({
+            "status": details.status.as_u16(),
+            "code": details.code,
+            "message": details.message,
+            "retry_after": details.retry_after
+        }));
+    match rendered {
+        Ok(html) => with_retry_after(warp::reply::with_status(warp::reply::html(html), details.status).into_response(), details),
+        Err(err) => {
+            error!("Error template {} rendering failed: {}", template, err);
+            json_response(details)
+        }
+    }
+}
+
+/// Единая обработка ошибок всех маршрутов.
+/// Отказ любого маршрута превращается в ответ с кодом ошибки, JSON или HTML страницей по заголовку Accept.
+pub(super) fn with_error_responses<F, R>(app: Arc<Application>, routes: F) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply
+{
+    let routes = routes
+        .map(|reply: R| Ok::<Response, Rejection>(reply.into_response()))
+        .or_else(|rejection| async move {
+            Ok::<_, Infallible>((Err(rejection),))
+        });
+
+    warp::header::headers_cloned()
+        .and(warp::path::full())
+        .and(routes)
+        .map(move |headers: HeaderMap, path: FullPath, result: Result<Response, Rejection>| {
+            match result {
+                Ok(response) => response,
+                Err(rejection) => {
+                    let details = error_details(&rejection);
+                    if wants_html(&headers) {
+                        html_response(&app, path.as_str(), &details)
+                    }else{
+                        json_response(&details)
+                    }
+                }
+            }
+        })
+}
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_wants_html(){
+        let headers = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT, accept.parse().unwrap());
+            headers
+        };
+        assert!(wants_html(&headers("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8")));
+        assert!(!wants_html(&headers("application/json")));
+        assert!(!wants_html(&headers("*/*")));
+        assert!(!wants_html(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_error_details(){
+        let details = error_details(&warp::reject::custom(FondyError::UnknownProduct(3)));
+        assert_eq!(details.status, StatusCode::NOT_FOUND);
+        assert_eq!(details.code, "unknown_product");
+
+        let details = error_details(&warp::reject::custom(FondyError::InvalidCallbackSignature));
+        assert_eq!(details.status, StatusCode::UNAUTHORIZED);
+
+        // Подробности внутренних ошибок клиенту не показываем
+        let details = error_details(&warp::reject::custom(FondyError::Custom("db password is wrong".to_owned())));
+        assert_eq!(details.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(details.message, "Internal server error");
+
+        let details = error_details(&warp::reject::not_found());
+        assert_eq!(details.code, "not_found");
+    }
+}
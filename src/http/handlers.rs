@@ -1,22 +1,59 @@
-use std::{str::FromStr, sync::{
+use std::{
+    convert::{
+        Infallible
+    },
+    sync::{
         Arc
-    }};
+    },
+    time::{
+        Duration
+    }
+};
+use futures::{
+    stream::{
+        self,
+        Stream
+    }
+};
+use tokio::{
+    sync::{
+        broadcast::{
+            self,
+            error::{
+                RecvError
+            }
+        }
+    }
+};
 use tracing::{
     debug, 
     error, 
-    instrument
+    info,
+    instrument,
+    warn
 };
 use warp::{
     Filter,
     Reply,
     Rejection,
-    reject::{
-        Reject
+    http::{
+        Method,
+        StatusCode,
+        header::{
+            CACHE_CONTROL,
+            SET_COOKIE
+        }
+    },
+    sse::{
+        Event
     }
 };
 use serde::{
     Deserialize
 };
+use handlebars::{
+    Handlebars
+};
 use serde_json::{
     json
 };
@@ -25,252 +62,490 @@ use tap::{
         *
     }
 };
-use reqwest_inspect_json::{
-    InspectJson
+use super::{
+    required_templates,
+    admin::{
+        admin_routes
+    },
+    auth::{
+        authorized
+    },
+    csrf::{
+        CSRF_COOKIE,
+        csrf_cookie,
+        csrf_token,
+        verify_csrf_token
+    },
+    errors::{
+        with_error_responses
+    },
+    client_ip::{
+        allowed_sources,
+        client_ip
+    },
+    listeners::{
+        Listeners,
+        serve_listeners
+    },
+    rate_limit::{
+        RateLimiter,
+        rate_limited
+    }
 };
 use crate::{
     error::{
         FondyError
     },
     application::{
-        Application,
+        Application
+    },
+    auth::{
+        Permission,
+        Principal,
+        token_hash
+    },
+    config::{
         AppConfig
-    }
-};
-use super::{
-    messages::{
-        FondyDataOrErrorResponse,
-        FondyInvalidResponse,
-        FondyRedirectUrlResponse,
-        FondyPaymentResponse
     },
-    signature::{
-        calculate_signature
+    fondy::{
+        FondyClient
+    },
+    database::{
+        Database
+    },
+    orders::{
+        PENDING_STATUSES
+    },
+    checkout::{
+        CheckoutMode,
+        CheckoutPayment,
+        CheckoutRequest,
+        create_checkout,
+        resolve_items,
+        validate_email,
+        validate_idempotency_key
+    },
+    callbacks::{
+        RawCallback,
+        handle_server_callback,
+        handle_browser_callback
+    },
+    health::{
+        readiness
+    },
+    shutdown::{
+        ShutdownListener
+    },
+    metrics::{
+        http_request_finished,
+        render_metrics,
+        route_label
     }
 };
 
 //////////////////////////////////////////////////////////////////////////////////////////
 
-impl Reject for FondyError {
-}
+/// Ограничение размера тела запроса к JSON API
+const API_BODY_LIMIT: u64 = 16 * 1024;
+
+/// Ограничение размера тела коллбека, настоящие коллбеки Fondy в несколько раз меньше
+const CALLBACK_BODY_LIMIT: u64 = 64 * 1024;
+
+/// Как часто поток статуса перечитывает заказ из базы.
+/// Нужно для изменений без оповещения, например из фоновых задач или другого процесса.
+const STATUS_RECHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Заголовок с ключом идемпотентности создания заказа
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
 
 //////////////////////////////////////////////////////////////////////////////////////////
 
-#[instrument(skip(app))]
-async fn index(app: Arc<Application>) -> Result<impl Reply, Rejection>{
+#[instrument(skip(app, csrf_cookie_token))]
+async fn index(app: Arc<Application>, csrf_cookie_token: Option<String>) -> Result<impl Reply, Rejection>{
+    let csrf_token = csrf_token(csrf_cookie_token);
+
+    // Свой ключ идемпотентности на каждую форму, повторная отправка формы не создаст второй заказ
     let html = app
         .templates
-        .render("index", &json!({}))
+        .render("index", &json!({
+            "csrf_token": csrf_token,
+            "idempotency_key": uuid::Uuid::new_v4().to_string(),
+            "embedded_idempotency_key": uuid::Uuid::new_v4().to_string()
+        }))
         .map_err(FondyError::from)
         .tap_err(|err| { error!("Index template rendering failed: {}", err); })?;
 
-    Ok(warp::reply::html(html))
+    // В странице токен конкретного браузера, кешировать ее нельзя
+    let reply = warp::reply::html(html);
+    let reply = warp::reply::with_header(reply, SET_COOKIE, csrf_cookie(&app, &csrf_token));
+    Ok(warp::reply::with_header(reply, CACHE_CONTROL, "no-store"))
 }
 
 //////////////////////////////////////////////////////////////////////////////////////////
 
 #[derive(Debug, Deserialize)]
 struct BuyItemParams{
-    item_id: i32
+    item_id: i32,
+
+    /// Страница оплаты Fondy или виджет на нашей странице
+    #[serde(default)]
+    mode: CheckoutMode,
+
+    /// Ключ идемпотентности из формы, иначе берется из заголовка
+    idempotency_key: Option<String>,
+
+    /// Токен из формы главной страницы
+    csrf_token: Option<String>
 }
 
-// Передаем сюда лишь конфиг и клиента, а не все приложение для возможности тестирования
-#[instrument(skip(http_client, config))]
-async fn buy(http_client: reqwest::Client, config: Arc<AppConfig>, buy_params: BuyItemParams) -> Result<impl Reply, Rejection>{
-    debug!("Buy params: {:#?}", buy_params);
+/// Параметры покупки из формы или, если разрешены GET запросы, из строки запроса.
+/// Для POST токен формы проверяется по CSRF cookie.
+fn buy_params() -> impl Filter<Extract = (BuyItemParams,), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::cookie::optional::<String>(CSRF_COOKIE))
+        .and(warp::filters::body::form()
+                .or(warp::query())
+                .unify())
+        .and_then(|method: Method, csrf_cookie_token: Option<String>, params: BuyItemParams| async move {
+            if method == Method::POST {
+                verify_csrf_token(csrf_cookie_token.as_deref(), params.csrf_token.as_deref())
+                    .tap_err(|_| { warn!("Buy request without valid CSRF token"); })?;
+            }
+            Ok::<_, Rejection>(params)
+        })
+}
 
-    let order_id = uuid::Uuid::new_v4().to_string();
+/// Ключ идемпотентности с областью клиента, чтобы ключи разных клиентов не пересекались.
+/// Область задает вызывающий: браузер по CSRF cookie, API по ключу или пользователю.
+fn scoped_idempotency_key(scope: &str, key: Option<String>) -> Result<Option<String>, FondyError> {
+    match key {
+        Some(key) => {
+            validate_idempotency_key(&key)?;
+            Ok(Some(format!("{}:{}", scope, key)))
+        },
+        None => Ok(None)
+    }
+}
 
-    // TODO: Сохраняем в базу, что данному order_id соответствует данный покупаемый итем
+// Передаем сюда лишь конфиг и клиента, а не все приложение для возможности тестирования
+#[instrument(skip(fondy, db, config, templates, csrf_cookie_token))]
+async fn buy(fondy: FondyClient, db: Arc<Database>, config: Arc<AppConfig>, templates: Arc<Handlebars<'static>>, header_key: Option<String>, csrf_cookie_token: Option<String>, buy_params: BuyItemParams) -> Result<impl Reply, Rejection>{
+    debug!("Buy params: {:#?}", buy_params);
 
-    // TODO: ? 
-    // Стоимость в центах, то есть умноженная на 10?
-    // Либо в копейках умноженная на 100?
-    let price: i32 = 10*100;
+    // Ключ действует только в пределах браузера с той же CSRF cookie,
+    // без cookie клиента не отличить, поэтому повтор не распознаем
+    let idempotency_key = match csrf_cookie_token.as_deref().filter(|token| !token.is_empty()) {
+        Some(token) => scoped_idempotency_key(&format!("buy:{}", token_hash(token)), buy_params.idempotency_key.clone().or(header_key))?,
+        None => None
+    };
 
-    // Стоимость в центах, то есть умноженная на 10
-    let currency = "USD"; // TODO: Пhоблема с другими валютами и маленькими суммами
+    // Цена и описание берутся из каталога продуктов
+    let (items, description, lifetime) = resolve_items(&db, &config, &[(buy_params.item_id, 1)])
+        .await
+        .tap_err(|err| { error!("Order items resolve failed: {}", err); })?;
+
+    let checkout = create_checkout(&fondy, &db, &config, CheckoutRequest{
+            mode: buy_params.mode,
+            items,
+            description,
+            customer_email: None,
+            lifetime,
+            idempotency_key: idempotency_key.as_deref()
+        })
+        .await?;
+
+    match checkout.payment {
+        CheckoutPayment::Redirect{ checkout_url, .. } => {
+            // Возвращаем код 307 + POST параметры
+            use std::str::FromStr;
+            let uri = warp::http::Uri::from_str(checkout_url.as_str())
+                .map_err(FondyError::from)
+                .tap_err(|err| { error!("Invaid receive URI: {:#?}", err); })?;
+
+            Ok(warp::redirect::see_other(uri).into_response())
+        },
+        CheckoutPayment::Embedded{ token } => {
+            // Виджет Fondy на нашей странице, рядом показываем статус заказа
+            let html = templates
+                .render("checkout", &json!({
+                    "order_id": checkout.order_id,
+                    "token": token,
+                    "amount": format!("{}.{:02}", checkout.amount / 100, checkout.amount % 100),
+                    "currency": checkout.currency
+                }))
+                .map_err(FondyError::from)
+                .tap_err(|err| { error!("Checkout template rendering failed: {}", err); })?;
+
+            Ok(warp::reply::html(html).into_response())
+        }
+    }
+}
 
-    // Адрес, куда будет редиректиться браузер
-    let browser_redirect_url = config
-        .site_url
-        .join("browser_redirect_callback_url")
-        .map_err(FondyError::from)
-        .tap_err(|err| { error!("Url join error: {}", err); })?;
-    debug!("Browser callback url: {}", browser_redirect_url);
+//////////////////////////////////////////////////////////////////////////////////////////
 
-    // Коллбека на нашем сервере
-    let server_callback_url = config
-        .site_url
-        .join("purchase_server_callback_url")
-        .map_err(FondyError::from)
-        .tap_err(|err| { error!("Url join error: {}", err); })?;
-    debug!("Server callback url: {}", server_callback_url);
-
-    // Данные, которые будут в коллбеке
-    let callback_data = "our_custom_payload";
-
-    // Идентификатор нашего продукта
-    let product_id = format!("{}", buy_params.item_id);
-
-    // Все параметры, но без подписи
-    // TODO: В структуру сериализации
-    let mut parameters = json!({
-        "order_id": order_id,
-        "merchant_id": config.merchant_id, 
-        "order_desc": "My product description",
-        "amount": price,
-        "currency": currency,
-        "version": "1.0.1",
-        "merchant_data": callback_data,
-        "server_callback_url": server_callback_url.as_str(),
-        // "response_url": browser_redirect_url.as_str(),
-        "product_id": product_id
-        // "payment_systems": "card, banklinks_eu, banklinks_pl",
-        // "default_payment_system": "card",
-        // "lifetime": 36000,
-        // "preauth": "N" // Тип снятия денег
-        // "sender_email": "test@gmail.com"
-        // "delayed": "Y"
-        // "lang": "ru"
-        // "required_rectoken": "N"         // Получение токена для будущих автоматических оплат
-        // "rectoken": "AAAA"               // Токен, по которому можно будет автоматически списывать деньги потом
-        // "receiver_rectoken": "AAAA"      // Токен карты, по которому можно кредитовать карту, не передавая полный номер карты
-        // "verification": "N"
-        // "verification_type": "amount"
-        // "design_id"                      // Кастомный дизайн
-        // "subscription"                   // Подписка на периодические платежи
-        // "subscription_callback_url"      // URL коллбека, куда будет перенаправлен покупатель при периодической покупке
-    });
+#[derive(Debug, Deserialize)]
+struct ApiOrderItem{
+    product_id: i32,
+    quantity: i64
+}
 
-    // Вычисляем подпись и добавляем к параметрам
-    let signature = calculate_signature(&config.merchant_password, &parameters, &[])
-        .tap_err(|err| { error!("Signature calculate error: {}", err); })?;
-    parameters["signature"] = serde_json::Value::String(signature);
+#[derive(Debug, Deserialize)]
+struct ApiCreateOrder{
+    items: Vec<ApiOrderItem>,
+    customer_email: Option<String>,
+    #[serde(default)]
+    mode: CheckoutMode
+}
 
-    debug!("Fondy request params: {:#?}", &parameters);
+/// Создание заказа для SPA и мобильного приложения, вместо редиректа отдаем ссылку на оплату.
+/// Повтор с тем же заголовком `Idempotency-Key` получает тот же заказ.
+#[instrument(skip(fondy, db, config), fields(actor = %principal.actor))]
+async fn api_create_order(fondy: FondyClient, db: Arc<Database>, config: Arc<AppConfig>, principal: Principal, idempotency_key: Option<String>, params: ApiCreateOrder) -> Result<impl Reply, Rejection>{
+    let idempotency_key = scoped_idempotency_key(&format!("api:{}", principal.actor), idempotency_key)?;
+
+    let customer_email = params
+        .customer_email
+        .as_deref()
+        .map(str::trim)
+        .filter(|val| !val.is_empty());
+    if let Some(email) = customer_email {
+        validate_email(email)?;
+    }
 
-    // Параметры: https://docs.fondy.eu/ru/docs/page/3/
-    let response = http_client
-        .post("https://pay.fondy.eu/api/checkout/url")
-        .json(&json!({
-            "request": parameters
-        }))
-        .send()
+    let requested: Vec<(i32, i64)> = params
+        .items
+        .iter()
+        .map(|item| (item.product_id, item.quantity))
+        .collect();
+    let (items, description, lifetime) = resolve_items(&db, &config, &requested)
         .await
-        .map_err(FondyError::from)
-        .tap_err(|err|{ error!("Fondy request send failed: {}", err); })?
-        .inspect_json::<FondyDataOrErrorResponse<FondyRedirectUrlResponse, FondyInvalidResponse>,
-                        FondyError>(|data|{
-            debug!("Fondy received data: {}", data)
+        .tap_err(|err| { error!("Order items resolve failed: {}", err); })?;
+
+    let checkout = create_checkout(&fondy, &db, &config, CheckoutRequest{
+            mode: params.mode,
+            items,
+            description,
+            customer_email,
+            lifetime,
+            idempotency_key: idempotency_key.as_deref()
+        })
+        .await?;
+
+    // Для встроенного виджета вместо ссылки отдаем токен
+    let reply = match checkout.payment {
+        CheckoutPayment::Redirect{ payment_id, checkout_url } => json!({
+            "order_id": checkout.order_id,
+            "payment_id": payment_id,
+            "checkout_url": checkout_url,
+            "expires_at": checkout.expires_at
+        }),
+        CheckoutPayment::Embedded{ token } => json!({
+            "order_id": checkout.order_id,
+            "token": token,
+            "expires_at": checkout.expires_at
         })
+    };
+    let reply = warp::reply::json(&reply);
+    Ok(warp::reply::with_status(reply, StatusCode::CREATED))
+}
+
+/// Состояние заказа для опроса клиентом
+#[instrument(skip(db), fields(actor = %principal.actor))]
+async fn api_get_order(order_id: String, db: Arc<Database>, principal: Principal) -> Result<impl Reply, Rejection>{
+    let order = db
+        .get_order(&order_id)
         .await
-        .tap_err(|err| { error!("Fondy response parsing failed: {}", err); })?
-        .into_result()
-        .map_err(FondyError::from)
-        .tap_err(|err| { error!("Fondy fail response: {:#?}", err); })?;
+        .tap_err(|err| { error!("Order read failed: {}", err); })?
+        .ok_or_else(|| FondyError::UnknownOrder(order_id.clone()))?;
+    let items = db
+        .list_order_items(&order_id)
+        .await
+        .tap_err(|err| { error!("Order items read failed: {}", err); })?;
+
+    let items: Vec<serde_json::Value> = items
+        .into_iter()
+        .map(|item| json!({
+            "product_id": item.product_id,
+            "quantity": item.quantity,
+            "unit_price": item.unit_price
+        }))
+        .collect();
+
+    Ok(warp::reply::json(&json!({
+        "order_id": order.order_id,
+        "order_status": order.order_status,
+        "amount": order.amount,
+        "currency": order.currency,
+        "payment_id": order.payment_id,
+        "checkout_url": order.checkout_url,
+        "customer_email": order.customer_email,
+        "items": items,
+        "created_at": order.created_at,
+        "updated_at": order.updated_at,
+        "expires_at": order.expires_at
+    })))
+}
+
+//////////////////////////////////////////////////////////////////////////////////////////
 
-    debug!("Received reponse: {:#?}", response);
+/// Состояние потока статуса одного заказа
+struct StatusStream{
+    db: Arc<Database>,
+    order_id: String,
+    receiver: broadcast::Receiver<String>,
+    shutdown: ShutdownListener,
+    last_status: Option<String>,
+    finished: bool
+}
 
-    // Возвращаем код 307 + POST параметры
-    use std::str::FromStr;
-    let uri = warp::http::Uri::from_str(response.checkout_url.as_str())
-        .map_err(FondyError::from)
-        .tap_err(|err| { error!("Invaid receive URI: {:#?}", err); })?;
+/// Событие со статусом заказа, после финального статуса поток завершается
+fn status_stream(state: StatusStream) -> impl Stream<Item = Result<Event, Infallible>> {
+    stream::unfold(state, |mut state| async move {
+        if state.finished {
+            return None;
+        }
+        loop {
+            // Первый раз отдаем текущий статус сразу, дальше ждем оповещения
+            if state.last_status.is_some() {
+                tokio::select!{
+                    res = state.receiver.recv() => {
+                        match res {
+                            Ok(order_id) if order_id != state.order_id => continue,
+                            Ok(_) | Err(RecvError::Lagged(_)) => {},
+                            Err(RecvError::Closed) => return None
+                        }
+                    },
+                    _ = tokio::time::sleep(STATUS_RECHECK_INTERVAL) => {},
+                    // Иначе открытые потоки не дадут серверу остановиться
+                    _ = state.shutdown.wait() => return None
+                }
+            }
+
+            // При ошибке закрываем поток, браузер сам переподключится
+            let order = match state.db.get_order(&state.order_id).await {
+                Ok(Some(order)) => order,
+                Ok(None) => return None,
+                Err(err) => {
+                    error!("Order status read failed: {}", err);
+                    return None;
+                }
+            };
+            if state.last_status.as_deref() == Some(order.order_status.as_str()) {
+                continue;
+            }
 
-    Ok(warp::redirect::see_other(uri))
+            state.finished = !PENDING_STATUSES
+                .iter()
+                .any(|status| status.as_str() == order.order_status);
+            let event = Event::default()
+                .event("status")
+                .data(json!({
+                    "order_id": order.order_id,
+                    "order_status": order.order_status
+                }).to_string());
+            state.last_status = Some(order.order_status);
+            return Some((Ok(event), state));
+        }
+    })
 }
 
-//////////////////////////////////////////////////////////////////////////////////////////
+/// Поток изменений статуса заказа для страницы ожидания оплаты
+#[instrument(skip(app))]
+async fn api_order_events(order_id: String, app: Arc<Application>) -> Result<impl Reply, Rejection>{
+    // Подписываемся до чтения заказа, чтобы не пропустить изменение между ними
+    let receiver = app.status_events.subscribe();
+    app.db
+        .get_order(&order_id)
+        .await
+        .tap_err(|err| { error!("Order read failed: {}", err); })?
+        .ok_or_else(|| FondyError::UnknownOrder(order_id.clone()))?;
+
+    let stream = status_stream(StatusStream{
+        db: app.db.clone(),
+        order_id,
+        receiver,
+        shutdown: app.shutdown.listener(),
+        last_status: None,
+        finished: false
+    });
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
+}
 
-#[instrument(skip(bytes, config), fields(order_id, order_status))]
-async fn purchase_server_callback(config: Arc<AppConfig>, bytes: bytes::Bytes) -> Result<impl Reply, Rejection>{
-    let text = std::str::from_utf8(bytes.as_ref())
-        .map_err(FondyError::from)
-        .tap_err(|err|{ error!("Data scream conver to bytes failed: {}", err); })?;
-    let data = serde_json::Value::from_str(text)
-        .map_err(FondyError::from)
-        .tap_err(|err|{ error!("Data stream parse failed: {}", err); })?;
+//////////////////////////////////////////////////////////////////////////////////////////
 
-    // Текущая полученная подпись
-    let received_signature = data
-        .as_object()
-        .ok_or_else(||{ 
-            FondyError::Custom("Received json must be dictionary".to_string())
-        })
-        .tap_err(|err|{ error!("{}", err); })?
-        .get("signature")
-        .ok_or_else(||{ 
-            FondyError::Custom("Signature field is missing".to_string())
-        })
-        .tap_err(|err|{ error!("{}", err); })?
-        .as_str()
-        .ok_or_else(||{ 
-            FondyError::Custom("Signature must be string".to_string())
-        })
-        .tap_err(|err|{ error!("{}", err); })?;
-
-    // Вычисляем подпись, пропуская поля для сигнатуры
-    let calculated_signature = calculate_signature(&config.merchant_password, &data, &["signature", "response_signature_string"])?;
-
-    // Парсим в структуру
-    let data = if received_signature.eq(calculated_signature.as_str()) {
-        let result = serde_json::from_value::<FondyPaymentResponse>(data)
-            .map_err(FondyError::from)?;
-        result
-    }else{
-        error!("Signatures are not equal: {} != {}", calculated_signature, received_signature);
-        return Err(warp::reject::reject());
-    };
+#[instrument(skip(app, callback), fields(order_id, order_status))]
+async fn purchase_server_callback(app: Arc<Application>, callback: RawCallback) -> Result<impl Reply, Rejection>{
+    // Коллбек сохраняется в журнал до обработки, поэтому его можно будет обработать повторно
+    let data = handle_server_callback(&app.db, &app.config, callback)
+        .await
+        .tap_err(|err|{ error!("Server callback processing failed: {}", err); })?;
 
     // Record the result as part of the current span.
     tracing::Span::current().record("order_id", &tracing::field::display(data.order_id.as_str()));
     tracing::Span::current().record("order_status", &tracing::field::debug(&data.order_status));
 
-    debug!("Purchase server callback success! Data: {:#?}", data);
+    // Страницы ожидания оплаты сами перечитают статус заказа
+    app.status_events.publish(&data.order_id);
 
-    // Данный коллбек вызывается несколько раз на изменение статуса платежа
+    Ok(warp::reply())
+}
 
-    // - Проверяем сигнатура на основании пароля
-    // - Проверяем, не была ли выдача уже через базу с транзакцией
-    // - Оповещаем наш сервер
-    // - Если наш сервер не ответил, тогда ставим в очередь периодическую отправку оповещения + сохраняем в базу до подтверждения
-    
-    // Может быть сразу делать коллбек на наш сервер для выдачи??
-    
-    // Лучше ждать прямо здесь пока наш сервер не ответит, затем возвращать ошибку
-    // Тогда их сервер будет сам делать перезапрос на выдачу
+//////////////////////////////////////////////////////////////////////////////////////////
 
-    Ok(warp::reply())
+#[instrument(skip(app, callback), fields(order_id, order_status))]
+async fn browser_callback(app: Arc<Application>, callback: RawCallback) -> Result<impl Reply, Rejection>{
+    let data = handle_browser_callback(&app.db, &app.config, callback)
+        .await
+        .tap_err(|err|{ error!("Browser callback processing failed: {}", err); })?;
+
+    tracing::Span::current().record("order_id", &tracing::field::display(data.order_id.as_str()));
+    tracing::Span::current().record("order_status", &tracing::field::debug(&data.order_status));
+
+    // Статус в браузерном коллбеке может опережать серверный коллбек,
+    // поэтому страница показывает статус из базы и ждет изменений через поток событий
+    let html = app
+        .templates
+        .render("status", &json!({
+            "order_id": data.order_id
+        }))
+        .map_err(FondyError::from)
+        .tap_err(|err| { error!("Status template rendering failed: {}", err); })?;
+
+    Ok(warp::reply::html(html))
 }
 
 //////////////////////////////////////////////////////////////////////////////////////////
 
-// #[instrument]
-// async fn browser_callback() -> Result<impl Reply, Rejection>{
-#[instrument(skip(data), fields(order_id = %data.order_id, order_status = ?data.order_status))]
-async fn browser_callback(data: FondyPaymentResponse) -> Result<impl Reply, Rejection>{
-    Ok(warp::reply::html("Success"))
+/// Метрики для Prometheus
+#[instrument(skip(app))]
+async fn metrics(app: Arc<Application>, _principal: Principal) -> Result<impl Reply, Rejection>{
+    let text = render_metrics(&app.db)
+        .await
+        .tap_err(|err| { error!("Metrics render failed: {}", err); })?;
+    Ok(warp::reply::with_header(text, "content-type", "text/plain; version=0.0.4"))
 }
 
 //////////////////////////////////////////////////////////////////////////////////////////
 
-#[instrument]
-async fn rejection_to_json(rejection: Rejection) -> Result<impl Reply, Rejection> {
-    if let Some(err) = rejection.find::<FondyError>(){
-        let reply = warp::reply::json(&json!({
-            "code": warp::http::StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-            "message": err.to_string()
-        }));
-        Ok(warp::reply::with_status(reply, warp::http::StatusCode::INTERNAL_SERVER_ERROR))
-    }else{
-        Err(rejection)
-    }
+/// Процесс жив и отвечает на запросы
+async fn healthz() -> Result<impl Reply, Rejection>{
+    Ok(warp::reply::json(&json!({
+        "status": "ok"
+    })))
+}
+
+/// Готовность принимать запросы: база, миграции, шаблоны, фоновые задачи и, если включено, API Fondy
+#[instrument(skip(app))]
+async fn readyz(app: Arc<Application>) -> Result<impl Reply, Rejection>{
+    let (ready, details) = readiness(&app, &required_templates())
+        .await;
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    Ok(warp::reply::with_status(warp::reply::json(&details), status))
 }
 
 //////////////////////////////////////////////////////////////////////////////////////////
 
-pub async fn start_server(app: Arc<Application>) {
+pub async fn start_server(app: Arc<Application>, listeners: Listeners) {
     // Маршрут индекса
     let index = warp::path::end()
         .and(warp::get())    
@@ -280,18 +555,41 @@ pub async fn start_server(app: Arc<Application>) {
                 index_app.clone()
             }
         }))
+        .and(warp::cookie::optional::<String>(CSRF_COOKIE))
         .and_then(index);
         // .with(warp::trace::named("index"));
 
+    // GET /buy без CSRF токена только если явно разрешен для старых ссылок
+    let allow_get_buy = app.config.buy.allow_get;
+    let buy_get = warp::get()
+        .and_then(move || async move {
+            if allow_get_buy {
+                Ok(())
+            }else{
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one();
+
+    // Ограничение частоты создания заказов с одного адреса и из одной сессии браузера
+    let buy_limiter = RateLimiter::new("buy", app.config.buy.rate_limit_burst, app.config.buy.rate_limit_per_minute);
+
     // Маршрут для покупки
     let buy = warp::path::path("buy")
         .and(warp::post()
-                .or(warp::get())
+                .or(buy_get)
                 .unify())
+        .and(rate_limited(buy_limiter, app.config.clone(), CSRF_COOKIE))
         .and(warp::any().map({
-            let http_client = app.http_client.clone();
+            let fondy = app.fondy.clone();
             move || { 
-                http_client.clone()
+                fondy.clone()
+            }
+        }))
+        .and(warp::any().map({
+            let db = app.db.clone();
+            move || { 
+                db.clone()
             }
         }))
         .and(warp::any().map({
@@ -300,49 +598,173 @@ pub async fn start_server(app: Arc<Application>) {
                 config.clone()
             }
         }))
-        .and(warp::filters::body::form()
-                .or(warp::query())
-                .unify())
-        .and_then(buy)
-        .recover(rejection_to_json);
+        .and(warp::any().map({
+            let templates = app.templates.clone();
+            move || { 
+                templates.clone()
+            }
+        }))
+        .and(warp::header::optional::<String>(IDEMPOTENCY_KEY_HEADER))
+        .and(warp::cookie::optional::<String>(CSRF_COOKIE))
+        .and(buy_params())
+        .and_then(buy);
         // .with(warp::trace::named("buy"));
 
-    // Маршрут для коллбека после покупки
-    let purchase_server_cb = warp::path::path("purchase_server_callback_url")
+    // JSON API создания заказа
+    let api_create_order = warp::path!("api" / "orders")
         .and(warp::post())
+        .and(warp::any().map({
+            let fondy = app.fondy.clone();
+            move || { 
+                fondy.clone()
+            }
+        }))
+        .and(warp::any().map({
+            let db = app.db.clone();
+            move || { 
+                db.clone()
+            }
+        }))
         .and(warp::any().map({
             let config = app.config.clone();
             move || { 
                 config.clone()
             }
         }))
-        .and(warp::filters::body::bytes()) // Коллбеки POST + Json
+        .and(authorized(app.clone(), Permission::CreateOrders))
+        .and(warp::header::optional::<String>(IDEMPOTENCY_KEY_HEADER))
+        .and(warp::body::content_length_limit(API_BODY_LIMIT))
+        .and(warp::body::json())
+        .and_then(api_create_order);
+
+    // JSON API опроса заказа
+    let api_get_order = warp::path!("api" / "orders" / String)
+        .and(warp::get())
+        .and(warp::any().map({
+            let db = app.db.clone();
+            move || { 
+                db.clone()
+            }
+        }))
+        .and(authorized(app.clone(), Permission::View))
+        .and_then(api_get_order);
+
+    // Поток изменений статуса заказа, открыт без авторизации для страницы оплаты покупателя,
+    // отдает только статус заказа
+    let api_order_events = warp::path!("api" / "orders" / String / "events")
+        .and(warp::get())
+        .and(warp::any().map({
+            let app = app.clone();
+            move || { 
+                app.clone()
+            }
+        }))
+        .and_then(api_order_events);
+
+    let api = api_create_order
+        .or(api_get_order)
+        .or(api_order_events);
+
+    // Исходный запрос коллбека целиком для сохранения в журнал
+    let raw_callback = client_ip(app.config.clone())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::content_length_limit(CALLBACK_BODY_LIMIT))
+        .and(warp::filters::body::bytes())
+        .map(|source_ip, headers, body|{
+            RawCallback{
+                source_ip,
+                headers,
+                body
+            }
+        });
+
+    // Маршрут для коллбека после покупки
+    // Серверный коллбек принимаем только с адресов Fondy, браузерный приходит с адреса покупателя
+    let callback_sources = Arc::new(app.config.callbacks.allowed_ips.clone());
+    if callback_sources.is_empty() {
+        warn!("CALLBACK_ALLOWED_IPS is empty, server callbacks are accepted from any address");
+    }
+    let purchase_server_cb = warp::path::path("purchase_server_callback_url")
+        .and(warp::post())
+        .and(allowed_sources(app.clone(), callback_sources))
+        .and(warp::any().map({
+            let app = app.clone();
+            move || { 
+                app.clone()
+            }
+        }))
+        .and(raw_callback.clone()) // Коллбеки POST + Json
         .and_then(purchase_server_callback);
         // .with(warp::trace::named("purchase_server_callback_url"));
 
     // Маршрут для коллбека после покупки
     let purchase_browser_cb = warp::path::path("browser_redirect_callback_url")
         .and(warp::post())
-        .and(warp::filters::body::form()
-                .or(warp::filters::body::form())
-                .unify()) // В браузере POST + Form
+        .and(warp::any().map({
+            let app = app.clone();
+            move || { 
+                app.clone()
+            }
+        }))
+        .and(raw_callback) // В браузере POST + Form
         .and_then(browser_callback);
         // .with(warp::trace::named("browser_redirect_callback_url"));
 
+    // Метрики Prometheus, доступны по ключу API с ролью viewer
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(warp::any().map({
+            let app = app.clone();
+            move || { 
+                app.clone()
+            }
+        }))
+        .and(authorized(app.clone(), Permission::View))
+        .and_then(metrics);
+
+    // Проверки для оркестратора, открыты без авторизации
+    let healthz = warp::path!("healthz")
+        .and(warp::get())
+        .and_then(healthz);
+    let readyz = warp::path!("readyz")
+        .and(warp::get())
+        .and(warp::any().map({
+            let app = app.clone();
+            move || { 
+                app.clone()
+            }
+        }))
+        .and_then(readyz);
+
     // Маршрут для отдачи статических данных
     let static_files = warp::path::path("static")
         .and(warp::fs::dir("static"));
 
     let routes = index
         .or(buy)
+        .or(api)
+        .or(admin_routes(app.clone()))
         .or(purchase_server_cb)
         .or(purchase_browser_cb)
-        .or(static_files)
+        .or(metrics)
+        .or(healthz)
+        .or(readyz)
+        .or(static_files);
+
+    // Ошибки всех маршрутов обрабатываются в одном месте, метрики и трассировка видят итоговый код ответа
+    let routes = with_error_responses(app.clone(), routes)
+        .with(warp::log::custom(|info|{
+            http_request_finished(route_label(info.path()), info.method().as_str(), info.status().as_u16(), info.elapsed());
+        }))
         .with(warp::trace::request());
 
-    warp::serve(routes)
-        .bind(([0, 0, 0, 0], 8080))
-        .await;
+    // После сигнала остановки новые соединения не принимаются, текущие запросы доделываются
+    let routes = routes
+        .map(Reply::into_response)
+        .boxed();
+    info!("Server started");
+    serve_listeners(listeners, routes, app.shutdown.listener()).await;
+    info!("Server stopped");
 }
 
 //////////////////////////////////////////////////////////////////////////////////////////
@@ -0,0 +1,481 @@
+use std::{
+    convert::{
+        Infallible
+    },
+    fs::{
+        File
+    },
+    io::{
+        BufReader
+    },
+    net::{
+        SocketAddr
+    },
+    path::{
+        Path,
+        PathBuf
+    },
+    sync::{
+        Arc,
+        RwLock
+    },
+    time::{
+        Duration,
+        SystemTime
+    }
+};
+use futures::{
+    stream
+};
+use hyper::{
+    Body,
+    Request,
+    Server,
+    server::{
+        accept,
+        conn::{
+            AddrIncoming,
+            AddrStream
+        }
+    },
+    service::{
+        Service,
+        make_service_fn,
+        service_fn
+    }
+};
+use tokio::{
+    io::{
+        AsyncRead,
+        AsyncWrite
+    },
+    net::{
+        TcpListener,
+        TcpStream
+    },
+    sync::{
+        mpsc
+    }
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        NoClientAuth,
+        ServerConfig,
+        internal::{
+            pemfile
+        }
+    },
+    server::{
+        TlsStream
+    }
+};
+use tracing::{
+    debug,
+    error,
+    info,
+    warn
+};
+use warp::{
+    Filter,
+    filters::{
+        BoxedFilter
+    },
+    reply::{
+        Response
+    }
+};
+use tap::{
+    prelude::{
+        *
+    }
+};
+use crate::{
+    error::{
+        FondyError
+    },
+    config::{
+        ListenAddress,
+        ServerConfig as AppServerConfig,
+        TlsConfig
+    },
+    shutdown::{
+        ShutdownListener
+    }
+};
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+/// Сколько ждать TLS рукопожатия, чтобы молчащие клиенты не копились
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Пауза после ошибки accept, например при нехватке файловых дескрипторов
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Очередь принятых, но еще не обслуживаемых соединений
+const ACCEPT_QUEUE_SIZE: usize = 64;
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+/// Сведения о соединении, кладутся в расширения каждого запроса.
+/// warp::addr::remote() работает только для собственного TCP сервера warp.
+#[derive(Debug, Clone)]
+struct ConnectionInfo{
+    remote_addr: Option<SocketAddr>
+}
+
+/// Адрес клиента, для Unix сокета отсутствует
+pub fn remote_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::ext::optional::<ConnectionInfo>()
+        .map(|info: Option<ConnectionInfo>| {
+            info.and_then(|info| info.remote_addr)
+        })
+}
+
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    fn remote_addr(&self) -> Option<SocketAddr>;
+}
+
+impl Connection for AddrStream {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(AddrStream::remote_addr(self))
+    }
+}
+
+impl Connection for TlsStream<TcpStream> {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.peer_addr().ok()
+    }
+}
+
+#[cfg(unix)]
+impl Connection for tokio::net::UnixStream {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+/// Текущий сертификат, заменяется при изменении файлов без перезапуска
+#[derive(Clone)]
+struct TlsCertificates{
+    config: TlsConfig,
+    server_config: Arc<RwLock<Arc<ServerConfig>>>
+}
+
+impl std::fmt::Debug for TlsCertificates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsCertificates")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+fn load_server_config(config: &TlsConfig) -> Result<ServerConfig, FondyError> {
+    let open = |path: &PathBuf| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| FondyError::Custom(format!("TLS file {} open failed: {}", path.display(), err)))
+    };
+
+    let certs = pemfile::certs(&mut open(&config.cert_path)?)
+        .map_err(|_| FondyError::Custom(format!("TLS certificate {} parse failed", config.cert_path.display())))?;
+    if certs.is_empty() {
+        return Err(FondyError::Custom(format!("TLS certificate {} has no certificates", config.cert_path.display())));
+    }
+
+    // Ключ может быть в PKCS#8 (openssl по-умолчанию) или в старом формате RSA
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(&config.key_path)?)
+        .unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(&config.key_path)?)
+            .unwrap_or_default();
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| FondyError::Custom(format!("TLS key {} has no private key", config.key_path.display())))?;
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config
+        .set_single_cert(certs, key)
+        .map_err(|err| FondyError::Custom(format!("TLS certificate and key mismatch: {}", err)))?;
+    server_config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    Ok(server_config)
+}
+
+impl TlsCertificates {
+    fn load(config: &TlsConfig) -> Result<TlsCertificates, FondyError> {
+        let server_config = load_server_config(config)?;
+        Ok(TlsCertificates{
+            config: config.clone(),
+            server_config: Arc::new(RwLock::new(Arc::new(server_config)))
+        })
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        let server_config = self.server_config
+            .read()
+            .expect("TLS config lock poisoned")
+            .clone();
+        TlsAcceptor::from(server_config)
+    }
+
+    /// Проверяет файлы на изменение и подменяет сертификат для новых соединений.
+    /// При ошибке остается прежний сертификат, например если ключ еще не дописан.
+    async fn run_reload(self, mut shutdown: ShutdownListener) {
+        let modified = |config: &TlsConfig| (modified_time(&config.cert_path), modified_time(&config.key_path));
+        let mut last_modified = modified(&self.config);
+        let mut interval = tokio::time::interval(self.config.reload_interval);
+        loop {
+            tokio::select!{
+                _ = interval.tick() => {},
+                _ = shutdown.wait() => break
+            }
+
+            let current = modified(&self.config);
+            if current == last_modified {
+                continue;
+            }
+            last_modified = current;
+
+            match load_server_config(&self.config) {
+                Ok(server_config) => {
+                    *self.server_config.write().expect("TLS config lock poisoned") = Arc::new(server_config);
+                    info!(cert = %self.config.cert_path.display(), "TLS certificate reloaded");
+                },
+                Err(err) => {
+                    warn!(%err, "TLS certificate reload failed, previous certificate is used");
+                }
+            }
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+enum Listener{
+    Http(AddrIncoming),
+    Https(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf)
+}
+
+/// Открытые сокеты, создаются до запуска фоновых задач, чтобы занятый порт
+/// или битый сертификат сразу останавливали запуск
+#[derive(Debug)]
+pub struct Listeners{
+    listeners: Vec<(ListenAddress, Listener)>,
+    tls: Option<TlsCertificates>
+}
+
+async fn bind(address: &ListenAddress) -> Result<Listener, FondyError> {
+    match address {
+        ListenAddress::Http(socket_address) => {
+            let mut incoming = AddrIncoming::bind(socket_address)
+                .map_err(|err| FondyError::Custom(format!("Bind {} failed: {}", address, err)))?;
+            incoming.set_nodelay(true);
+            Ok(Listener::Http(incoming))
+        },
+        ListenAddress::Https(socket_address) => {
+            let listener = TcpListener::bind(socket_address)
+                .await?;
+            Ok(Listener::Https(listener))
+        },
+        #[cfg(unix)]
+        ListenAddress::Unix(path) => {
+            // Файл от прошлого запуска мешает bind, но чужой файл трогать нельзя
+            if let Ok(meta) = std::fs::symlink_metadata(path) {
+                use std::os::unix::fs::FileTypeExt;
+                if !meta.file_type().is_socket() {
+                    return Err(FondyError::Custom(format!("{} exists and is not a socket", path.display())));
+                }
+                std::fs::remove_file(path)?;
+            }
+            let listener = tokio::net::UnixListener::bind(path)?;
+            Ok(Listener::Unix(listener, path.clone()))
+        },
+        #[cfg(not(unix))]
+        ListenAddress::Unix(_) => {
+            Err(FondyError::Custom(format!("Unix sockets are not supported on this platform: {}", address)))
+        }
+    }
+}
+
+pub async fn bind_listeners(config: &AppServerConfig) -> Result<Listeners, FondyError> {
+    let tls = config
+        .tls
+        .as_ref()
+        .map(TlsCertificates::load)
+        .transpose()?;
+
+    let mut listeners = Vec::with_capacity(config.listen.len());
+    for address in config.listen.iter() {
+        let listener = bind(address)
+            .await
+            .tap_err(|err| {
+                error!(%address, %err, "Listener bind failed");
+            })?;
+        listeners.push((address.clone(), listener));
+    }
+
+    Ok(Listeners{
+        listeners,
+        tls
+    })
+}
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+/// Принимает соединения в отдельной задаче и отдает их серверу через очередь
+fn channel_incoming<C: Connection>(receiver: mpsc::Receiver<C>) -> impl accept::Accept<Conn = C, Error = Infallible> {
+    let mut receiver = receiver;
+    accept::from_stream(stream::poll_fn(move |cx| {
+        receiver
+            .poll_recv(cx)
+            .map(|connection| connection.map(Ok))
+    }))
+}
+
+async fn accept_tls(listener: TcpListener, certificates: TlsCertificates, sender: mpsc::Sender<TlsStream<TcpStream>>, mut shutdown: ShutdownListener) {
+    loop {
+        let (socket, remote_addr) = tokio::select!{
+            res = listener.accept() => match res {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!(%err, "TCP accept failed");
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            },
+            _ = shutdown.wait() => break
+        };
+
+        // Рукопожатие в отдельной задаче, чтобы медленный клиент не держал остальных
+        let acceptor = certificates.acceptor();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                Ok(Ok(stream)) => {
+                    sender.send(stream).await.ok();
+                },
+                Ok(Err(err)) => {
+                    debug!(%remote_addr, %err, "TLS handshake failed");
+                },
+                Err(_) => {
+                    debug!(%remote_addr, "TLS handshake timed out");
+                }
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+async fn accept_unix(listener: tokio::net::UnixListener, sender: mpsc::Sender<tokio::net::UnixStream>, mut shutdown: ShutdownListener) {
+    loop {
+        let socket = tokio::select!{
+            res = listener.accept() => match res {
+                Ok((socket, _)) => socket,
+                Err(err) => {
+                    warn!(%err, "Unix socket accept failed");
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            },
+            _ = shutdown.wait() => break
+        };
+        if sender.send(socket).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Обслуживает соединения до сигнала остановки, после него дожидается текущих запросов
+async fn serve<I>(incoming: I, routes: BoxedFilter<(Response,)>, mut shutdown: ShutdownListener) -> Result<(), FondyError>
+where
+    I: accept::Accept,
+    I::Conn: Connection,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>
+{
+    let service = warp::service(routes);
+    let make_service = make_service_fn(move |connection: &I::Conn| {
+        let info = ConnectionInfo{
+            remote_addr: connection.remote_addr()
+        };
+        let service = service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut request: Request<Body>| {
+                request.extensions_mut().insert(info.clone());
+                let mut service = service.clone();
+                service.call(request)
+            }))
+        }
+    });
+    Server::builder(incoming)
+        .serve(make_service)
+        .with_graceful_shutdown(async move {
+            shutdown.wait().await;
+        })
+        .await
+        .map_err(|err| FondyError::Custom(format!("Server failed: {}", err)))
+}
+
+/// Запускает все слушатели с общими маршрутами, завершается когда остановлены все
+pub async fn serve_listeners(listeners: Listeners, routes: BoxedFilter<(Response,)>, shutdown: ShutdownListener) {
+    let Listeners{ listeners, tls } = listeners;
+
+    let mut servers = Vec::with_capacity(listeners.len() + 1);
+    if let Some(tls) = tls.clone() {
+        let shutdown = shutdown.clone();
+        servers.push(tokio::spawn(async move {
+            tls.run_reload(shutdown).await;
+            Ok(())
+        }));
+    }
+
+    for (address, listener) in listeners {
+        let routes = routes.clone();
+        let shutdown = shutdown.clone();
+        info!(%address, "Listening");
+        let server = match listener {
+            Listener::Http(incoming) => {
+                tokio::spawn(serve(incoming, routes, shutdown))
+            },
+            Listener::Https(listener) => {
+                let (sender, receiver) = mpsc::channel(ACCEPT_QUEUE_SIZE);
+                let certificates = tls
+                    .clone()
+                    .expect("TLS listener without certificates");
+                tokio::spawn(accept_tls(listener, certificates, sender, shutdown.clone()));
+                tokio::spawn(serve(channel_incoming(receiver), routes, shutdown))
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                let (sender, receiver) = mpsc::channel(ACCEPT_QUEUE_SIZE);
+                tokio::spawn(accept_unix(listener, sender, shutdown.clone()));
+                tokio::spawn(async move {
+                    let res = serve(channel_incoming(receiver), routes, shutdown).await;
+                    std::fs::remove_file(&path).ok();
+                    res
+                })
+            }
+        };
+        servers.push(server);
+    }
+
+    for server in futures::future::join_all(servers).await {
+        match server {
+            Ok(Ok(())) => {},
+            Ok(Err(err)) => error!(%err, "Listener failed"),
+            Err(err) => error!(%err, "Listener task failed")
+        }
+    }
+}
@@ -1,12 +1,32 @@
-mod messages;
 mod handlers;
-mod signature;
+mod admin;
+mod auth;
+mod client_ip;
+mod csrf;
+mod errors;
+mod listeners;
+mod rate_limit;
 
 pub use self::{
     handlers::{
         start_server
     },
-    messages::{
-        FondyInvalidResponse
+    listeners::{
+        bind_listeners
     }
-};
\ No newline at end of file
+};
+
+/// Шаблоны страниц из templates
+pub const PAGE_TEMPLATES: &[&str] = &["index", "status", "checkout", "error", "maintenance"];
+
+/// Шаблоны из templates/admin, регистрируются с префиксом admin/
+pub const ADMIN_TEMPLATES: &[&str] = &["header", "footer", "login", "dashboard", "orders", "order", "products", "error"];
+
+/// Имена всех шаблонов, которые нужны серверу
+pub fn required_templates() -> Vec<String> {
+    PAGE_TEMPLATES
+        .iter()
+        .map(|name| name.to_string())
+        .chain(ADMIN_TEMPLATES.iter().map(|name| format!("admin/{}", name)))
+        .collect()
+}
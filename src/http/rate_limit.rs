@@ -0,0 +1,172 @@
+use std::{
+    collections::{
+        HashMap
+    },
+    net::{
+        IpAddr
+    },
+    sync::{
+        Arc,
+        Mutex
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+use tracing::{
+    warn
+};
+use warp::{
+    Filter,
+    Rejection
+};
+use super::{
+    client_ip::{
+        client_ip
+    }
+};
+use crate::{
+    config::{
+        AppConfig
+    },
+    error::{
+        FondyError
+    },
+    metrics::{
+        http_request_rate_limited
+    }
+};
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+/// Как часто выбрасываются корзины клиентов, которые успели полностью восстановиться
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Bucket{
+    tokens: f64,
+    updated_at: Instant
+}
+
+#[derive(Debug)]
+struct LimiterState{
+    buckets: HashMap<String, Bucket>,
+    pruned_at: Instant
+}
+
+/// Ограничение частоты запросов корзиной токенов: клиент может сделать `burst` запросов подряд,
+/// дальше запас восстанавливается на `per_minute` запросов в минуту.
+#[derive(Debug)]
+pub(super) struct RateLimiter{
+    /// Имя для логов и метрик
+    name: &'static str,
+    burst: f64,
+    refill_per_sec: f64,
+    state: Mutex<LimiterState>
+}
+
+impl RateLimiter {
+    pub(super) fn new(name: &'static str, burst: u32, per_minute: u32) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter{
+            name,
+            burst: f64::from(burst),
+            refill_per_sec: f64::from(per_minute) / 60.0,
+            state: Mutex::new(LimiterState{
+                buckets: HashMap::new(),
+                pruned_at: Instant::now()
+            })
+        })
+    }
+
+    /// Забирает по токену из корзины каждого ключа.
+    /// Если хоть в одной пусто, не списывает ничего и возвращает время до появления токена.
+    fn acquire(&self, keys: &[String]) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("Rate limiter lock poisoned");
+
+        if now.duration_since(state.pruned_at) >= PRUNE_INTERVAL {
+            let (burst, refill_per_sec) = (self.burst, self.refill_per_sec);
+            state.buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated_at).as_secs_f64() * refill_per_sec < burst
+            });
+            state.pruned_at = now;
+        }
+
+        let mut wait = Duration::from_secs(0);
+        for key in keys {
+            let bucket = state
+                .buckets
+                .entry(key.clone())
+                .or_insert(Bucket{
+                    tokens: self.burst,
+                    updated_at: now
+                });
+            let refilled = now.duration_since(bucket.updated_at).as_secs_f64() * self.refill_per_sec;
+            bucket.tokens = (bucket.tokens + refilled).min(self.burst);
+            bucket.updated_at = now;
+            if bucket.tokens < 1.0 {
+                wait = wait.max(Duration::from_secs_f64((1.0 - bucket.tokens) / self.refill_per_sec));
+            }
+        }
+        if wait > Duration::from_secs(0) {
+            return Err(wait);
+        }
+
+        for key in keys {
+            if let Some(bucket) = state.buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Фильтр, ограничивающий частоту запросов с одного адреса и из одной сессии.
+/// Сессией считается значение cookie `session_cookie`, если браузер его прислал.
+pub(super) fn rate_limited(limiter: Arc<RateLimiter>, config: Arc<AppConfig>, session_cookie: &'static str) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    client_ip(config)
+        .and(warp::cookie::optional::<String>(session_cookie))
+        .and_then(move |client_ip: Option<IpAddr>, session: Option<String>| {
+            let limiter = limiter.clone();
+            async move {
+                let keys: Vec<String> = client_ip
+                    .map(|ip| format!("ip:{}", ip))
+                    .into_iter()
+                    .chain(session.map(|session| format!("session:{}", session)))
+                    .collect();
+                limiter
+                    .acquire(&keys)
+                    .map_err(|retry_after| {
+                        warn!(limiter = limiter.name, ?client_ip, "Request rate limit exceeded");
+                        http_request_rate_limited(limiter.name);
+                        warp::reject::custom(FondyError::RateLimited(retry_after))
+                    })
+            }
+        })
+        .untuple_one()
+}
+
+//////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter(){
+        let limiter = RateLimiter::new("test", 2, 60);
+        let ip = vec!["ip:1".to_owned()];
+        let ip_and_session = vec!["ip:1".to_owned(), "session:a".to_owned()];
+        assert!(limiter.acquire(&ip).is_ok());
+        assert!(limiter.acquire(&ip).is_ok());
+
+        // Токен восстанавливается за секунду, отказ не списывает токен из корзины сессии
+        let wait = limiter.acquire(&ip_and_session).unwrap_err();
+        assert!(wait <= Duration::from_secs(1));
+        assert!(limiter.acquire(&["session:a".to_owned()]).is_ok());
+        assert!(limiter.acquire(&["session:a".to_owned()]).is_ok());
+        assert!(limiter.acquire(&["session:a".to_owned()]).is_err());
+        assert!(limiter.acquire(&["ip:2".to_owned()]).is_ok());
+    }
+}
@@ -0,0 +1,329 @@
+use std::{
+    collections::{
+        BTreeMap
+    }
+};
+use chrono::{
+    Duration as ChronoDuration,
+    NaiveDate
+};
+use serde::{
+    Serialize
+};
+use tracing::{
+    debug,
+    warn,
+    instrument
+};
+use crate::{
+    database::{
+        Database,
+        LedgerDailyTotal,
+        NewLedgerEntry
+    },
+    error::{
+        FondyError
+    },
+    fondy::{
+        OrderStatus
+    }
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Счета учета, все суммы в минимальных единицах валюты
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedgerAccount{
+    /// Деньги покупателей, которые находятся у Fondy и еще не выплачены нам
+    FondyReceivable,
+
+    /// Деньги, выплаченные Fondy на наш счет
+    Bank,
+
+    /// Выручка от продаж
+    Revenue,
+
+    /// Комиссии Fondy
+    Fees,
+
+    /// Возвраты покупателям
+    Refunds,
+
+    /// Списания по чарджбекам
+    Chargebacks
+}
+
+impl LedgerAccount {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LedgerAccount::FondyReceivable => "fondy_receivable",
+            LedgerAccount::Bank => "bank",
+            LedgerAccount::Revenue => "revenue",
+            LedgerAccount::Fees => "fees",
+            LedgerAccount::Refunds => "refunds",
+            LedgerAccount::Chargebacks => "chargebacks"
+        }
+    }
+}
+
+/// Вид проводки
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind{
+    Payment,
+    Fee,
+    Refund,
+    Chargeback,
+    Payout
+}
+
+impl EntryKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EntryKind::Payment => "payment",
+            EntryKind::Fee => "fee",
+            EntryKind::Refund => "refund",
+            EntryKind::Chargeback => "chargeback",
+            EntryKind::Payout => "payout"
+        }
+    }
+}
+
+/// Проводка по правилу двойной записи: дебет положительный, кредит отрицательный, в сумме ноль
+#[derive(Debug, Clone)]
+pub struct JournalEntry{
+    pub key: String,
+    pub kind: EntryKind,
+    pub order_id: Option<String>,
+    pub currency: String,
+    pub postings: Vec<(LedgerAccount, i64)>
+}
+
+impl JournalEntry {
+    /// Перевод суммы с кредита одного счета в дебет другого
+    pub fn transfer(key: String, kind: EntryKind, order_id: Option<&str>, currency: &str, debit: LedgerAccount, credit: LedgerAccount, amount: i64) -> JournalEntry {
+        JournalEntry{
+            key,
+            kind,
+            order_id: order_id.map(|val| val.to_owned()),
+            currency: currency.to_owned(),
+            postings: vec![(debit, amount), (credit, -amount)]
+        }
+    }
+
+    /// Проверка инвариантов: хотя бы две строки, без нулевых сумм, сумма строк равна нулю
+    pub fn validate(&self) -> Result<(), FondyError> {
+        let balanced = self.postings.len() >= 2 &&
+            self.postings.iter().all(|(_, amount)| *amount != 0) &&
+            self.postings.iter().map(|(_, amount)| *amount).sum::<i64>() == 0;
+        if balanced {
+            Ok(())
+        }else{
+            Err(FondyError::UnbalancedLedgerEntry(self.key.clone()))
+        }
+    }
+}
+
+/// Суммы платежа, известные на данный момент из коллбека или запроса статуса
+#[derive(Debug)]
+pub struct PaymentAmounts<'a>{
+    pub order_id: &'a str,
+    pub order_status: OrderStatus,
+    pub currency: &'a str,
+    /// Сумма после всех возвратов
+    pub actual_amount: i64,
+    /// Сумма всех возвратов
+    pub reversal_amount: i64,
+    pub fee: Option<i64>
+}
+
+/// Остаток на счете на конец дня
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DailyBalance{
+    pub date: NaiveDate,
+    pub account: String,
+    pub currency: String,
+    pub balance: i64
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Сохраняет проводку, если она сбалансирована. Возвращает false для уже записанной проводки.
+#[instrument(skip(db), fields(key = %entry.key))]
+pub async fn record_entry(db: &Database, entry: &JournalEntry) -> Result<bool, FondyError> {
+    entry.validate()?;
+
+    let postings: Vec<(&str, i64)> = entry
+        .postings
+        .iter()
+        .map(|(account, amount)| (account.as_str(), *amount))
+        .collect();
+    let inserted = db
+        .insert_ledger_entry(NewLedgerEntry{
+            entry_key: &entry.key,
+            entry_kind: entry.kind.as_str(),
+            order_id: entry.order_id.as_deref(),
+            currency: &entry.currency,
+            postings: &postings
+        })
+        .await?;
+    if inserted {
+        debug!(kind = entry.kind.as_str(), "Ledger entry recorded");
+    }
+    Ok(inserted)
+}
+
+/// Проводки по текущим суммам платежа: оплата, комиссия и новые возвраты.
+/// Можно вызывать на каждый коллбек, уже записанное повторно не сохраняется.
+#[instrument(skip(db))]
+pub async fn record_payment_amounts(db: &Database, amounts: PaymentAmounts<'_>) -> Result<(), FondyError> {
+    if !matches!(amounts.order_status, OrderStatus::Approved | OrderStatus::Reversed) {
+        return Ok(());
+    }
+    let order_id = Some(amounts.order_id);
+
+    // actual_amount уже уменьшен на возвраты, поэтому оплаченная сумма - это их сумма
+    let captured = amounts.actual_amount + amounts.reversal_amount;
+    if captured > 0 {
+        let entry = JournalEntry::transfer(format!("payment:{}", amounts.order_id), EntryKind::Payment, order_id, amounts.currency,
+                                           LedgerAccount::FondyReceivable, LedgerAccount::Revenue, captured);
+        record_entry(db, &entry).await?;
+    }
+
+    if let Some(fee) = amounts.fee {
+        record_fee(db, amounts.order_id, amounts.currency, fee).await?;
+    }
+
+    // Возвраты приходят накопленной суммой, записываем только разницу с уже учтенными
+    let refunded = db
+        .ledger_order_account_total(amounts.order_id, LedgerAccount::Refunds.as_str())
+        .await?;
+    let refund = amounts.reversal_amount - refunded;
+    if refund > 0 {
+        let entry = JournalEntry::transfer(format!("refund:{}:{}", amounts.order_id, amounts.reversal_amount), EntryKind::Refund, order_id, amounts.currency,
+                                           LedgerAccount::Refunds, LedgerAccount::FondyReceivable, refund);
+        record_entry(db, &entry).await?;
+    }else if refund < 0 {
+        warn!(order_id = amounts.order_id, reversal_amount = amounts.reversal_amount, refunded, "Reversal amount is less than recorded refunds");
+    }
+
+    Ok(())
+}
+
+/// Комиссия Fondy по заказу, приходит и в коллбеке, и в отчете, записывается один раз
+#[instrument(skip(db))]
+pub async fn record_fee(db: &Database, order_id: &str, currency: &str, fee: i64) -> Result<(), FondyError> {
+    if fee <= 0 {
+        return Ok(());
+    }
+    let entry = JournalEntry::transfer(format!("fee:{}", order_id), EntryKind::Fee, Some(order_id), currency,
+                                       LedgerAccount::Fees, LedgerAccount::FondyReceivable, fee);
+    record_entry(db, &entry).await?;
+    Ok(())
+}
+
+/// Выплата Fondy на наш счет по транзакции из отчета
+#[instrument(skip(db))]
+pub async fn record_payout(db: &Database, payment_id: &str, order_id: &str, currency: &str, amount: i64) -> Result<(), FondyError> {
+    if amount <= 0 {
+        return Ok(());
+    }
+    let entry = JournalEntry::transfer(format!("payout:{}", payment_id), EntryKind::Payout, Some(order_id), currency,
+                                       LedgerAccount::Bank, LedgerAccount::FondyReceivable, amount);
+    record_entry(db, &entry).await?;
+    Ok(())
+}
+
+/// Чарджбек по заказу, Fondy сообщает о них отдельно, поэтому записывается вручную
+#[instrument(skip(db))]
+pub async fn record_chargeback(db: &Database, order_id: &str, currency: &str, amount: i64) -> Result<bool, FondyError> {
+    let entry = JournalEntry::transfer(format!("chargeback:{}", order_id), EntryKind::Chargeback, Some(order_id), currency,
+                                       LedgerAccount::Chargebacks, LedgerAccount::FondyReceivable, amount);
+    record_entry(db, &entry).await
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Остатки по счетам на конец каждого дня периода из дневных оборотов
+fn accumulate_balances(totals: &[LedgerDailyTotal], from: NaiveDate, to: NaiveDate) -> Vec<DailyBalance> {
+    let epoch = NaiveDate::from_ymd(1970, 1, 1);
+    let day_number = |date: NaiveDate| (date - epoch).num_days();
+
+    // Остаток на начало периода
+    let mut balances: BTreeMap<(String, String), i64> = BTreeMap::new();
+    for total in totals.iter().filter(|total| total.day < day_number(from)) {
+        *balances.entry((total.account.clone(), total.currency.clone())).or_default() += total.amount;
+    }
+
+    let mut result = Vec::new();
+    let mut date = from;
+    while date <= to {
+        for total in totals.iter().filter(|total| total.day == day_number(date)) {
+            *balances.entry((total.account.clone(), total.currency.clone())).or_default() += total.amount;
+        }
+        for ((account, currency), balance) in balances.iter() {
+            result.push(DailyBalance{
+                date,
+                account: account.clone(),
+                currency: currency.clone(),
+                balance: *balance
+            });
+        }
+        date += ChronoDuration::days(1);
+    }
+    result
+}
+
+/// Остатки по счетам на конец каждого дня с `from` по `to` включительно, дни в UTC
+#[instrument(skip(db))]
+pub async fn daily_balances(db: &Database, from: NaiveDate, to: NaiveDate) -> Result<Vec<DailyBalance>, FondyError> {
+    let before = (to + ChronoDuration::days(1)).and_hms(0, 0, 0).timestamp();
+    let totals = db.ledger_daily_totals(before).await?;
+    Ok(accumulate_balances(&totals, from, to))
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_entry_validation(){
+        let entry = JournalEntry::transfer("payment:1".to_owned(), EntryKind::Payment, Some("1"), "USD",
+                                           LedgerAccount::FondyReceivable, LedgerAccount::Revenue, 100);
+        assert!(entry.validate().is_ok());
+
+        let mut unbalanced = entry.clone();
+        unbalanced.postings.push((LedgerAccount::Fees, 5));
+        assert!(unbalanced.validate().is_err());
+
+        let zero = JournalEntry::transfer("payment:2".to_owned(), EntryKind::Payment, None, "USD",
+                                          LedgerAccount::FondyReceivable, LedgerAccount::Revenue, 0);
+        assert!(zero.validate().is_err());
+    }
+
+    #[test]
+    fn test_accumulate_balances(){
+        let total = |day: i64, account: &str, amount: i64| LedgerDailyTotal{
+            day,
+            account: account.to_owned(),
+            currency: "USD".to_owned(),
+            amount
+        };
+        // 18763 - 2021-05-16
+        let totals = vec![
+            total(18762, "revenue", -100),
+            total(18763, "revenue", -50),
+            total(18765, "revenue", -10)
+        ];
+        let balances = accumulate_balances(&totals, NaiveDate::from_ymd(2021, 5, 16), NaiveDate::from_ymd(2021, 5, 17));
+        let values: Vec<(NaiveDate, i64)> = balances
+            .iter()
+            .map(|val| (val.date, val.balance))
+            .collect();
+        assert_eq!(values, vec![
+            (NaiveDate::from_ymd(2021, 5, 16), -150),
+            (NaiveDate::from_ymd(2021, 5, 17), -150)
+        ]);
+    }
+}
@@ -2,31 +2,36 @@ mod error;
 mod http;
 mod database;
 mod application;
+mod secret;
+mod config;
+mod fondy;
+mod cli;
+mod callbacks;
+mod orders;
+mod reconciliation;
+mod expiry;
+mod settlements;
+mod ledger;
+mod checkout;
+mod auth;
+mod metrics;
+mod health;
+mod shutdown;
 
 
-use std::{
-    sync::{
-        Arc
-    }
+use structopt::{
+    StructOpt
 };
 use tracing_subscriber::{
     prelude::{
         *
     }
 };
-use url::{
-    Url
-};
 use crate::{
-    http::{
-        start_server
-    },
-    database::{
-        Database
-    },
-    application::{
-        Application,
-        AppConfig
+    cli::{
+        CliArgs,
+        Command,
+        execute_command
     },
     error::{
         FondyError
@@ -55,10 +60,16 @@ fn initialize_logs() {
         .unwrap();
 }
 
+// Макрос human_panic внутри использует устаревший тип PanicInfo
+#[allow(deprecated)]
+fn initialize_panic_handler() {
+    human_panic::setup_panic!();
+}
+
 #[tokio::main]
 async fn main() -> Result<(), FondyError> {
     // Настраиваем удобное чтение паники
-    human_panic::setup_panic!();
+    initialize_panic_handler();
 
     // Подтягиваем окружение из файлика .env
     dotenv::dotenv().ok();
@@ -66,46 +77,10 @@ async fn main() -> Result<(), FondyError> {
     // Инициализируем менеджер логирования
     initialize_logs();
 
-    // База данных
-    let db = Arc::new(Database::open_database()
-        .await);
-
-    // Шаблоны HTML
-    let mut templates = handlebars::Handlebars::new();
-    {
-        templates.register_template_file("index", "templates/index.hbs")
-            .expect("Index template read failed");
-    }
-
-    // Адрес нашего сайта
-    let site_url = Url::parse(std::env::var("SITE_URL")
-                                .expect("SITE_URL variable is missing")
-                                .as_str())
-        .expect("SITE_URL is invalid url");
+    // Аргументы командной строки
+    let args = CliArgs::from_args();
 
-    // Идентификаторы продавца
-    let merchant_id = std::env::var("MERCHANT_ID")
-        .expect("MERCHANT_ID env variable is missing")
-        .parse::<u64>()
-        .expect("MERCHANT_ID must be u64");
-    let merchant_password = std::env::var("MERCHANT_PASSWORD")
-        .expect("MERCHANT_PASSWORD env variable is missing");
-
-    // Приложение со всеми нужными нам менеджерами
-    let app = Arc::new(Application{
-        db,
-        templates: Arc::new(templates),
-        http_client: reqwest::Client::new(),
-        config: Arc::new(AppConfig{
-            site_url,
-            merchant_id,
-            merchant_password
-        })
-    });
-
-    // Стартуем сервер
-    start_server(app)
-        .await;
-    
-    Ok(())
-}
\ No newline at end of file
+    // Без команды просто стартуем сервер
+    execute_command(args.command.unwrap_or(Command::Serve))
+        .await
+}
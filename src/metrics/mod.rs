@@ -0,0 +1,246 @@
+use std::{
+    time::{
+        Duration
+    }
+};
+use lazy_static::{
+    lazy_static
+};
+use prometheus::{
+    Encoder,
+    HistogramVec,
+    IntCounter,
+    IntCounterVec,
+    IntGauge,
+    TextEncoder,
+    register_histogram_vec,
+    register_int_counter,
+    register_int_counter_vec,
+    register_int_gauge
+};
+use crate::{
+    database::{
+        Database,
+        OrderFilter
+    },
+    error::{
+        FondyError
+    },
+    orders::{
+        PENDING_STATUSES
+    }
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// Метрики регистрируются в общем реестре prometheus при первом обращении.
+// Так их можно обновлять из любого модуля, не протаскивая реестр через все вызовы.
+lazy_static! {
+    static ref CHECKOUTS_CREATED: IntCounterVec = register_int_counter_vec!(
+        "fondy_checkouts_created_total",
+        "Created checkouts by payment page mode",
+        &["mode"]
+    ).expect("Metric register failed");
+
+    static ref CALLBACKS_RECEIVED: IntCounterVec = register_int_counter_vec!(
+        "fondy_callbacks_received_total",
+        "Received Fondy callbacks by kind, payment status and signature validity",
+        &["kind", "order_status", "signature_valid"]
+    ).expect("Metric register failed");
+
+    static ref FONDY_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "fondy_api_request_duration_seconds",
+        "Fondy API request latency by method",
+        &["method"]
+    ).expect("Metric register failed");
+
+    static ref FONDY_REQUEST_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "fondy_api_errors_total",
+        "Failed Fondy API requests by method and Fondy error code, transport errors have code 'transport'",
+        &["method", "error_code"]
+    ).expect("Metric register failed");
+
+    static ref ORDER_TRANSITIONS: IntCounterVec = register_int_counter_vec!(
+        "fondy_order_transitions_total",
+        "Applied order status transitions",
+        &["source", "from", "to"]
+    ).expect("Metric register failed");
+
+    static ref CALLBACK_SOURCE_REJECTED: IntCounter = register_int_counter!(
+        "fondy_callbacks_rejected_source_total",
+        "Server callbacks rejected because source address is not allowed"
+    ).expect("Metric register failed");
+
+    static ref FAILED_CALLBACKS: IntGauge = register_int_gauge!(
+        "fondy_callbacks_failed",
+        "Server callbacks with failed processing waiting for replay"
+    ).expect("Metric register failed");
+
+    static ref PENDING_ORDERS: IntGauge = register_int_gauge!(
+        "fondy_orders_pending",
+        "Orders waiting for payment result"
+    ).expect("Metric register failed");
+
+    static ref FONDY_CIRCUIT_OPEN: IntGauge = register_int_gauge!(
+        "fondy_circuit_open",
+        "1 while Fondy requests are paused after repeated failures"
+    ).expect("Metric register failed");
+
+    static ref FONDY_REQUEST_RETRIES: IntCounterVec = register_int_counter_vec!(
+        "fondy_api_retries_total",
+        "Retried Fondy API requests by method",
+        &["method"]
+    ).expect("Metric register failed");
+
+    static ref HTTP_RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        "http_rate_limited_total",
+        "Requests rejected by rate limiter",
+        &["limiter"]
+    ).expect("Metric register failed");
+
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "HTTP request latency by route, method and status",
+        &["route", "method", "status"]
+    ).expect("Metric register failed");
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub fn checkout_created(mode: &str) {
+    CHECKOUTS_CREATED
+        .with_label_values(&[mode])
+        .inc();
+}
+
+/// Статус платежа есть только у разобранного коллбека, подпись - только у проверенного
+pub fn callback_received(kind: &str, order_status: Option<&str>, signature_valid: Option<bool>) {
+    let signature_valid = match signature_valid {
+        Some(true) => "true",
+        Some(false) => "false",
+        None => "unknown"
+    };
+    CALLBACKS_RECEIVED
+        .with_label_values(&[kind, order_status.unwrap_or("unknown"), signature_valid])
+        .inc();
+}
+
+pub fn callback_source_rejected() {
+    CALLBACK_SOURCE_REJECTED.inc();
+}
+
+/// Результат запроса к API Fondy, код ошибки берется из ответа Fondy
+pub fn fondy_request_finished(method: &str, elapsed: Duration, result: Result<(), &FondyError>) {
+    FONDY_REQUEST_DURATION
+        .with_label_values(&[method])
+        .observe(elapsed.as_secs_f64());
+    if let Err(err) = result {
+        let error_code = match err {
+            FondyError::InvalidAPIResponse(response) => response.error_code.to_string(),
+            _ => "transport".to_owned()
+        };
+        FONDY_REQUEST_ERRORS
+            .with_label_values(&[method, &error_code])
+            .inc();
+    }
+}
+
+pub fn fondy_request_retried(method: &str) {
+    FONDY_REQUEST_RETRIES
+        .with_label_values(&[method])
+        .inc();
+}
+
+pub fn fondy_circuit_changed(open: bool) {
+    FONDY_CIRCUIT_OPEN.set(open as i64);
+}
+
+pub fn order_transition(source: &str, from: &str, to: &str) {
+    ORDER_TRANSITIONS
+        .with_label_values(&[source, from, to])
+        .inc();
+}
+
+pub fn http_request_rate_limited(limiter: &str) {
+    HTTP_RATE_LIMITED
+        .with_label_values(&[limiter])
+        .inc();
+}
+
+pub fn http_request_finished(route: &str, method: &str, status: u16, elapsed: Duration) {
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[route, method, &status.to_string()])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Метка маршрута вместо пути, чтобы идентификаторы заказов не плодили отдельные серии
+pub fn route_label(path: &str) -> &'static str {
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|val| !val.is_empty())
+        .collect();
+    match segments.as_slice() {
+        [] => "/",
+        ["buy"] => "/buy",
+        ["metrics"] => "/metrics",
+        ["healthz"] => "/healthz",
+        ["readyz"] => "/readyz",
+        ["api", "orders"] => "/api/orders",
+        ["api", "orders", _] => "/api/orders/{id}",
+        ["api", "orders", _, "events"] => "/api/orders/{id}/events",
+        ["admin"] => "/admin",
+        ["admin", "login"] => "/admin/login",
+        ["admin", "logout"] => "/admin/logout",
+        ["admin", "orders"] => "/admin/orders",
+        ["admin", "orders", _] => "/admin/orders/{id}",
+        ["admin", "orders", _, "refund"] => "/admin/orders/{id}/refund",
+        ["admin", "orders", _, "capture"] => "/admin/orders/{id}/capture",
+        ["admin", "products"] => "/admin/products",
+        ["purchase_server_callback_url"] => "/purchase_server_callback_url",
+        ["browser_redirect_callback_url"] => "/browser_redirect_callback_url",
+        ["static", ..] => "/static",
+        _ => "unmatched"
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Все метрики в текстовом формате Prometheus.
+/// Показатели из базы обновляются в момент запроса.
+pub async fn render_metrics(db: &Database) -> Result<String, FondyError> {
+    FAILED_CALLBACKS.set(db.count_failed_server_callbacks().await?);
+    let mut pending = 0;
+    for status in PENDING_STATUSES {
+        pending += db
+            .count_orders(&OrderFilter{
+                order_status: Some(status.as_str().to_owned()),
+                ..Default::default()
+            })
+            .await?;
+    }
+    PENDING_ORDERS.set(pending);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|err| FondyError::Custom(format!("Metrics encode failed: {}", err)))?;
+    String::from_utf8(buffer)
+        .map_err(|err| FondyError::Custom(format!("Metrics encode failed: {}", err)))
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_route_label(){
+        assert_eq!(route_label("/"), "/");
+        assert_eq!(route_label("/api/orders/abc"), "/api/orders/{id}");
+        assert_eq!(route_label("/api/orders/abc/events"), "/api/orders/{id}/events");
+        assert_eq!(route_label("/admin/orders/abc/refund"), "/admin/orders/{id}/refund");
+        assert_eq!(route_label("/static/js/script.js"), "/static");
+        assert_eq!(route_label("/wp-login.php"), "unmatched");
+    }
+}
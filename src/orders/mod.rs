@@ -0,0 +1,177 @@
+use std::{
+    str::{
+        FromStr
+    }
+};
+use tokio::{
+    sync::{
+        broadcast
+    }
+};
+use tracing::{
+    debug,
+    warn,
+    instrument
+};
+use crate::{
+    database::{
+        Database
+    },
+    error::{
+        FondyError
+    },
+    fondy::{
+        OrderStatus
+    },
+    metrics::{
+        order_transition
+    }
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Источник изменения статуса заказа, сохраняется в истории
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventSource{
+    /// Создание заказа при покупке
+    Checkout,
+
+    /// Серверный коллбек Fondy
+    Callback,
+
+    /// Фоновая сверка с API Fondy
+    Reconciliation,
+
+    /// Истечение срока оплаты
+    Expiry
+}
+
+impl EventSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventSource::Checkout => "checkout",
+            EventSource::Callback => "callback",
+            EventSource::Reconciliation => "reconciliation",
+            EventSource::Expiry => "expiry"
+        }
+    }
+}
+
+/// Результат попытки смены статуса
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransitionOutcome{
+    /// Статус изменен
+    Applied{
+        from: OrderStatus,
+        to: OrderStatus
+    },
+
+    /// Заказ уже в этом статусе, например при повторном коллбеке
+    Unchanged,
+
+    /// Переход недопустим, например из финального статуса
+    Rejected{
+        from: OrderStatus,
+        to: OrderStatus
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Статусы, в которых заказ еще может поменяться без участия оператора
+pub const PENDING_STATUSES: &[OrderStatus] = &[OrderStatus::Created, OrderStatus::Processing];
+
+/// Допустимые переходы между статусами заказа.
+/// Expired выставляем мы сами по сроку оплаты, а approved приходит только от Fondy,
+/// поэтому оплата, прошедшая после отметки просроченным, все равно применяется.
+pub fn can_transition(from: OrderStatus, to: OrderStatus) -> bool {
+    use OrderStatus::*;
+    matches!((from, to),
+        (Created, Processing) |
+        (Created, Approved) |
+        (Created, Declined) |
+        (Created, Expired) |
+        (Processing, Approved) |
+        (Processing, Declined) |
+        (Processing, Expired) |
+        (Expired, Approved) |
+        (Approved, Reversed))
+}
+
+/// Применяет новый статус к заказу через машину состояний.
+/// Используется и коллбеками, и фоновыми задачами, чтобы правила переходов были одни.
+#[instrument(skip(db))]
+pub async fn apply_status(db: &Database, order_id: &str, to: OrderStatus, source: EventSource) -> Result<TransitionOutcome, FondyError> {
+    // Статус могут поменять параллельно, тогда перечитываем и пробуем еще раз
+    loop {
+        let order = db
+            .get_order(order_id)
+            .await?
+            .ok_or_else(|| FondyError::UnknownOrder(order_id.to_owned()))?;
+        let from = OrderStatus::from_str(&order.order_status)
+            .map_err(FondyError::Custom)?;
+
+        if from == to {
+            debug!(%from, "Order status is unchanged");
+            return Ok(TransitionOutcome::Unchanged);
+        }
+        if !can_transition(from, to) {
+            warn!(%from, %to, "Order status transition rejected");
+            return Ok(TransitionOutcome::Rejected{ from, to });
+        }
+
+        if db.update_order_status(order_id, from.as_str(), to.as_str(), source.as_str()).await? {
+            debug!(%from, %to, "Order status changed");
+            order_transition(source.as_str(), from.as_str(), to.as_str());
+            return Ok(TransitionOutcome::Applied{ from, to });
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Сколько оповещений хранится для отстающих подписчиков
+pub const STATUS_EVENTS_CAPACITY: usize = 256;
+
+/// Оповещения об изменении статуса заказа внутри процесса.
+/// Передается только идентификатор заказа, сам статус подписчики читают из базы.
+#[derive(Debug, Clone)]
+pub struct StatusEvents{
+    sender: broadcast::Sender<String>
+}
+
+impl StatusEvents {
+    pub fn new(capacity: usize) -> StatusEvents {
+        let (sender, _) = broadcast::channel(capacity);
+        StatusEvents{
+            sender
+        }
+    }
+
+    /// Оповещает подписчиков, ошибка означает лишь отсутствие подписчиков
+    pub fn publish(&self, order_id: &str) {
+        let _ = self.sender.send(order_id.to_owned());
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.sender.subscribe()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_transitions(){
+        assert!(can_transition(OrderStatus::Created, OrderStatus::Approved));
+        assert!(can_transition(OrderStatus::Approved, OrderStatus::Reversed));
+        assert!(!can_transition(OrderStatus::Declined, OrderStatus::Approved));
+        assert!(!can_transition(OrderStatus::Reversed, OrderStatus::Approved));
+        assert!(!can_transition(OrderStatus::Approved, OrderStatus::Processing));
+        assert!(can_transition(OrderStatus::Expired, OrderStatus::Approved));
+        assert!(!can_transition(OrderStatus::Expired, OrderStatus::Processing));
+    }
+}
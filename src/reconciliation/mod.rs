@@ -0,0 +1,180 @@
+use std::{
+    sync::{
+        Arc
+    },
+    time::{
+        Duration
+    }
+};
+use tracing::{
+    debug,
+    error,
+    info,
+    warn,
+    instrument
+};
+use crate::{
+    health::{
+        Heartbeat
+    },
+    shutdown::{
+        ShutdownListener
+    },
+    config::{
+        ReconciliationConfig
+    },
+    database::{
+        Database,
+        unix_now
+    },
+    error::{
+        FondyError
+    },
+    fondy::{
+        FondyClient,
+        FondyErrorCode
+    },
+    orders::{
+        EventSource,
+        TransitionOutcome,
+        PENDING_STATUSES,
+        apply_status
+    },
+    ledger::{
+        PaymentAmounts,
+        record_payment_amounts
+    }
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Итог одного прохода сверки
+#[derive(Debug, Default)]
+pub struct ReconciliationReport{
+    pub checked: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub rejected: usize,
+    /// Заказы, о которых Fondy ничего не знает, например если создание платежа упало
+    pub unknown_in_fondy: Vec<String>,
+    /// Заказы, которые не удалось проверить, с текстом ошибки
+    pub failed: Vec<(String, String)>
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Один проход сверки: берет зависшие заказы, запрашивает их статус в Fondy
+/// и применяет результат через ту же машину состояний, что и коллбеки
+#[instrument(skip(db, fondy))]
+pub async fn reconcile_pending_orders(db: &Database, fondy: &FondyClient, min_age: Duration, batch_size: i64) -> Result<ReconciliationReport, FondyError> {
+    let created_before = unix_now() - min_age.as_secs() as i64;
+    let statuses: Vec<&str> = PENDING_STATUSES
+        .iter()
+        .map(|status| status.as_str())
+        .collect();
+    let orders = db
+        .list_orders_in_statuses(&statuses, created_before, batch_size)
+        .await?;
+    debug!(count = orders.len(), "Pending orders for reconciliation");
+
+    let mut report = ReconciliationReport::default();
+    for order in orders {
+        report.checked += 1;
+
+        // Отмечаем попытку до запроса, чтобы заказ с постоянной ошибкой
+        // не попадал в каждый пакет раньше остальных
+        if let Err(err) = db.set_order_reconciled_at(&order.order_id).await {
+            error!(order_id = %order.order_id, %err, "Reconciliation attempt mark failed");
+        }
+
+        let remote = match fondy.order_status(&order.order_id).await {
+            Ok(remote) => remote,
+            Err(FondyError::InvalidAPIResponse(err)) if err.code() == FondyErrorCode::OrderNotFound => {
+                warn!(order_id = %order.order_id, "Order is unknown to Fondy");
+                report.unknown_in_fondy.push(order.order_id);
+                continue;
+            },
+            Err(err) => {
+                // Временный сбой пройдет к следующему проходу, остальные ошибки требуют вмешательства
+                if err.is_retryable() {
+                    warn!(order_id = %order.order_id, %err, "Order status request failed, will retry on next pass");
+                }else{
+                    error!(order_id = %order.order_id, %err, "Order status request failed");
+                }
+                report.failed.push((order.order_id, err.to_string()));
+                continue;
+            }
+        };
+
+        match apply_status(db, &order.order_id, remote.order_status, EventSource::Reconciliation).await {
+            Ok(TransitionOutcome::Applied{ from, to }) => {
+                info!(order_id = %order.order_id, %from, %to, "Order status reconciled");
+                report.updated += 1;
+
+                // Коллбек с суммами не пришел, поэтому учитываем оплату по ответу статуса
+                let ledger_result = record_payment_amounts(db, PaymentAmounts{
+                        order_id: &order.order_id,
+                        order_status: to,
+                        currency: &remote.currency,
+                        actual_amount: remote.actual_amount as i64,
+                        reversal_amount: remote.reversal_amount as i64,
+                        fee: None
+                    })
+                    .await;
+                if let Err(err) = ledger_result {
+                    error!(order_id = %order.order_id, %err, "Ledger update failed");
+                    report.failed.push((order.order_id, err.to_string()));
+                }
+            },
+            Ok(TransitionOutcome::Unchanged) => {
+                report.unchanged += 1;
+            },
+            Ok(TransitionOutcome::Rejected{ .. }) => {
+                report.rejected += 1;
+            },
+            Err(err) => {
+                error!(order_id = %order.order_id, %err, "Order status apply failed");
+                report.failed.push((order.order_id, err.to_string()));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Фоновая задача, периодически выполняющая сверку
+pub async fn run_reconciliation_worker(db: Arc<Database>, fondy: FondyClient, config: ReconciliationConfig, heartbeat: Heartbeat, mut shutdown: ShutdownListener) {
+    info!(interval = ?config.interval, min_age = ?config.min_age, "Reconciliation worker started");
+
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        // Остановка проверяется только между проходами, начатый проход доделывается
+        tokio::select!{
+            _ = interval.tick() => {},
+            _ = shutdown.wait() => break
+        }
+
+        match reconcile_pending_orders(&db, &fondy, config.min_age, config.batch_size).await {
+            Ok(report) => {
+                if !report.unknown_in_fondy.is_empty() {
+                    warn!(orders = ?report.unknown_in_fondy, "Orders unknown to Fondy");
+                }
+                info!(checked = report.checked,
+                      updated = report.updated,
+                      unchanged = report.unchanged,
+                      rejected = report.rejected,
+                      unknown = report.unknown_in_fondy.len(),
+                      failed = report.failed.len(),
+                      "Reconciliation finished");
+            },
+            Err(err) => {
+                error!(%err, "Reconciliation failed");
+            }
+        }
+
+        // Отметка для /readyz, что задача не зависла
+        heartbeat.beat();
+    }
+
+    info!("Reconciliation worker stopped");
+}
@@ -0,0 +1,81 @@
+use std::{
+    fmt::{
+        self,
+        Debug,
+        Display
+    }
+};
+use serde::{
+    Deserialize
+};
+use crate::{
+    error::{
+        FondyError
+    }
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Секретная строка, значение которой никогда не попадает в Debug/Display вывод и в логи.
+/// Получить значение можно только явным вызовом `expose`.
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Secret {
+        Secret(value)
+    }
+
+    /// Явный доступ к значению секрета
+    pub fn expose(&self) -> &str {
+        self.0.as_str()
+    }
+
+    /// Загружает секрет из файла, например из Docker secret.
+    /// Завершающие пробелы и переводы строк отбрасываются.
+    pub fn from_file(path: &std::path::Path) -> Result<Secret, FondyError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err|{
+                FondyError::SecretLoadError(format!("{}: {}", path.display(), err))
+            })?;
+        Ok(Secret::new(text.trim_end().to_owned()))
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+impl Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_secret_is_not_printed(){
+        let secret = Secret::new("my_password".to_owned());
+        assert!(!format!("{:?}", secret).contains("my_password"));
+        assert!(!format!("{:#?}", secret).contains("my_password"));
+        assert!(!format!("{}", secret).contains("my_password"));
+        assert_eq!(secret.expose(), "my_password");
+    }
+
+    #[test]
+    fn test_secret_from_file(){
+        let path = std::env::temp_dir().join(format!("secret_{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "file_password\n").unwrap();
+        let secret = Secret::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(secret.expose(), "file_password");
+    }
+}
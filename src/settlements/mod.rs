@@ -0,0 +1,447 @@
+use std::{
+    collections::{
+        HashMap
+    },
+    sync::{
+        Arc
+    }
+};
+use chrono::{
+    Duration as ChronoDuration,
+    NaiveDate,
+    NaiveDateTime,
+    Utc
+};
+use serde::{
+    Serialize
+};
+use tracing::{
+    debug,
+    error,
+    info,
+    warn,
+    instrument
+};
+use crate::{
+    health::{
+        Heartbeat
+    },
+    shutdown::{
+        ShutdownListener
+    },
+    config::{
+        SettlementConfig
+    },
+    database::{
+        Database,
+        NewSettlement,
+        OrderRecord,
+        SettlementRecord
+    },
+    error::{
+        FondyError
+    },
+    fondy::{
+        FondyClient,
+        FondyReportTransaction,
+        OrderStatus
+    },
+    ledger::{
+        record_fee,
+        record_payout
+    }
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Формат времени транзакции в отчетах Fondy
+const FONDY_ORDER_TIME_FORMAT: &str = "%d.%m.%Y %H:%M:%S";
+
+/// Формат дня в журнале загрузок отчетов
+const IMPORT_DAY_FORMAT: &str = "%Y-%m-%d";
+
+/// На сколько дней назад фоновая задача догружает отчеты после простоя.
+/// Более старые дни загружаются вручную командой settlements import.
+const MAX_CATCH_UP_DAYS: i64 = 31;
+
+/// Тип транзакции оплаты в отчетах Fondy
+const TRAN_TYPE_PURCHASE: &str = "purchase";
+
+/// Статусы, в которых заказ считается оплаченным и должен быть в расчетах Fondy
+const PAID_STATUSES: &[OrderStatus] = &[OrderStatus::Approved, OrderStatus::Reversed];
+
+/// Вид расхождения между нашими заказами и отчетом Fondy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscrepancyKind{
+    /// Заказ оплачен у нас, но в отчете Fondy оплаты нет
+    Missing,
+
+    /// Оплата есть в отчете Fondy, но у нас заказ не оплачен или неизвестен
+    Extra,
+
+    /// Сумма или валюта оплаты не совпадает с заказом
+    AmountMismatch
+}
+
+/// Строка отчета о расхождениях
+#[derive(Debug, Clone, Serialize)]
+pub struct Discrepancy{
+    pub kind: DiscrepancyKind,
+    pub order_id: String,
+    pub local_status: Option<String>,
+    pub local_amount: Option<i64>,
+    pub local_currency: Option<String>,
+    pub fondy_status: Option<String>,
+    pub fondy_amount: Option<i64>,
+    pub fondy_currency: Option<String>,
+    pub payment_id: Option<String>,
+    pub settlement_amount: Option<i64>,
+    pub settlement_currency: Option<String>,
+    pub settlement_date: Option<String>,
+    pub fee: Option<i64>
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Полуинтервал unix времени [начало первого дня, начало дня после последнего) в UTC
+fn period_bounds(from: NaiveDate, to: NaiveDate) -> (i64, i64) {
+    let start = from.and_hms(0, 0, 0).timestamp();
+    let end = (to + ChronoDuration::days(1)).and_hms(0, 0, 0).timestamp();
+    (start, end)
+}
+
+/// Время транзакции в отчете Fondy без часового пояса, в поясе мерчанта.
+/// Храним его как UTC без пересчета: так же без пояса Fondy получает и границы периода отчета,
+/// поэтому дни отчета совпадают с днями Fondy. Время создания наших заказов при этом настоящее UTC,
+/// и заказы у границы дня могут попасть в соседний день отчета на величину смещения пояса.
+fn parse_order_time(text: &str) -> Option<i64> {
+    NaiveDateTime::parse_from_str(text.trim(), FONDY_ORDER_TIME_FORMAT)
+        .ok()
+        .map(|time| time.timestamp())
+}
+
+fn is_paid(status: &str) -> bool {
+    PAID_STATUSES
+        .iter()
+        .any(|paid| paid.as_str() == status)
+}
+
+/// Сравнивает заказы и транзакции одного периода.
+/// Заказ, созданный в конце периода, попадет в missing, если оплата прошла уже на следующий день.
+pub fn compare(orders: &[OrderRecord], settlements: &[SettlementRecord]) -> Vec<Discrepancy> {
+    let is_paid_purchase = |record: &SettlementRecord| record.tran_type == TRAN_TYPE_PURCHASE && is_paid(&record.order_status);
+    let purchases: HashMap<&str, &SettlementRecord> = settlements
+        .iter()
+        .filter(|record| is_paid_purchase(record))
+        .map(|record| (record.order_id.as_str(), record))
+        .collect();
+    let orders_by_id: HashMap<&str, &OrderRecord> = orders
+        .iter()
+        .map(|order| (order.order_id.as_str(), order))
+        .collect();
+
+    let discrepancy = |kind, order_id: &str, order: Option<&OrderRecord>, purchase: Option<&SettlementRecord>| {
+        Discrepancy{
+            kind,
+            order_id: order_id.to_owned(),
+            local_status: order.map(|val| val.order_status.clone()),
+            local_amount: order.map(|val| val.amount),
+            local_currency: order.map(|val| val.currency.clone()),
+            fondy_status: purchase.map(|val| val.order_status.clone()),
+            fondy_amount: purchase.and_then(|val| val.amount.or(val.actual_amount)),
+            fondy_currency: purchase.map(|val| val.currency.clone()),
+            payment_id: purchase.map(|val| val.payment_id.clone()),
+            settlement_amount: purchase.and_then(|val| val.settlement_amount),
+            settlement_currency: purchase.and_then(|val| val.settlement_currency.clone()),
+            settlement_date: purchase.and_then(|val| val.settlement_date.clone()),
+            fee: purchase.and_then(|val| val.fee)
+        }
+    };
+
+    let mut result = Vec::new();
+    for order in orders.iter().filter(|order| is_paid(&order.order_status)) {
+        match purchases.get(order.order_id.as_str()) {
+            None => {
+                result.push(discrepancy(DiscrepancyKind::Missing, &order.order_id, Some(order), None));
+            },
+            Some(purchase) => {
+                let amount = purchase.amount.or(purchase.actual_amount);
+                if amount != Some(order.amount) || !purchase.currency.eq_ignore_ascii_case(&order.currency) {
+                    result.push(discrepancy(DiscrepancyKind::AmountMismatch, &order.order_id, Some(order), Some(purchase)));
+                }
+            }
+        }
+    }
+    for purchase in settlements.iter().filter(|record| is_paid_purchase(record)) {
+        let order = orders_by_id.get(purchase.order_id.as_str()).copied();
+        if !order.map(|val| is_paid(&val.order_status)).unwrap_or(false) {
+            result.push(discrepancy(DiscrepancyKind::Extra, &purchase.order_id, order, Some(purchase)));
+        }
+    }
+
+    result
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Загружает из Fondy транзакции за дни с `from` по `to` включительно и сохраняет их в базу.
+/// Возвращает количество сохраненных транзакций.
+#[instrument(skip(db, fondy))]
+pub async fn import_settlements(db: &Database, fondy: &FondyClient, from: NaiveDate, to: NaiveDate) -> Result<usize, FondyError> {
+    let transactions: Vec<FondyReportTransaction> = fondy
+        .report_transactions(from.and_hms(0, 0, 0), to.and_hms(23, 59, 59))
+        .await?;
+    debug!(count = transactions.len(), "Fondy report received");
+
+    for transaction in transactions.iter() {
+        let order_time = transaction
+            .order_time
+            .as_deref()
+            .and_then(parse_order_time);
+        if order_time.is_none() {
+            warn!(order_id = %transaction.order_id, order_time = ?transaction.order_time, "Transaction time parse failed");
+        }
+
+        db.upsert_settlement(NewSettlement{
+                order_id: &transaction.order_id,
+                payment_id: &transaction.payment_id,
+                tran_type: &transaction.tran_type,
+                order_status: &transaction.order_status,
+                currency: &transaction.currency,
+                amount: transaction.amount,
+                actual_amount: transaction.actual_amount,
+                settlement_amount: transaction.settlement_amount,
+                settlement_currency: transaction.settlement_currency.as_deref(),
+                settlement_date: transaction.settlement_date.as_deref(),
+                fee: transaction.fee,
+                order_time
+            })
+            .await?;
+
+        // Комиссия и выплата по оплаченным транзакциям попадают в учет
+        if transaction.tran_type == TRAN_TYPE_PURCHASE && is_paid(&transaction.order_status) {
+            if let Some(fee) = transaction.fee {
+                record_fee(db, &transaction.order_id, &transaction.currency, fee).await?;
+            }
+            let settlement_currency = transaction
+                .settlement_currency
+                .as_deref()
+                .filter(|currency| !currency.is_empty())
+                .unwrap_or(&transaction.currency);
+            let settled = transaction
+                .settlement_date
+                .as_deref()
+                .map(|date| !date.trim().is_empty())
+                .unwrap_or(false);
+            match transaction.settlement_amount {
+                Some(amount) if settled && settlement_currency.eq_ignore_ascii_case(&transaction.currency) => {
+                    record_payout(db, &transaction.payment_id, &transaction.order_id, &transaction.currency, amount).await?;
+                },
+                Some(_) if settled => {
+                    // Конвертацию валют в учете не ведем
+                    warn!(order_id = %transaction.order_id, %settlement_currency, "Payout in other currency is not recorded");
+                },
+                _ => {}
+            }
+        }
+    }
+
+    Ok(transactions.len())
+}
+
+/// Отчет о расхождениях за дни с `from` по `to` включительно по уже загруженным транзакциям
+#[instrument(skip(db))]
+pub async fn settlement_report(db: &Database, from: NaiveDate, to: NaiveDate) -> Result<Vec<Discrepancy>, FondyError> {
+    let (start, end) = period_bounds(from, to);
+    let mut orders = db.list_orders_created_between(start, end).await?;
+    let settlements = db.list_settlements(start, end).await?;
+
+    // Заказ мог быть создан до начала периода, а оплачен уже в нем
+    for settlement in settlements.iter() {
+        if orders.iter().any(|order| order.order_id == settlement.order_id) {
+            continue;
+        }
+        if let Some(order) = db.get_order(&settlement.order_id).await? {
+            orders.push(order);
+        }
+    }
+
+    Ok(compare(&orders, &settlements))
+}
+
+/// Дни для загрузки: после последнего загруженного по вчерашний включительно.
+/// При первом запуске только вчерашний, после долгого простоя не дальше MAX_CATCH_UP_DAYS назад.
+fn days_to_import(last_imported: Option<NaiveDate>, yesterday: NaiveDate) -> Vec<NaiveDate> {
+    let earliest = yesterday - ChronoDuration::days(MAX_CATCH_UP_DAYS - 1);
+    let mut day = last_imported
+        .map(|day| day + ChronoDuration::days(1))
+        .unwrap_or(yesterday)
+        .max(earliest);
+    let mut days = Vec::new();
+    while day <= yesterday {
+        days.push(day);
+        day += ChronoDuration::days(1);
+    }
+    days
+}
+
+/// Загружает отчет за день, отмечает день загруженным и возвращает расхождения
+async fn import_day(db: &Database, fondy: &FondyClient, day: NaiveDate) -> Result<(usize, Vec<Discrepancy>), FondyError> {
+    let imported = import_settlements(db, fondy, day, day).await?;
+    let report = settlement_report(db, day, day).await?;
+    db.mark_settlement_day_imported(&day.format(IMPORT_DAY_FORMAT).to_string(), imported as i64)
+        .await?;
+    Ok((imported, report))
+}
+
+/// Последний загруженный фоновой задачей день
+async fn last_imported_day(db: &Database) -> Result<Option<NaiveDate>, FondyError> {
+    match db.last_imported_settlement_day().await? {
+        Some(text) => NaiveDate::parse_from_str(&text, IMPORT_DAY_FORMAT)
+            .map(Some)
+            .map_err(|err| FondyError::Custom(format!("Invalid settlement import day {}: {}", text, err))),
+        None => Ok(None)
+    }
+}
+
+/// Фоновая задача: раз в период загружает транзакции за дни начиная с последнего загруженного
+/// по вчерашний и пишет расхождения в лог. День с ошибкой загрузки повторяется на следующем проходе.
+pub async fn run_settlement_worker(db: Arc<Database>, fondy: FondyClient, config: SettlementConfig, heartbeat: Heartbeat, mut shutdown: ShutdownListener) {
+    info!(interval = ?config.interval, "Settlement worker started");
+
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        tokio::select!{
+            _ = interval.tick() => {},
+            _ = shutdown.wait() => break
+        }
+
+        let yesterday = Utc::today().naive_utc() - ChronoDuration::days(1);
+        let last_imported = match last_imported_day(&db).await {
+            Ok(day) => day,
+            Err(err) => {
+                error!(%err, "Last imported settlement day read failed");
+                heartbeat.beat();
+                continue;
+            }
+        };
+        let days = days_to_import(last_imported, yesterday);
+        if let (Some(last), Some(first)) = (last_imported, days.first()) {
+            if *first > last + ChronoDuration::days(1) {
+                warn!(%last, %first, "Settlement days after a long downtime are skipped, import them with settlements import");
+            }
+        }
+
+        for day in days {
+            match import_day(&db, &fondy, day).await {
+                Ok((imported, report)) => {
+                    for discrepancy in report.iter() {
+                        warn!(?discrepancy, "Settlement discrepancy");
+                    }
+                    info!(%day, imported, discrepancies = report.len(), "Settlement reconciliation finished");
+                },
+                Err(err) => {
+                    // Следующие дни не загружаем, чтобы последний загруженный день не перескочил через пропуск
+                    error!(%day, %err, "Settlement reconciliation failed");
+                    break;
+                }
+            }
+        }
+
+        heartbeat.beat();
+    }
+
+    info!("Settlement worker stopped");
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn order(order_id: &str, status: &str, amount: i64) -> OrderRecord {
+        OrderRecord{
+            order_id: order_id.to_owned(),
+            product_id: None,
+            amount,
+            currency: "USD".to_owned(),
+            order_status: status.to_owned(),
+            payment_id: None,
+            checkout_url: None,
+            created_at: 0,
+            updated_at: 0,
+            expires_at: None,
+            customer_email: None,
+            idempotency_hash: None,
+            checkout_token: None,
+            reconciled_at: None
+        }
+    }
+
+    fn purchase(order_id: &str, amount: i64) -> SettlementRecord {
+        SettlementRecord{
+            order_id: order_id.to_owned(),
+            payment_id: format!("p-{}", order_id),
+            tran_type: TRAN_TYPE_PURCHASE.to_owned(),
+            order_status: "approved".to_owned(),
+            currency: "USD".to_owned(),
+            amount: Some(amount),
+            actual_amount: Some(amount),
+            settlement_amount: None,
+            settlement_currency: None,
+            settlement_date: None,
+            fee: None
+        }
+    }
+
+    #[test]
+    fn test_compare(){
+        let orders = vec![
+            order("ok", "approved", 100),
+            order("missing", "approved", 100),
+            order("mismatch", "approved", 100),
+            order("declined", "declined", 100)
+        ];
+        let settlements = vec![
+            purchase("ok", 100),
+            purchase("mismatch", 90),
+            purchase("declined", 100),
+            purchase("unknown", 100)
+        ];
+        let result: Vec<(DiscrepancyKind, String)> = compare(&orders, &settlements)
+            .into_iter()
+            .map(|val| (val.kind, val.order_id))
+            .collect();
+        assert_eq!(result, vec![
+            (DiscrepancyKind::Missing, "missing".to_owned()),
+            (DiscrepancyKind::AmountMismatch, "mismatch".to_owned()),
+            (DiscrepancyKind::Extra, "declined".to_owned()),
+            (DiscrepancyKind::Extra, "unknown".to_owned())
+        ]);
+    }
+
+    #[test]
+    fn test_period_bounds(){
+        let day = NaiveDate::from_ymd(2021, 5, 16);
+        assert_eq!(period_bounds(day, day), (1621123200, 1621209600));
+        assert_eq!(parse_order_time("16.05.2021 00:00:00"), Some(1621123200));
+    }
+
+    #[test]
+    fn test_days_to_import(){
+        let yesterday = NaiveDate::from_ymd(2021, 5, 16);
+        assert_eq!(days_to_import(None, yesterday), vec![yesterday]);
+        assert!(days_to_import(Some(yesterday), yesterday).is_empty());
+
+        // После простоя догружаются все пропущенные дни
+        let days = days_to_import(Some(NaiveDate::from_ymd(2021, 5, 13)), yesterday);
+        assert_eq!(days, vec![NaiveDate::from_ymd(2021, 5, 14), NaiveDate::from_ymd(2021, 5, 15), yesterday]);
+
+        // Но не дальше ограничения
+        let days = days_to_import(Some(NaiveDate::from_ymd(2020, 1, 1)), yesterday);
+        assert_eq!(days.len() as i64, MAX_CATCH_UP_DAYS);
+        assert_eq!(days.last(), Some(&yesterday));
+    }
+}
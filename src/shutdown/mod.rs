@@ -0,0 +1,114 @@
+use tokio::{
+    sync::{
+        watch
+    }
+};
+use tracing::{
+    info
+};
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Сигнал остановки сервиса для сервера, фоновых задач и долгих запросов
+#[derive(Debug)]
+pub struct Shutdown{
+    sender: watch::Sender<bool>,
+    receiver: watch::Receiver<bool>
+}
+
+/// Ожидание остановки, у каждого потребителя свой экземпляр
+#[derive(Debug, Clone)]
+pub struct ShutdownListener{
+    receiver: watch::Receiver<bool>
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (sender, receiver) = watch::channel(false);
+        Shutdown{
+            sender,
+            receiver
+        }
+    }
+
+    pub fn trigger(&self) {
+        // Ошибка только если слушателей уже нет, тогда и оповещать некого
+        self.sender.send(true).ok();
+    }
+
+    pub fn listener(&self) -> ShutdownListener {
+        ShutdownListener{
+            receiver: self.receiver.clone()
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Shutdown {
+        Shutdown::new()
+    }
+}
+
+impl ShutdownListener {
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Завершается при остановке, в том числе если она уже началась
+    pub async fn wait(&mut self) {
+        while !self.is_triggered() {
+            if self.receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Ждет SIGTERM от оркестратора или SIGINT из терминала
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{
+            signal,
+            SignalKind
+        };
+        let mut terminate = signal(SignalKind::terminate())
+            .expect("SIGTERM handler install failed");
+        tokio::select!{
+            _ = terminate.recv() => info!("SIGTERM received"),
+            _ = tokio::signal::ctrl_c() => info!("SIGINT received")
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c()
+            .await
+            .ok();
+        info!("Ctrl-C received");
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_listener(){
+        let shutdown = Shutdown::new();
+        let mut listener = shutdown.listener();
+        assert!(!listener.is_triggered());
+
+        let waiter = tokio::spawn(async move {
+            listener.wait().await;
+        });
+        shutdown.trigger();
+        waiter.await.unwrap();
+
+        // Подписавшийся после остановки не должен ждать
+        let mut late = shutdown.listener();
+        late.wait().await;
+    }
+}
@@ -0,0 +1,56 @@
+// Живой статус оплаты на странице ожидания.
+// Сервер отдает поток событий status, после финального статуса поток закрывается.
+
+const PAYMENT_STATUS_VIEWS = {
+    created: ["waiting", "Waiting for payment..."],
+    processing: ["waiting", "Waiting for payment..."],
+    approved: ["paid", "Paid"],
+    reversed: ["declined", "Payment reversed"],
+    declined: ["declined", "Payment declined"],
+    expired: ["declined", "Payment expired"]
+};
+
+function showPaymentStatus(element, status) {
+    const view = PAYMENT_STATUS_VIEWS[status] || ["waiting", status];
+    element.className = "payment-status payment-status-" + view[0];
+    element.querySelector(".payment-status-text").textContent = view[1];
+}
+
+function watchPaymentStatus(element) {
+    const orderId = element.dataset.orderId;
+    const source = new EventSource("/api/orders/" + encodeURIComponent(orderId) + "/events");
+
+    source.addEventListener("status", (event) => {
+        const data = JSON.parse(event.data);
+        showPaymentStatus(element, data.order_status);
+    });
+
+    // Поток закрывается сервером после финального статуса,
+    // браузер в этом случае переподключается, поэтому закрываем его сами
+    source.addEventListener("error", () => {
+        if (!element.classList.contains("payment-status-waiting")) {
+            source.close();
+        }
+    });
+}
+
+// Виджет оплаты Fondy по токену, полученному сервером
+function mountCheckoutWidget(element) {
+    fondy("#" + element.id, {
+        params: {
+            token: element.dataset.token
+        }
+    });
+}
+
+document.addEventListener("DOMContentLoaded", () => {
+    const checkout = document.getElementById("checkout-container");
+    if (checkout) {
+        mountCheckoutWidget(checkout);
+    }
+
+    const element = document.getElementById("payment-status");
+    if (element) {
+        watchPaymentStatus(element);
+    }
+});